@@ -1,5 +1,7 @@
 library flutter_compositor;
 
+export 'src/surface_texture.dart';
+
 /// A Calculator.
 class Calculator {
   /// Returns [value] plus 1.
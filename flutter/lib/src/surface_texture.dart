@@ -0,0 +1,105 @@
+import 'package:flutter/services.dart';
+import 'package:flutter/widgets.dart';
+
+const BasicMessageChannel<dynamic> _texturesChannel =
+    BasicMessageChannel<dynamic>('flutter_compositor/textures', JSONMessageCodec());
+
+/// How a surface texture is presented, as sent by the compositor.
+class SurfaceTextureInfo {
+  const SurfaceTextureInfo({
+    @required this.width,
+    @required this.height,
+    @required this.crop,
+    @required this.logicalSize,
+  });
+
+  /// Size of the texture in pixels.
+  final double width;
+  final double height;
+
+  /// Region of the texture to show, in pixels.
+  final Rect crop;
+
+  /// Logical size the cropped region is shown at.
+  final Size logicalSize;
+}
+
+/// Keeps track of the presentation of every surface texture.
+class SurfaceTextures extends ChangeNotifier {
+  SurfaceTextures._() {
+    _texturesChannel.setMessageHandler(_onMessage);
+  }
+
+  static final SurfaceTextures instance = SurfaceTextures._();
+
+  final Map<int, SurfaceTextureInfo> _textures = <int, SurfaceTextureInfo>{};
+
+  SurfaceTextureInfo operator [](int id) => _textures[id];
+
+  Future<dynamic> _onMessage(dynamic message) async {
+    final int id = message['id'];
+    switch (message['event']) {
+      case 'updated':
+        _textures[id] = SurfaceTextureInfo(
+          width: message['width'].toDouble(),
+          height: message['height'].toDouble(),
+          crop: Rect.fromLTWH(
+            message['cropX'].toDouble(),
+            message['cropY'].toDouble(),
+            message['cropWidth'].toDouble(),
+            message['cropHeight'].toDouble(),
+          ),
+          logicalSize: Size(
+            message['logicalWidth'].toDouble(),
+            message['logicalHeight'].toDouble(),
+          ),
+        );
+        break;
+      case 'removed':
+        _textures.remove(id);
+        break;
+    }
+    notifyListeners();
+    return null;
+  }
+}
+
+/// Shows a surface texture at its logical size, cropped to the region set by the client.
+class SurfaceTexture extends StatelessWidget {
+  const SurfaceTexture({Key key, @required this.textureId}) : super(key: key);
+
+  final int textureId;
+
+  @override
+  Widget build(BuildContext context) {
+    return AnimatedBuilder(
+      animation: SurfaceTextures.instance,
+      builder: (BuildContext context, Widget child) {
+        final SurfaceTextureInfo info = SurfaceTextures.instance[textureId];
+        if (info == null || info.crop.isEmpty) {
+          return const SizedBox.shrink();
+        }
+
+        // Scale the whole texture so that the cropped region fills the logical size
+        final double scaleX = info.logicalSize.width / info.crop.width;
+        final double scaleY = info.logicalSize.height / info.crop.height;
+        return SizedBox.fromSize(
+          size: info.logicalSize,
+          child: ClipRect(
+            child: Stack(
+              children: <Widget>[
+                Positioned(
+                  left: -info.crop.left * scaleX,
+                  top: -info.crop.top * scaleY,
+                  width: info.width * scaleX,
+                  height: info.height * scaleY,
+                  child: Texture(textureId: textureId),
+                ),
+              ],
+            ),
+          ),
+        );
+      },
+    );
+  }
+}
@@ -8,6 +8,7 @@ use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use std::{env, fs, io};
+use wayland_scanner::Side;
 
 fn main() {
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -27,6 +28,7 @@ fn main() {
     let mut file_output = File::create(&out_path.join("gl_bindings.rs")).unwrap();
     generate_gl_bindings(&mut file_output);
     generate_flutter_bindings(&out_path, &root_dir);
+    generate_wayland_protocols(&out_path);
 }
 
 fn generate_gl_bindings<W>(dest: &mut W)
@@ -122,6 +124,8 @@ where
             "GL_NV_pixel_buffer_object",
             "GL_OES_depth_texture",
             "GL_OES_draw_elements_base_vertex",
+            "GL_OES_EGL_image",
+            "GL_OES_EGL_image_external",
             "GL_OES_packed_depth_stencil",
             "GL_OES_primitive_bounding_box",
             "GL_OES_rgb8_rgba8",
@@ -246,6 +250,26 @@ fn generate_flutter_bindings(out_path: &PathBuf, root_dir: &PathBuf) {
         .expect("Couldn't write bindings!");
 }
 
+/// Generates server code for protocols not yet shipped by wayland-protocols.
+fn generate_wayland_protocols(out_path: &PathBuf) {
+    let protocols = [
+        "fractional-scale-v1",
+        "wlr-output-power-management-unstable-v1",
+        "wlr-screencopy-unstable-v1",
+    ];
+
+    for name in protocols.iter() {
+        let source = Path::new("protocols").join(format!("{}.xml", name));
+        println!("cargo:rerun-if-changed={}", source.to_str().unwrap());
+
+        wayland_scanner::generate_code(
+            &source,
+            out_path.join(format!("{}_server_api.rs", name)),
+            Side::Server,
+        );
+    }
+}
+
 fn download_file<P: AsRef<Path>>(url: &str, target: P) {
     let mut resp = reqwest::get(url).expect("Failed to fetch file");
 
@@ -0,0 +1,314 @@
+use std::cell::{Cell, Ref, RefCell};
+
+use smithay::reexports::image::{Rgba, RgbaImage};
+use smithay::reexports::wayland_server::protocol::{wl_buffer, wl_shm, wl_surface};
+use smithay::wayland::seat::CursorImageRole;
+use smithay::wayland::shm::{with_buffer_contents, BufferData};
+
+use log::warn;
+
+use crate::capture::{CursorOverlay, Frame};
+use crate::shell::MyCompositorToken;
+
+/// Built in arrow, used until a cursor is chosen. `X` is the outline, `.` the fill.
+const DEFAULT_CURSOR: [&str; 19] = [
+    "X           ",
+    "XX          ",
+    "X.X         ",
+    "X..X        ",
+    "X...X       ",
+    "X....X      ",
+    "X.....X     ",
+    "X......X    ",
+    "X.......X   ",
+    "X........X  ",
+    "X.........X ",
+    "X......XXXXX",
+    "X...X..X    ",
+    "X..XX..X    ",
+    "X.X  X..X   ",
+    "XX   X..X   ",
+    "X     X..X  ",
+    "      X..X  ",
+    "       XX   ",
+];
+
+/// What the pointer should look like.
+pub(crate) enum CursorStatus {
+    Default,
+    Hidden,
+    /// A surface with the cursor role, set by a client through `wl_pointer.set_cursor`.
+    Surface(wl_surface::WlSurface),
+    /// An image chosen by flutter.
+    Image(CursorImage),
+}
+
+/// A cursor image, as premultiplied RGBA.
+#[derive(Clone)]
+pub(crate) struct CursorImage {
+    pub image: RgbaImage,
+    /// Point of the image at the pointer location, in pixels.
+    pub hotspot: (i32, i32),
+    /// Name of the theme cursor, for backends that can show it natively.
+    pub name: Option<&'static str>,
+}
+
+/// The committed contents of a cursor surface, kept in the user data of the surface.
+///
+/// Cursor planes are written from memory, so shm contents are kept around instead of only being
+/// uploaded into a texture.
+#[derive(Default)]
+pub(crate) struct CursorContents(Option<RgbaImage>);
+
+impl CursorContents {
+    pub fn set(&mut self, image: Option<RgbaImage>) {
+        self.0 = image;
+    }
+}
+
+/// Tracks the pointer location and the image shown for it.
+pub(crate) struct CursorState {
+    status: RefCell<CursorStatus>,
+    /// Pointer location in framebuffer pixels.
+    location: Cell<(f64, f64)>,
+    /// The image for the current status, `None` while hidden.
+    image: RefCell<Option<CursorImage>>,
+    /// Incremented whenever the image changes, so renderers know when to reupload it.
+    serial: Cell<u64>,
+    dirty: Cell<bool>,
+    default: RefCell<CursorImage>,
+}
+
+impl Default for CursorState {
+    fn default() -> Self {
+        Self {
+            status: RefCell::new(CursorStatus::Default),
+            location: Cell::new((0.0, 0.0)),
+            image: RefCell::new(None),
+            serial: Cell::new(0),
+            dirty: Cell::new(true),
+            default: RefCell::new(default_cursor()),
+        }
+    }
+}
+
+impl CursorState {
+    pub fn location(&self) -> (f64, f64) {
+        self.location.get()
+    }
+
+    pub fn set_location(&self, location: (f64, f64)) {
+        self.location.set(location);
+    }
+
+    pub fn set_status(&self, status: CursorStatus) {
+        self.status.replace(status);
+        self.dirty.set(true);
+    }
+
+    /// Replaces the image shown for the default cursor.
+    pub fn set_default(&self, image: CursorImage) {
+        self.default.replace(image);
+        if let CursorStatus::Default = &*self.status.borrow() {
+            self.dirty.set(true);
+        }
+    }
+
+    /// Notes that a cursor surface committed new contents.
+    pub fn surface_committed(&self, surface: &wl_surface::WlSurface) {
+        if let CursorStatus::Surface(current) = &*self.status.borrow() {
+            if current.as_ref().equals(surface.as_ref()) {
+                self.dirty.set(true);
+            }
+        }
+    }
+
+    /// Resolves the image for the current status, returning whether it changed.
+    pub fn refresh(&self, token: Option<MyCompositorToken>) -> bool {
+        if !self.dirty.replace(false) {
+            return false;
+        }
+
+        let image = match &*self.status.borrow() {
+            CursorStatus::Default => Some(self.default.borrow().clone()),
+            CursorStatus::Hidden => None,
+            CursorStatus::Surface(surface) if !surface.as_ref().is_alive() => {
+                Some(self.default.borrow().clone())
+            }
+            CursorStatus::Surface(surface) => match token {
+                Some(token) => surface_image(surface, token),
+                None => None,
+            },
+            CursorStatus::Image(image) => Some(image.clone()),
+        };
+
+        self.image.replace(image);
+        self.serial.set(self.serial.get() + 1);
+        true
+    }
+
+    pub fn image(&self) -> Ref<Option<CursorImage>> {
+        self.image.borrow()
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial.get()
+    }
+
+    /// Returns where the top left corner of the image goes, in framebuffer pixels.
+    pub fn position(&self) -> Option<(i32, i32)> {
+        let (x, y) = self.location.get();
+        self.image
+            .borrow()
+            .as_ref()
+            .map(|cursor| (x as i32 - cursor.hotspot.0, y as i32 - cursor.hotspot.1))
+    }
+
+    /// Returns the cursor as drawn over captured frames.
+    pub fn overlay(&self) -> Option<CursorOverlay> {
+        let position = self.position()?;
+        let image = self.image.borrow();
+        let image = &image.as_ref()?.image;
+        Some(CursorOverlay {
+            image: Frame {
+                width: image.width(),
+                height: image.height(),
+                data: image.clone().into_raw(),
+            },
+            position,
+        })
+    }
+}
+
+fn surface_image(surface: &wl_surface::WlSurface, token: MyCompositorToken) -> Option<CursorImage> {
+    let (image, scale) = token.with_surface_data(surface, |attributes| {
+        let image = attributes
+            .user_data
+            .get::<CursorContents>()
+            .and_then(|contents| contents.0.clone());
+        (image, attributes.buffer_scale.max(1))
+    });
+
+    // The hotspot is in surface coordinates, the image is shown at its buffer resolution
+    let hotspot = token
+        .with_role_data(surface, |role: &mut CursorImageRole| role.hotspot)
+        .unwrap_or((0, 0));
+
+    image.map(|image| CursorImage {
+        image,
+        hotspot: (hotspot.0 * scale, hotspot.1 * scale),
+        name: None,
+    })
+}
+
+/// Copies the contents of an shm buffer into a premultiplied RGBA image.
+///
+/// Returns `None` for buffers that are not in shared memory or do not fit in their pool.
+pub(crate) fn read_shm_buffer(buffer: &wl_buffer::WlBuffer) -> Option<RgbaImage> {
+    with_buffer_contents(buffer, |pool, data| read_pool(pool, &data))
+        .ok()
+        .and_then(|image| image)
+}
+
+fn read_pool(pool: &[u8], data: &BufferData) -> Option<RgbaImage> {
+    let opaque = match data.format {
+        wl_shm::Format::Argb8888 => false,
+        wl_shm::Format::Xrgb8888 => true,
+        format => {
+            warn!("Unsupported cursor format {:?}", format);
+            return None;
+        }
+    };
+
+    if data.offset < 0 || data.width < 0 || data.height < 0 {
+        return None;
+    }
+    let offset = data.offset as usize;
+    let width = data.width as usize;
+    let height = data.height as usize;
+    let row = width.checked_mul(4)?;
+    if data.stride < 0 || (data.stride as usize) < row {
+        warn!("Cursor buffer stride {} is too small", data.stride);
+        return None;
+    }
+    let stride = data.stride as usize;
+
+    // check the last row before allocating, the size comes from the client
+    if height > 0 {
+        let end = (height - 1)
+            .checked_mul(stride)?
+            .checked_add(offset)?
+            .checked_add(row)?;
+        if end > pool.len() {
+            warn!("Cursor buffer does not fit in its pool");
+            return None;
+        }
+    }
+
+    let mut image = RgbaImage::new(width as u32, height as u32);
+    for y in 0..height {
+        let start = y * stride + offset;
+        let pixels = &pool[start..start + row];
+        for (x, bgra) in pixels.chunks(4).enumerate() {
+            // wl_shm formats are little endian, so the bytes are in BGRA order
+            let alpha = if opaque { 255 } else { bgra[3] };
+            image.put_pixel(x as u32, y as u32, Rgba([bgra[2], bgra[1], bgra[0], alpha]));
+        }
+    }
+    Some(image)
+}
+
+fn default_cursor() -> CursorImage {
+    let width = DEFAULT_CURSOR[0].len() as u32;
+    let height = DEFAULT_CURSOR.len() as u32;
+
+    let mut image = RgbaImage::new(width, height);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        *pixel = match DEFAULT_CURSOR[y as usize].as_bytes()[x as usize] {
+            b'X' => Rgba([0, 0, 0, 255]),
+            b'.' => Rgba([255, 255, 255, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        };
+    }
+
+    CursorImage {
+        image,
+        hotspot: (0, 0),
+        name: Some("default"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(offset: i32, width: i32, height: i32, stride: i32) -> BufferData {
+        BufferData {
+            offset,
+            width,
+            height,
+            stride,
+            format: wl_shm::Format::Argb8888,
+        }
+    }
+
+    #[test]
+    fn read_pool_converts_to_rgba() {
+        let pool = [0, 0, 1, 2, 3, 4, 0, 0, 5, 6, 7, 8];
+        let image = read_pool(&pool, &data(2, 1, 2, 6)).unwrap();
+        assert_eq!(image.dimensions(), (1, 2));
+        assert_eq!(*image.get_pixel(0, 0), Rgba([3, 2, 1, 4]));
+        assert_eq!(*image.get_pixel(0, 1), Rgba([7, 6, 5, 8]));
+    }
+
+    #[test]
+    fn read_pool_rejects_bad_layouts() {
+        let pool = [0; 64];
+        assert!(read_pool(&pool, &data(0, 4, 4, 12)).is_none());
+        assert!(read_pool(&pool, &data(4, 4, 4, 16)).is_none());
+        assert!(read_pool(&pool, &data(-4, 1, 1, 4)).is_none());
+        assert!(read_pool(&pool, &data(0, 1, 1, -4)).is_none());
+        assert!(read_pool(&pool, &data(i32::MAX, 1, 1, 4)).is_none());
+        assert!(read_pool(&pool, &data(0, 1, i32::MAX, i32::MAX)).is_none());
+    }
+}
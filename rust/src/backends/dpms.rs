@@ -0,0 +1,94 @@
+use std::io::{self, ErrorKind};
+use std::os::unix::io::RawFd;
+
+use crate::backends::drm_ioctl::ioctl;
+
+// The DRM crate does not expose connector properties, so they are set through the mode ioctls
+const IOCTL_MODE_GETPROPERTY: u64 = 0xaa;
+const IOCTL_MODE_SETPROPERTY: u64 = 0xab;
+const IOCTL_MODE_OBJ_GETPROPERTIES: u64 = 0xb9;
+
+const OBJECT_CONNECTOR: u32 = 0xc0c0_c0c0;
+
+const DPMS_PROPERTY: &[u8] = b"DPMS";
+const DPMS_ON: u64 = 0;
+const DPMS_OFF: u64 = 3;
+
+#[repr(C)]
+#[derive(Default)]
+struct ObjGetProperties {
+    props_ptr: u64,
+    prop_values_ptr: u64,
+    count_props: u32,
+    obj_id: u32,
+    obj_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct GetProperty {
+    values_ptr: u64,
+    enum_blob_ptr: u64,
+    prop_id: u32,
+    flags: u32,
+    name: [u8; 32],
+    count_values: u32,
+    count_enum_blobs: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ConnectorSetProperty {
+    value: u64,
+    prop_id: u32,
+    connector_id: u32,
+}
+
+/// Turns the monitor on a connector on or off through its legacy DPMS property.
+pub(crate) fn set_dpms(fd: RawFd, connector: u32, on: bool) -> io::Result<()> {
+    let prop_id = find_property(fd, connector, DPMS_PROPERTY)?
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "connector has no DPMS property"))?;
+
+    let mut request = ConnectorSetProperty {
+        value: if on { DPMS_ON } else { DPMS_OFF },
+        prop_id,
+        connector_id: connector,
+    };
+    ioctl(fd, IOCTL_MODE_SETPROPERTY, &mut request)
+}
+
+fn find_property(fd: RawFd, connector: u32, name: &[u8]) -> io::Result<Option<u32>> {
+    // The first call only returns the number of properties
+    let mut request = ObjGetProperties {
+        obj_id: connector,
+        obj_type: OBJECT_CONNECTOR,
+        ..Default::default()
+    };
+    ioctl(fd, IOCTL_MODE_OBJ_GETPROPERTIES, &mut request)?;
+
+    let count = request.count_props as usize;
+    let mut ids = vec![0u32; count];
+    let mut values = vec![0u64; count];
+    request.props_ptr = ids.as_mut_ptr() as u64;
+    request.prop_values_ptr = values.as_mut_ptr() as u64;
+    ioctl(fd, IOCTL_MODE_OBJ_GETPROPERTIES, &mut request)?;
+    ids.truncate(request.count_props as usize);
+
+    for id in ids {
+        let mut property = GetProperty {
+            prop_id: id,
+            ..Default::default()
+        };
+        ioctl(fd, IOCTL_MODE_GETPROPERTY, &mut property)?;
+
+        let len = property
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or_else(|| property.name.len());
+        if property.name[..len] == *name {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
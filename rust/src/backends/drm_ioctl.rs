@@ -0,0 +1,61 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::backends::vsync::Vblank;
+
+const IOCTL_WAIT_VBLANK: u64 = 0x3a;
+
+const VBLANK_RELATIVE: u32 = 0x1;
+const VBLANK_SECONDARY: u32 = 0x2000_0000;
+const VBLANK_HIGH_CRTC_SHIFT: u32 = 1;
+const VBLANK_HIGH_CRTC_MASK: u32 = 0x0000_003e;
+
+/// `drm_wait_vblank`, the request and reply share the same memory.
+#[repr(C)]
+#[derive(Default)]
+struct WaitVblank {
+    kind: u32,
+    sequence: u32,
+    /// `signal` of the request.
+    tv_sec: libc::c_long,
+    tv_usec: libc::c_long,
+}
+
+/// Returns the counter and timestamp of the latest vblank of a CRTC, identified by its index in
+/// the resources of the device.
+///
+/// Page flips complete at a vblank, so right after a page flip event this is the vblank the
+/// frame was shown at.
+pub(crate) fn last_vblank(fd: RawFd, pipe: u32) -> io::Result<Vblank> {
+    let mut kind = VBLANK_RELATIVE;
+    if pipe == 1 {
+        kind |= VBLANK_SECONDARY;
+    } else if pipe > 1 {
+        kind |= (pipe << VBLANK_HIGH_CRTC_SHIFT) & VBLANK_HIGH_CRTC_MASK;
+    }
+
+    // Waiting for zero vblanks returns straight away
+    let mut request = WaitVblank {
+        kind,
+        ..Default::default()
+    };
+    ioctl(fd, IOCTL_WAIT_VBLANK, &mut request)?;
+
+    // DRM timestamps are taken on the monotonic clock
+    Ok(Vblank {
+        time: request.tv_sec as u64 * 1_000_000_000 + request.tv_usec as u64 * 1000,
+        sequence: u64::from(request.sequence),
+    })
+}
+
+/// Issues a read-write DRM ioctl, the number of which is encoded like the kernel's `DRM_IOWR`.
+pub(crate) fn ioctl<T>(fd: RawFd, nr: u64, data: &mut T) -> io::Result<()> {
+    let request = 3 << 30 | (mem::size_of::<T>() as u64) << 16 | u64::from(b'd') << 8 | nr;
+    let result = unsafe { libc::ioctl(fd, request as _, data as *mut T) };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// Offsets of the four 18 byte display descriptors of the base block.
+const DESCRIPTORS: [usize; 4] = [54, 72, 90, 108];
+
+const DESCRIPTOR_SERIAL: u8 = 0xff;
+const DESCRIPTOR_TEXT: u8 = 0xfe;
+const DESCRIPTOR_NAME: u8 = 0xfc;
+
+/// Identification of a monitor, read from the base block of its EDID.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Edid {
+    /// Three letter PNP id of the manufacturer, like `DEL`.
+    pub make: String,
+    /// The monitor name, or the product code if the EDID has no name.
+    pub model: String,
+    /// The serial number, empty if the EDID has none.
+    pub serial: String,
+    /// Size of the screen in millimetres, zero if unknown or for projectors.
+    pub size_mm: (u32, u32),
+}
+
+impl Edid {
+    pub fn parse(data: &[u8]) -> Option<Edid> {
+        if data.len() < 128 || data[..8] != HEADER {
+            return None;
+        }
+
+        // Letters are 5 bits each, 1 being 'A'
+        let id = u16::from(data[8]) << 8 | u16::from(data[9]);
+        let make = [(id >> 10) & 0x1f, (id >> 5) & 0x1f, id & 0x1f]
+            .iter()
+            .map(|&letter| (b'A' - 1 + letter as u8) as char)
+            .collect();
+
+        let product = u16::from(data[10]) | u16::from(data[11]) << 8;
+        let serial_number = u32::from(data[12])
+            | u32::from(data[13]) << 8
+            | u32::from(data[14]) << 16
+            | u32::from(data[15]) << 24;
+
+        let mut name = None;
+        let mut serial = None;
+        let mut text = None;
+        for &offset in &DESCRIPTORS {
+            let descriptor = &data[offset..offset + 18];
+            // Detailed timings come first, their pixel clock is never zero
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                continue;
+            }
+            match descriptor[3] {
+                DESCRIPTOR_NAME => name = descriptor_string(descriptor),
+                DESCRIPTOR_SERIAL => serial = descriptor_string(descriptor),
+                DESCRIPTOR_TEXT => text = descriptor_string(descriptor),
+                _ => {}
+            }
+        }
+
+        let model = name
+            .or(text)
+            .unwrap_or_else(|| format!("0x{:04X}", product));
+        let serial = serial.unwrap_or_else(|| match serial_number {
+            0 => String::new(),
+            number => number.to_string(),
+        });
+
+        Some(Edid {
+            make,
+            model,
+            serial,
+            // The base block only has the size in centimetres
+            size_mm: (u32::from(data[21]) * 10, u32::from(data[22]) * 10),
+        })
+    }
+}
+
+/// Reads the text of a descriptor, which ends at a line feed and is padded with spaces.
+fn descriptor_string(descriptor: &[u8]) -> Option<String> {
+    let text: String = descriptor[5..]
+        .iter()
+        .take_while(|&&byte| byte != b'\n')
+        .map(|&byte| byte as char)
+        .collect();
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A base block of a `DEL` monitor with product code 0x1234, serial number 12345 and a 52x29
+    /// cm screen, whose first descriptor holds detailed timings.
+    fn base_block() -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[..8].copy_from_slice(&HEADER);
+        data[8..10].copy_from_slice(&[0x10, 0xac]);
+        data[10..12].copy_from_slice(&[0x34, 0x12]);
+        data[12..16].copy_from_slice(&12345u32.to_le_bytes());
+        data[21] = 52;
+        data[22] = 29;
+        data[54..56].copy_from_slice(&[0x02, 0x3a]);
+        data
+    }
+
+    fn set_descriptor(data: &mut [u8], offset: usize, tag: u8, text: &str) {
+        let descriptor = &mut data[offset..offset + 18];
+        descriptor[3] = tag;
+        for byte in &mut descriptor[5..] {
+            *byte = b' ';
+        }
+        descriptor[5..5 + text.len()].copy_from_slice(text.as_bytes());
+        descriptor[5 + text.len()] = b'\n';
+    }
+
+    #[test]
+    fn parse_reads_descriptors() {
+        let mut data = base_block();
+        set_descriptor(&mut data, 72, DESCRIPTOR_NAME, "DELL U2415");
+        set_descriptor(&mut data, 90, DESCRIPTOR_SERIAL, "ABC123");
+        set_descriptor(&mut data, 108, DESCRIPTOR_TEXT, "unused");
+
+        let edid = Edid::parse(&data).unwrap();
+        assert_eq!(
+            edid,
+            Edid {
+                make: "DEL".into(),
+                model: "DELL U2415".into(),
+                serial: "ABC123".into(),
+                size_mm: (520, 290),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_falls_back_to_ids() {
+        let mut data = base_block();
+        let edid = Edid::parse(&data).unwrap();
+        assert_eq!(edid.model, "0x1234");
+        assert_eq!(edid.serial, "12345");
+
+        set_descriptor(&mut data, 72, DESCRIPTOR_TEXT, "Monitor");
+        data[12..16].copy_from_slice(&[0; 4]);
+        let edid = Edid::parse(&data).unwrap();
+        assert_eq!(edid.model, "Monitor");
+        assert_eq!(edid.serial, "");
+    }
+
+    #[test]
+    fn parse_rejects_invalid_blocks() {
+        let data = base_block();
+        assert!(Edid::parse(&data[..127]).is_none());
+
+        let mut data = data;
+        data[0] = 0xff;
+        assert!(Edid::parse(&data).is_none());
+    }
+}
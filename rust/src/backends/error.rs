@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::renderer::egl_util::EglError;
+
+/// Failures bringing up the compositor.
+///
+/// Supervisors can tell apart failures worth retrying once the environment changes, like a
+/// missing seat or monitor, from broken setups.
+#[derive(Debug)]
+pub enum CompositorError {
+    /// No session could be opened, usually because the process is not running on a seat.
+    NoSession,
+    /// The session could not be bound to the event loop.
+    Session(io::Error),
+    Udev(io::Error),
+    /// No DRM device could be opened.
+    NoDrmDevice,
+    /// None of the connectors of the DRM device has a monitor connected.
+    NoConnectedMonitor,
+    Drm(String),
+    Egl(EglError),
+    EventLoop(io::Error),
+    /// The wayland socket could not be created.
+    Socket(io::Error),
+    Window(String),
+    /// libinput could not be set up for the seat.
+    Input(io::Error),
+    /// SIGTERM and SIGINT could not be handled by the event loop.
+    Signals(io::Error),
+}
+
+impl fmt::Display for CompositorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompositorError::NoSession => write!(f, "no session available"),
+            CompositorError::Session(err) => write!(f, "failed to bind the session: {}", err),
+            CompositorError::Udev(err) => write!(f, "udev failure: {}", err),
+            CompositorError::NoDrmDevice => write!(f, "no DRM device found"),
+            CompositorError::NoConnectedMonitor => write!(f, "no connected monitor found"),
+            CompositorError::Drm(err) => write!(f, "DRM failure: {}", err),
+            CompositorError::Egl(err) => write!(f, "EGL failure: {}", err),
+            CompositorError::EventLoop(err) => write!(f, "failed to create event loop: {}", err),
+            CompositorError::Socket(err) => write!(f, "failed to create wayland socket: {}", err),
+            CompositorError::Window(err) => write!(f, "failed to create window: {}", err),
+            CompositorError::Input(err) => write!(f, "failed to set up input: {}", err),
+            CompositorError::Signals(err) => write!(f, "failed to handle signals: {}", err),
+        }
+    }
+}
+
+impl Error for CompositorError {}
+
+impl From<EglError> for CompositorError {
+    fn from(err: EglError) -> Self {
+        CompositorError::Egl(err)
+    }
+}
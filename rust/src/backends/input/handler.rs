@@ -1,36 +1,122 @@
-
-
-
-use smithay::{
-    backend::input::{
-        self, Event, InputBackend, InputHandler, KeyState, KeyboardKeyEvent, PointerAxisEvent,
-        PointerButtonEvent, PointerMotionAbsoluteEvent, PointerMotionEvent,
-    },
-    reexports::wayland_server::protocol::wl_pointer,
-    wayland::{
-        seat::{AxisFrame, KeyboardHandle, ModifiersState, PointerHandle},
-        SERIAL_COUNTER as SCOUNTER,
-    },
+use smithay::backend::input::{
+    self, Axis, InputBackend, InputHandler, KeyboardKeyEvent, MouseButton, MouseButtonState,
+    PointerAxisEvent, PointerButtonEvent, PointerMotionAbsoluteEvent, PointerMotionEvent,
+    TouchCancelEvent, TouchDownEvent, TouchMotionEvent, TouchSlot, TouchUpEvent,
 };
 
+use crate::engine::PointerEvent;
+use crate::flutter::ffi::{
+    FlutterPointerDeviceKind, FlutterPointerMouseButtons, FlutterPointerPhase,
+};
+use crate::{FlutterCompositor, FlutterCompositorWeakRef};
+use log::debug;
+use std::collections::HashMap;
 
-pub use xkbcommon::xkb::{keysyms, Keysym};
-
-use crate::FlutterCompositorWeakRef;
-use log::{debug, error, info, trace, warn};
-
-
-use crate::smithay::backend::input::TouchDownEvent;
-use std::borrow::Borrow;
+/// Device id of the mouse, touch points get the following ones.
+const MOUSE_DEVICE: i32 = 0;
+/// Pixels scrolled per wheel step.
+const SCROLL_STEP: f64 = 20.0;
 
 pub struct FlutterInputHandler {
     compositor: FlutterCompositorWeakRef,
+    /// Whether flutter knows about the mouse, which has to be added before its first event.
+    mouse_added: bool,
+    /// Pressed mouse buttons, as `FlutterPointerMouseButtons` flags.
+    buttons: i64,
+    /// Device id and last position of the touch points on the screen.
+    touches: HashMap<Option<TouchSlot>, (i32, (f64, f64))>,
 }
 
 impl FlutterInputHandler {
     pub fn new(compositor: FlutterCompositorWeakRef) -> Self {
-        Self { compositor }
+        Self {
+            compositor,
+            mouse_added: false,
+            buttons: 0,
+            touches: HashMap::new(),
+        }
+    }
+
+    /// Sends a mouse event at the cursor location, adding the mouse first if needed.
+    fn send_mouse_event(
+        &mut self,
+        compositor: &FlutterCompositor,
+        phase: FlutterPointerPhase,
+        scroll: (f64, f64),
+    ) {
+        let location = compositor.backend.cursor.location();
+        if !self.mouse_added {
+            self.mouse_added = true;
+            self.send_mouse_phase(compositor, FlutterPointerPhase::kAdd, location, (0.0, 0.0));
+        }
+        self.send_mouse_phase(compositor, phase, location, scroll);
+    }
+
+    fn send_mouse_phase(
+        &self,
+        compositor: &FlutterCompositor,
+        phase: FlutterPointerPhase,
+        location: (f64, f64),
+        scroll: (f64, f64),
+    ) {
+        compositor.engine.send_pointer_event(PointerEvent {
+            phase,
+            kind: FlutterPointerDeviceKind::kFlutterPointerDeviceKindMouse,
+            device: MOUSE_DEVICE,
+            location,
+            buttons: self.buttons,
+            scroll,
+        });
+    }
+
+    /// Phase of mouse motion, which is a drag while a button is pressed.
+    fn motion_phase(&self) -> FlutterPointerPhase {
+        if self.buttons == 0 {
+            FlutterPointerPhase::kHover
+        } else {
+            FlutterPointerPhase::kMove
+        }
     }
+
+    fn send_touch_event(
+        compositor: &FlutterCompositor,
+        phase: FlutterPointerPhase,
+        device: i32,
+        location: (f64, f64),
+    ) {
+        compositor.engine.send_pointer_event(PointerEvent {
+            phase,
+            kind: FlutterPointerDeviceKind::kFlutterPointerDeviceKindTouch,
+            device,
+            location,
+            buttons: 0,
+            scroll: (0.0, 0.0),
+        });
+    }
+
+    /// Ends a touch point, either lifted or cancelled, and removes its device.
+    fn end_touch(
+        &mut self,
+        compositor: &FlutterCompositor,
+        slot: Option<TouchSlot>,
+        phase: FlutterPointerPhase,
+    ) {
+        if let Some((device, location)) = self.touches.remove(&slot) {
+            Self::send_touch_event(compositor, phase, device, location);
+            Self::send_touch_event(compositor, FlutterPointerPhase::kRemove, device, location);
+        }
+    }
+}
+
+/// Flutter flag of a mouse button, or zero for buttons flutter does not know.
+fn button_flag(button: MouseButton) -> i64 {
+    let button = match button {
+        MouseButton::Left => FlutterPointerMouseButtons::kFlutterPointerButtonMousePrimary,
+        MouseButton::Right => FlutterPointerMouseButtons::kFlutterPointerButtonMouseSecondary,
+        MouseButton::Middle => FlutterPointerMouseButtons::kFlutterPointerButtonMouseMiddle,
+        MouseButton::Other(_) => return 0,
+    };
+    button as i64
 }
 
 impl<B: InputBackend> InputHandler<B> for FlutterInputHandler {
@@ -59,42 +145,118 @@ impl<B: InputBackend> InputHandler<B> for FlutterInputHandler {
         manager.key(state, keycode);
     }
 
-    fn on_pointer_move(&mut self, _: &input::Seat, _evt: B::PointerMotionEvent) {
-        // TODO: Implement mouse support
+    fn on_pointer_move(&mut self, _: &input::Seat, evt: B::PointerMotionEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        let backend = &compositor.backend;
+
+        let (width, height) = backend.get_framebuffer_size();
+        let (x, y) = backend.cursor.location();
+        let x = (x + evt.delta_x() as f64).max(0.0).min(width as f64);
+        let y = (y + evt.delta_y() as f64).max(0.0).min(height as f64);
+        backend.set_pointer_location((x, y));
+
+        let phase = self.motion_phase();
+        self.send_mouse_event(&compositor, phase, (0.0, 0.0));
     }
 
-    fn on_pointer_move_absolute(&mut self, _: &input::Seat, _evt: B::PointerMotionAbsoluteEvent) {
-        // TODO: Implement mouse support
+    fn on_pointer_move_absolute(&mut self, _: &input::Seat, evt: B::PointerMotionAbsoluteEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        let backend = &compositor.backend;
+
+        let location = backend.absolute_location(|size| evt.position_transformed(size));
+        backend.set_pointer_location(location);
+
+        let phase = self.motion_phase();
+        self.send_mouse_event(&compositor, phase, (0.0, 0.0));
     }
 
-    fn on_pointer_button(&mut self, _: &input::Seat, _evt: B::PointerButtonEvent) {
-        // TODO: Implement mouse support
+    fn on_pointer_button(&mut self, _: &input::Seat, evt: B::PointerButtonEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+
+        let flag = button_flag(evt.button());
+        let pressed = self.buttons;
+        match evt.state() {
+            MouseButtonState::Pressed => self.buttons |= flag,
+            MouseButtonState::Released => self.buttons &= !flag,
+        }
+
+        if pressed == self.buttons {
+            return;
+        }
+
+        // Flutter only sees a press of the first button and a release of the last one
+        let phase = if pressed == 0 {
+            FlutterPointerPhase::kDown
+        } else if self.buttons == 0 {
+            FlutterPointerPhase::kUp
+        } else {
+            FlutterPointerPhase::kMove
+        };
+        self.send_mouse_event(&compositor, phase, (0.0, 0.0));
     }
 
-    fn on_pointer_axis(&mut self, _: &input::Seat, _evt: B::PointerAxisEvent) {
-        // TODO: Implement mouse support
+    fn on_pointer_axis(&mut self, _: &input::Seat, evt: B::PointerAxisEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+
+        let amount = |axis| {
+            evt.amount_discrete(&axis)
+                .map(|steps| steps * SCROLL_STEP)
+                .or_else(|| evt.amount(&axis))
+                .unwrap_or(0.0)
+        };
+        let scroll = (amount(Axis::Horizontal), amount(Axis::Vertical));
+        if scroll != (0.0, 0.0) {
+            let phase = self.motion_phase();
+            self.send_mouse_event(&compositor, phase, scroll);
+        }
     }
 
     fn on_touch_down(&mut self, _: &input::Seat, evt: B::TouchDownEvent) {
-        info!(
-            "TOUCH DOWN {:?} >> {:?}",
-            evt.position(),
-            evt.position_transformed((1920, 1080))
-        );
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        let location = compositor
+            .backend
+            .absolute_location(|size| evt.position_transformed(size));
+        debug!("Touch down at {:?}", location);
 
-        // TODO: Implement touch support
+        // A slot going down again was never lifted
+        self.end_touch(&compositor, evt.slot(), FlutterPointerPhase::kCancel);
+        let device = (MOUSE_DEVICE + 1..)
+            .find(|&id| self.touches.values().all(|&(device, _)| device != id))
+            .unwrap();
+        self.touches.insert(evt.slot(), (device, location));
+
+        Self::send_touch_event(&compositor, FlutterPointerPhase::kAdd, device, location);
+        Self::send_touch_event(&compositor, FlutterPointerPhase::kDown, device, location);
     }
-    fn on_touch_motion(&mut self, _: &input::Seat, _: B::TouchMotionEvent) {
-        // TODO: Implement touch support
+    fn on_touch_motion(&mut self, _: &input::Seat, evt: B::TouchMotionEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        let location = compositor
+            .backend
+            .absolute_location(|size| evt.position_transformed(size));
+
+        if let Some(touch) = self.touches.get_mut(&evt.slot()) {
+            touch.1 = location;
+            Self::send_touch_event(&compositor, FlutterPointerPhase::kMove, touch.0, location);
+        }
     }
-    fn on_touch_up(&mut self, _: &input::Seat, _: B::TouchUpEvent) {
-        // TODO: Implement touch support
+    fn on_touch_up(&mut self, _: &input::Seat, evt: B::TouchUpEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        self.end_touch(&compositor, evt.slot(), FlutterPointerPhase::kUp);
     }
-    fn on_touch_cancel(&mut self, _: &input::Seat, _: B::TouchCancelEvent) {
-        // TODO: Implement touch support
+    fn on_touch_cancel(&mut self, _: &input::Seat, evt: B::TouchCancelEvent) {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        self.end_touch(&compositor, evt.slot(), FlutterPointerPhase::kCancel);
     }
     fn on_touch_frame(&mut self, _: &input::Seat, _: B::TouchFrameEvent) {
-        // TODO: Implement touch support
+        // Flutter gets every touch event as it comes
     }
     fn on_input_config_changed(&mut self, _: &mut B::InputConfig) {
         // TODO: Implement touch support
@@ -7,12 +7,10 @@ use std::time::{Duration};
 use wayland_server::protocol::wl_keyboard::WlKeyboard;
 use xkbcommon::xkb;
 
-use crate::flutter::channel::{
-    BasicMessageChannel, ChannelRegistry, MessageChannel, MessageHandler,
-};
-use crate::flutter::codec::{json_codec, Value};
-use crate::flutter::error::MessageError;
-use crate::{FlutterCompositorRef, FlutterCompositorWeakRef};
+use crate::channel_util::IgnoreHandler;
+use crate::flutter::channel::{BasicMessageChannel, ChannelRegistry, MessageChannel};
+use crate::flutter::codec::json_codec;
+use crate::FlutterCompositorWeakRef;
 use log::{debug};
 
 use crate::backends::input::glfw::{GLFW_KEY_UNKNOWN, GLFW_MAPPING};
@@ -55,6 +53,8 @@ pub struct InputManager {
     keymap: xkb::Keymap,
     state: RefCell<xkb::State>,
     ongoing: RefCell<Option<(u32, mpsc::Sender<()>)>>,
+    /// Keys held down, released when the keyboard is reset.
+    pressed: RefCell<Vec<u32>>,
     rate: i32,
     delay: i32,
 
@@ -85,6 +85,7 @@ impl InputManager {
             keymap,
             state: RefCell::new(state),
             ongoing: RefCell::new(None),
+            pressed: RefCell::new(vec![]),
             rate: 500,
             delay: 1000,
             ignore_handler: Arc::new(RwLock::new(IgnoreHandler)),
@@ -114,6 +115,8 @@ impl InputManager {
 
         match state {
             KeyState::Released => {
+                self.pressed.borrow_mut().retain(|key| *key != code);
+
                 // Check if we are repeating a character
                 let current_val = self.ongoing.borrow_mut().take();
                 if let Some((current_key, sender)) = current_val {
@@ -137,6 +140,8 @@ impl InputManager {
                     }
                 }
 
+                self.pressed.borrow_mut().push(code);
+
                 // replace any previously repeating key
                 let (sender, receiver) = mpsc::channel();
                 self.ongoing.replace(Some((code, sender)));
@@ -177,6 +182,18 @@ impl InputManager {
         }
     }
 
+    /// Releases all keys and stops repeating, for when key releases may have been missed.
+    pub fn reset(&self) {
+        // Dropping the sender stops the repeat thread
+        self.ongoing.replace(None);
+
+        let pressed = self.pressed.replace(vec![]);
+        for code in pressed {
+            self.key_event(KeyState::Released, code);
+        }
+        self.state.replace(xkb::State::new(&self.keymap));
+    }
+
     fn key_event(&self, keystate: KeyState, rawcode: u32) {
         let mut state = self.state.borrow_mut();
 
@@ -234,11 +251,3 @@ impl InputManager {
         }
     }
 }
-
-struct IgnoreHandler;
-
-impl MessageHandler for IgnoreHandler {
-    fn on_message(&mut self, _: Value, _: FlutterCompositorRef) -> Result<Value, MessageError> {
-        Ok(Value::Null)
-    }
-}
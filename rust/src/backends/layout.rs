@@ -0,0 +1,195 @@
+use smithay::reexports::wayland_server::protocol::wl_output::Transform;
+use smithay::utils::Rectangle;
+use smithay::wayland::output::Mode;
+
+/// How flutter is shown when several monitors are connected.
+///
+/// The engine drives a single view, so monitors either share one large flutter surface or show
+/// the same one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MonitorLayout {
+    /// One flutter surface covering all monitors placed side by side, each showing its region.
+    Span,
+    /// Every monitor shows the whole flutter surface, which has the size of the first monitor.
+    Mirror,
+}
+
+// Deriving it needs `#[default]`, which is only stable since Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for MonitorLayout {
+    fn default() -> Self {
+        MonitorLayout::Span
+    }
+}
+
+/// A display mode to use for a monitor instead of its preferred one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeSelection {
+    pub width: u32,
+    pub height: u32,
+    /// Refresh rate in mHz, the closest available one is used. The highest one is used if `None`.
+    pub refresh: Option<i32>,
+}
+
+/// A monitor as advertised to clients through `wl_output`.
+#[derive(Clone, PartialEq)]
+pub(crate) struct OutputInfo {
+    pub name: String,
+    pub make: String,
+    pub model: String,
+    /// Serial number of the monitor, empty if unknown.
+    pub serial: String,
+    /// Region of the flutter surface shown on the monitor.
+    pub region: Rectangle,
+    /// Resolution of the monitor in pixels.
+    pub size: (u32, u32),
+    /// Size of the monitor in millimetres, or zero if unknown.
+    pub size_mm: (u32, u32),
+    /// Refresh rate in mHz.
+    pub refresh: i32,
+    pub transform: Transform,
+    /// Modes the monitor supports, the preferred one first.
+    pub modes: Vec<Mode>,
+    /// Whether the monitor is on, rather than turned off through DPMS.
+    pub powered: bool,
+}
+
+/// A monitor being connected, disconnected or switching modes, reported to flutter.
+pub(crate) enum OutputEvent {
+    Added(OutputInfo),
+    /// The monitor switched to another mode.
+    Changed(OutputInfo),
+    /// The name of the disconnected monitor.
+    Removed(String),
+}
+
+/// Places monitors of the given sizes, in order, returning the region of the flutter surface
+/// each of them shows and the size of the flutter surface.
+pub(crate) fn arrange(layout: MonitorLayout, sizes: &[(u32, u32)]) -> (Vec<Rectangle>, (u32, u32)) {
+    match layout {
+        MonitorLayout::Span => {
+            let mut regions = Vec::with_capacity(sizes.len());
+            let mut width = 0;
+            let mut height = 0;
+            for &(w, h) in sizes {
+                regions.push(Rectangle {
+                    x: width as i32,
+                    y: 0,
+                    width: w as i32,
+                    height: h as i32,
+                });
+                width += w;
+                height = height.max(h);
+            }
+            (regions, (width, height))
+        }
+        MonitorLayout::Mirror => {
+            let size = sizes.first().cloned().unwrap_or((0, 0));
+            let region = Rectangle {
+                x: 0,
+                y: 0,
+                width: size.0 as i32,
+                height: size.1 as i32,
+            };
+            (vec![region; sizes.len()], size)
+        }
+    }
+}
+
+/// Size of a monitor as flutter sees it, rotated by the transform.
+pub(crate) fn transformed_size(transform: Transform, size: (u32, u32)) -> (u32, u32) {
+    match transform {
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+            (size.1, size.0)
+        }
+        _ => size,
+    }
+}
+
+/// Maps a position on a monitor of the given size onto the transformed monitor, as flutter sees
+/// it. Both have their origin at the top left.
+pub(crate) fn transform_point(
+    transform: Transform,
+    (x, y): (f64, f64),
+    (width, height): (f64, f64),
+) -> (f64, f64) {
+    match transform {
+        Transform::_90 => (y, width - x),
+        Transform::_180 => (width - x, height - y),
+        Transform::_270 => (height - y, x),
+        Transform::Flipped => (width - x, y),
+        Transform::Flipped90 => (y, x),
+        Transform::Flipped180 => (x, height - y),
+        Transform::Flipped270 => (height - y, width - x),
+        _ => (x, y),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, width: i32, height: i32) -> Rectangle {
+        Rectangle {
+            x,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn span_places_monitors_side_by_side() {
+        let (regions, size) = arrange(MonitorLayout::Span, &[(1920, 1080), (1280, 1024)]);
+        assert_eq!(regions, vec![rect(0, 1920, 1080), rect(1920, 1280, 1024)]);
+        assert_eq!(size, (3200, 1080));
+    }
+
+    #[test]
+    fn mirror_uses_first_monitor() {
+        let (regions, size) = arrange(MonitorLayout::Mirror, &[(1920, 1080), (1280, 1024)]);
+        assert_eq!(regions, vec![rect(0, 1920, 1080); 2]);
+        assert_eq!(size, (1920, 1080));
+    }
+
+    #[test]
+    fn arrange_without_monitors() {
+        assert_eq!(arrange(MonitorLayout::Span, &[]), (vec![], (0, 0)));
+        assert_eq!(arrange(MonitorLayout::Mirror, &[]), (vec![], (0, 0)));
+    }
+
+    #[test]
+    fn transform_point_rotates_and_flips() {
+        let map = |transform| transform_point(transform, (10.0, 20.0), (100.0, 50.0));
+        assert_eq!(map(Transform::Normal), (10.0, 20.0));
+        assert_eq!(map(Transform::_90), (20.0, 90.0));
+        assert_eq!(map(Transform::_180), (90.0, 30.0));
+        assert_eq!(map(Transform::_270), (30.0, 10.0));
+        assert_eq!(map(Transform::Flipped), (90.0, 20.0));
+        assert_eq!(map(Transform::Flipped90), (20.0, 10.0));
+        assert_eq!(map(Transform::Flipped180), (10.0, 30.0));
+        assert_eq!(map(Transform::Flipped270), (30.0, 90.0));
+    }
+
+    #[test]
+    fn transform_point_stays_within_transformed_size() {
+        let transforms = [
+            Transform::Normal,
+            Transform::_90,
+            Transform::_180,
+            Transform::_270,
+            Transform::Flipped,
+            Transform::Flipped90,
+            Transform::Flipped180,
+            Transform::Flipped270,
+        ];
+        for &transform in &transforms {
+            let (width, height) = transformed_size(transform, (100, 50));
+            for &corner in &[(0.0, 0.0), (100.0, 0.0), (0.0, 50.0), (100.0, 50.0)] {
+                let (x, y) = transform_point(transform, corner, (100.0, 50.0));
+                assert!(x == 0.0 || x == f64::from(width), "{:?}", transform);
+                assert!(y == 0.0 || y == f64::from(height), "{:?}", transform);
+            }
+        }
+    }
+}
@@ -1,32 +1,73 @@
 use crate::backends::udev::UdevInner;
 use crate::backends::winit::WInitInner;
-use crate::shell::init_shell;
+use crate::shell::{init_shell, MyCompositorToken, MyWindowMap};
 use crate::FlutterCompositorWeakRef;
-use smithay::reexports::wayland_server::protocol::wl_output;
+use smithay::backend::egl::{BufferAccessError, EGLImages};
+use smithay::reexports::wayland_server::protocol::{wl_buffer, wl_output, wl_surface};
+use smithay::utils::Rectangle;
 use smithay::wayland::data_device::{
-    default_action_chooser, init_data_device,
+    default_action_chooser, init_data_device, DataDeviceEvent,
 };
 use smithay::wayland::output::{Mode, Output, PhysicalProperties};
 
 use smithay::wayland::shm::init_shm_global;
 use std::borrow::{Borrow, BorrowMut};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use wayland_server::calloop::EventLoop;
-use wayland_server::Display;
+use wayland_server::{Display, Global};
 
+use crate::backends::cursor::{CursorState, CursorStatus};
 use crate::backends::input::manager::InputManager;
+use crate::backends::layout::{OutputEvent, OutputInfo};
 use crate::backends::seat::FlutterSeat;
+use crate::backends::vsync::{monotonic_nanos, FrameScheduler, FrameTimes};
+use crate::capture::CaptureQueue;
+use crate::protocols::dmabuf::init_dmabuf_global;
+use crate::protocols::fractional_scale::{init_fractional_scale_global, FractionalScales};
+use crate::protocols::output_power::{init_output_power_global, OutputPowers};
+use crate::protocols::presentation::{
+    init_presentation_global, PresentationEvent, PresentationQueue,
+};
+use crate::protocols::screencopy::{init_screencopy_global, Screencopy, ScreencopyPolicy};
+use crate::protocols::viewporter::init_viewporter_global;
+use crate::protocols::xdg_output::{init_xdg_output_global, XdgOutputs};
+use crate::renderer::convert::Converter;
+use crate::renderer::cursor::SoftwareCursor;
+use crate::renderer::egl_util::WrappedDisplay;
+use crate::renderer::gl;
 use log::{debug, error, info, trace, warn};
 use std::ffi::c_void;
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use wayland_protocols::presentation_time::server::wp_presentation_feedback::Kind;
 
 pub(crate) mod udev;
 pub(crate) mod winit;
 
+mod error;
+pub use self::error::CompositorError;
+
+pub(crate) mod layout;
+pub use self::layout::{ModeSelection, MonitorLayout};
+
+mod dpms;
+mod drm_ioctl;
+mod edid;
+
 pub(crate) mod seat;
 
+pub(crate) mod cursor;
+pub(crate) mod xcursor;
+
 pub(crate) mod input;
 
+pub(crate) mod vsync;
+
+/// Name of the single output of the winit backend.
+const WINIT_OUTPUT: &str = "Winit";
+
 pub struct CompositorBackend {
     compositor: RefCell<FlutterCompositorWeakRef>,
     display: RefCell<Option<Display>>,
@@ -34,6 +75,28 @@ pub struct CompositorBackend {
     kind: CompositorBackendKind,
     seat: RefCell<Option<FlutterSeat>>,
     pub(crate) input: RefCell<Option<InputManager>>,
+    pub(crate) converter: RefCell<Option<Converter>>,
+    pub(crate) compositor_token: RefCell<Option<MyCompositorToken>>,
+    pub(crate) window_map: RefCell<Option<Rc<RefCell<MyWindowMap>>>>,
+    pub(crate) dnd_icon: Arc<Mutex<Option<wl_surface::WlSurface>>>,
+    outputs: RefCell<Vec<OutputGlobal>>,
+    output_events: Mutex<Vec<OutputEvent>>,
+    xdg_outputs: XdgOutputs,
+    output_powers: OutputPowers,
+    fractional_scales: FractionalScales,
+    scale_factor: Option<f64>,
+    monitor_layout: MonitorLayout,
+    mode_selections: HashMap<String, ModeSelection>,
+    transforms: HashMap<String, wl_output::Transform>,
+    gpu: Option<PathBuf>,
+    pub(crate) captures: CaptureQueue,
+    pub(crate) cursor: CursorState,
+    software_cursor: RefCell<Option<SoftwareCursor>>,
+    screencopy: Screencopy,
+    screencopy_policy: Option<ScreencopyPolicy>,
+    pub(crate) scheduler: FrameScheduler,
+    pub(crate) presentation: PresentationQueue,
+    presentations: Mutex<Vec<PresentationEvent>>,
 }
 
 pub enum CompositorBackendKind {
@@ -50,6 +113,28 @@ impl CompositorBackend {
             kind: CompositorBackendKind::WInit(WInitInner::default()),
             seat: RefCell::new(None),
             input: RefCell::new(None),
+            converter: RefCell::new(None),
+            compositor_token: RefCell::new(None),
+            window_map: RefCell::new(None),
+            dnd_icon: Arc::new(Mutex::new(None)),
+            outputs: RefCell::new(vec![]),
+            output_events: Mutex::new(vec![]),
+            xdg_outputs: XdgOutputs::default(),
+            output_powers: OutputPowers::default(),
+            fractional_scales: FractionalScales::default(),
+            scale_factor: None,
+            monitor_layout: MonitorLayout::default(),
+            mode_selections: HashMap::new(),
+            transforms: HashMap::new(),
+            gpu: None,
+            captures: CaptureQueue::default(),
+            cursor: CursorState::default(),
+            software_cursor: RefCell::new(None),
+            screencopy: Screencopy::default(),
+            screencopy_policy: None,
+            scheduler: FrameScheduler::default(),
+            presentation: PresentationQueue::default(),
+            presentations: Mutex::new(vec![]),
         }
     }
 
@@ -61,10 +146,72 @@ impl CompositorBackend {
             kind: CompositorBackendKind::TtyUDev(UdevInner::default()),
             seat: RefCell::new(None),
             input: RefCell::new(None),
+            converter: RefCell::new(None),
+            compositor_token: RefCell::new(None),
+            window_map: RefCell::new(None),
+            dnd_icon: Arc::new(Mutex::new(None)),
+            outputs: RefCell::new(vec![]),
+            output_events: Mutex::new(vec![]),
+            xdg_outputs: XdgOutputs::default(),
+            output_powers: OutputPowers::default(),
+            fractional_scales: FractionalScales::default(),
+            scale_factor: None,
+            monitor_layout: MonitorLayout::default(),
+            mode_selections: HashMap::new(),
+            transforms: HashMap::new(),
+            gpu: None,
+            captures: CaptureQueue::default(),
+            cursor: CursorState::default(),
+            software_cursor: RefCell::new(None),
+            screencopy: Screencopy::default(),
+            screencopy_policy: None,
+            scheduler: FrameScheduler::default(),
+            presentation: PresentationQueue::default(),
+            presentations: Mutex::new(vec![]),
         }
     }
 
-    pub fn init(&self, compositor: FlutterCompositorWeakRef) {
+    /// Overrides the scale factor, instead of deriving it from the output.
+    pub fn with_scale_factor(mut self, scale_factor: f64) -> Self {
+        self.scale_factor = Some(scale_factor);
+        self
+    }
+
+    /// Sets how flutter is shown when several monitors are connected.
+    pub fn with_monitor_layout(mut self, layout: MonitorLayout) -> Self {
+        self.monitor_layout = layout;
+        self
+    }
+
+    /// Sets the mode of the monitor with the given name, like `HDMI-A-1`, instead of its preferred
+    /// mode.
+    pub fn with_output_mode(mut self, output: &str, mode: ModeSelection) -> Self {
+        self.mode_selections.insert(output.to_string(), mode);
+        self
+    }
+
+    /// Rotates or flips the monitor with the given name, for monitors mounted in portrait or
+    /// behind mirrors.
+    pub fn with_output_transform(mut self, output: &str, transform: wl_output::Transform) -> Self {
+        self.transforms.insert(output.to_string(), transform);
+        self
+    }
+
+    /// Renders on the GPU with the given device node, like `/dev/dri/card1`, instead of the one
+    /// the system booted with. Monitors of the other GPUs are still driven.
+    pub fn with_gpu(mut self, gpu: PathBuf) -> Self {
+        self.gpu = Some(gpu);
+        self
+    }
+
+    /// Sets which clients may capture the output through wlr-screencopy. Without a policy the
+    /// protocol is not advertised.
+    pub fn with_screencopy_policy(mut self, policy: ScreencopyPolicy) -> Self {
+        self.screencopy_policy = Some(policy);
+        self
+    }
+
+    pub fn init(&self, compositor: FlutterCompositorWeakRef) -> Result<(), CompositorError> {
         info!("Initialising backend");
         self.compositor.replace(compositor.clone());
         match &self.kind {
@@ -78,12 +225,12 @@ impl CompositorBackend {
 
         // Create display
         if let CompositorBackendKind::WInit(inner) = &self.kind {
-            inner.create_window();
+            inner.create_window()?;
         }
 
         // Create display
         debug!("Creating display");
-        let event_loop = EventLoop::<()>::new().unwrap();
+        let event_loop = EventLoop::<()>::new().map_err(CompositorError::EventLoop)?;
         let display = Display::new(event_loop.handle());
 
         self.event_loop.replace(Some(event_loop));
@@ -94,7 +241,11 @@ impl CompositorBackend {
 
         // Start wayland socket
         debug!("Creating wayland socket");
-        let name = display.add_socket_auto().unwrap().into_string().unwrap();
+        let name = display
+            .add_socket_auto()
+            .map_err(CompositorError::Socket)?
+            .to_string_lossy()
+            .into_owned();
         info!("Listening on wayland socket {}", name.clone());
         ::std::env::set_var("WAYLAND_DISPLAY", name);
 
@@ -104,13 +255,44 @@ impl CompositorBackend {
 
         // Init shell
         debug!("Creating shell");
-        let (compositor_token, _, _, _window_map) = init_shell(&mut display);
+        let (compositor_token, _, _, window_map) = init_shell(&mut display, compositor.clone());
+        self.window_map.replace(Some(window_map));
+        self.compositor_token.replace(Some(compositor_token));
+
+        // Init surface scaling
+        debug!("Initialising viewporter and fractional scale");
+        init_viewporter_global(&mut display, compositor_token);
+        init_fractional_scale_global(
+            &mut display,
+            compositor_token,
+            self.fractional_scales.clone(),
+            compositor.clone(),
+        );
+
+        // Init output descriptions
+        debug!("Initialising xdg-output");
+        init_xdg_output_global(&mut display, self.xdg_outputs.clone(), compositor.clone());
+
+        // Init output power management
+        debug!("Initialising output power management");
+        init_output_power_global(&mut display, self.output_powers.clone(), compositor.clone());
+
+        // Init presentation feedback
+        debug!("Initialising presentation time");
+        init_presentation_global(&mut display, compositor_token);
 
         // Enable clipboard/DND support
         debug!("Initialising data device");
+        let dnd_icon = self.dnd_icon.clone();
         init_data_device(
             &mut display,
             move |event| match event {
+                DataDeviceEvent::DnDStarted { icon, .. } => {
+                    *dnd_icon.lock().unwrap() = icon;
+                }
+                DataDeviceEvent::DnDDropped => {
+                    *dnd_icon.lock().unwrap() = None;
+                }
                 _ => {}
             },
             default_action_chooser,
@@ -120,7 +302,11 @@ impl CompositorBackend {
 
         // Create session
         if let CompositorBackendKind::TtyUDev(inner) = &self.kind {
-            inner.init_session();
+            inner.set_layout(self.monitor_layout);
+            inner.set_mode_selections(self.mode_selections.clone());
+            inner.set_transforms(self.transforms.clone());
+            inner.set_gpu(self.gpu.clone());
+            inner.init_session()?;
         }
 
         // Initialising IO
@@ -131,7 +317,7 @@ impl CompositorBackend {
 
         match &self.kind {
             CompositorBackendKind::WInit(inner) => {
-                inner.init_io(display);
+                inner.init_io(display)?;
             }
             CompositorBackendKind::TtyUDev(inner) => {
                 drop(display);
@@ -139,12 +325,37 @@ impl CompositorBackend {
                 inner.init_io(
                     compositor_token.clone(),
                     RefCell::borrow(self.event_loop.borrow()).as_ref().unwrap(),
-                );
+                )?;
                 display_borrow = self.display.borrow_mut();
                 display = display_borrow.as_mut().unwrap();
             }
         }
 
+        // Init dmabuf, once EGL is available to query the supported formats
+        debug!("Initialising dmabuf");
+        let formats = self
+            .with_upload_context(|_| unsafe {
+                WrappedDisplay::new().map(|display| display.dmabuf_formats())
+            })
+            .and_then(Result::ok)
+            .unwrap_or_default();
+        if formats.is_empty() {
+            warn!("No dmabuf formats available, not advertising linux-dmabuf");
+        } else {
+            init_dmabuf_global(&mut display, formats, compositor.clone());
+        }
+
+        // Init screencopy
+        if let Some(policy) = &self.screencopy_policy {
+            debug!("Initialising screencopy");
+            init_screencopy_global(
+                &mut display,
+                self.screencopy.clone(),
+                policy.clone(),
+                compositor.clone(),
+            );
+        }
+
         // Configure input
         debug!("Configuring input");
         let seat = FlutterSeat::new(
@@ -170,39 +381,17 @@ impl CompositorBackend {
             .expect("Failed to initialize the keyboard");
         self.keyboard.replace(Some(keyboard));*/
 
-        let (output, _output_global) = Output::new(
-            &mut display,
-            "Temporary Output".into(),
-            PhysicalProperties {
-                width: 0,
-                height: 0,
-                subpixel: wl_output::Subpixel::Unknown,
-                make: "Flutter-Compositor".into(),
-                model: "Generic".into(),
-            },
-            None,
-        );
+        info!("Output scale factor {}", self.get_scale_factor());
 
-        let (w, h) = self.get_framebuffer_size();
-        output.change_current_state(
-            Some(Mode {
-                width: w as i32,
-                height: h as i32,
-                refresh: 60_000,
-            }),
-            None,
-            None,
-        );
-        output.set_preferred(Mode {
-            width: w as i32,
-            height: h as i32,
-            refresh: 60_000,
-        });
+        drop(display);
+        drop(display_borrow);
+        self.refresh_outputs();
 
         info!("3");
+        Ok(())
     }
 
-    pub fn run(&self) {
+    pub fn run(&self) -> Result<(), CompositorError> {
         info!("run1");
         match &self.kind {
             CompositorBackendKind::WInit(_inner) => {}
@@ -212,11 +401,35 @@ impl CompositorBackend {
                     &mut RefCell::borrow_mut(self.event_loop.borrow())
                         .as_mut()
                         .unwrap(),
-                );
+                )?;
             }
         }
 
         info!("run2");
+        Ok(())
+    }
+
+    /// Tears the backend down once the compositor stopped, disconnecting clients and handing the
+    /// devices and VT back.
+    ///
+    /// Also releases what a failed `init` or `run` acquired.
+    pub fn shutdown(&self) {
+        if let Some(window_map) = self.window_map.replace(None) {
+            window_map.borrow_mut().clear();
+        }
+
+        // Globals cannot be destroyed once the display is gone
+        for output in self.outputs.borrow_mut().drain(..) {
+            output.global.destroy();
+        }
+        self.display.replace(None);
+
+        if let CompositorBackendKind::TtyUDev(inner) = &self.kind {
+            inner.shutdown();
+        }
+
+        // The display is only destroyed with its event source, disconnecting the clients
+        self.event_loop.replace(None);
     }
 
     pub fn update(&self) {
@@ -229,16 +442,127 @@ impl CompositorBackend {
             }
         }
 
+        if let Some(window_map) = self.window_map.borrow().as_ref() {
+            window_map.borrow_mut().refresh();
+        }
+
+        self.refresh_cursor();
+
+        self.screencopy.poll(self);
+
+        // Deliver frame callbacks and feedback for the frames presented since the last update
+        let presentations: Vec<PresentationEvent> =
+            self.presentations.lock().unwrap().drain(..).collect();
+        for event in presentations {
+            let outputs = self.outputs.borrow();
+            let output = outputs
+                .iter()
+                .find(|output| output.info.name == event.output)
+                .map(|output| &output.output);
+            self.presentation.presented(event, output);
+        }
+        self.presentation.flush_stale(monotonic_nanos());
+
         self.display.borrow().as_ref().unwrap().flush_clients();
     }
 
     pub fn present(&self) -> bool {
+        if self.captures.is_pending() {
+            let (width, height) = self.get_framebuffer_size();
+            let gl = gl::Gl::load_with(|proc| self.gl_proc_resolver(proc) as *const _);
+            self.captures.fulfil(&gl, self.framebuffer(), width, height);
+        }
+
+        match &self.kind {
+            CompositorBackendKind::WInit(inner) => {
+                // Without page flip events, the frame is considered presented once swapped
+                let presented = inner.present();
+                if presented {
+                    self.presentation.frame_submitted();
+                    self.push_presentation(PresentationEvent {
+                        output: WINIT_OUTPUT.into(),
+                        time: monotonic_nanos(),
+                        refresh: self.scheduler.refresh_period() as u32,
+                        sequence: 0,
+                        flags: Kind::empty(),
+                    });
+                }
+                presented
+            }
+            CompositorBackendKind::TtyUDev(inner) => {
+                if !inner.has_cursor_plane() {
+                    self.draw_software_cursor();
+                }
+
+                // Swapping queues a page flip, the next frame starts once it completes
+                let presented = inner.present();
+                if presented {
+                    self.presentation.frame_submitted();
+                    self.scheduler.flip_queued();
+                }
+                presented
+            }
+        }
+    }
+
+    fn draw_software_cursor(&self) {
+        let mut software_cursor = self.software_cursor.borrow_mut();
+        let software_cursor = software_cursor.get_or_insert_with(|| {
+            SoftwareCursor::new(gl::Gl::load_with(|proc| {
+                self.gl_proc_resolver(proc) as *const _
+            }))
+        });
+        software_cursor.draw(
+            &self.cursor,
+            self.framebuffer(),
+            self.get_framebuffer_size(),
+        );
+    }
+
+    /// Moves the pointer, updating the cursor plane straight away.
+    pub(crate) fn set_pointer_location(&self, location: (f64, f64)) {
+        self.cursor.set_location(location);
+        if let CompositorBackendKind::TtyUDev(inner) = &self.kind {
+            if let Some(position) = self.cursor.position() {
+                inner.move_cursor(position);
+            }
+        }
+    }
+
+    pub(crate) fn set_cursor(&self, status: CursorStatus) {
+        self.cursor.set_status(status);
+        self.refresh_cursor();
+    }
+
+    /// Picks up new contents of a surface with the cursor role.
+    pub(crate) fn cursor_committed(&self, surface: &wl_surface::WlSurface) {
+        self.cursor.surface_committed(surface);
+        self.refresh_cursor();
+    }
+
+    fn refresh_cursor(&self) {
+        if !self.cursor.refresh(*self.compositor_token.borrow()) {
+            return;
+        }
+
         match &self.kind {
-            CompositorBackendKind::WInit(inner) => inner.present(),
-            CompositorBackendKind::TtyUDev(inner) => inner.present(),
+            CompositorBackendKind::WInit(inner) => {
+                inner.set_cursor_image(self.cursor.image().as_ref());
+            }
+            CompositorBackendKind::TtyUDev(inner) => {
+                inner.set_cursor_image(self.cursor.image().as_ref());
+                if let Some(position) = self.cursor.position() {
+                    inner.move_cursor(position);
+                }
+            }
         }
     }
 
+    /// Records a presented frame, clients are notified on the next update.
+    pub(crate) fn push_presentation(&self, event: PresentationEvent) {
+        self.presentations.lock().unwrap().push(event);
+    }
+
     pub fn make_current(&self) -> bool {
         match &self.kind {
             CompositorBackendKind::WInit(inner) => inner.make_current(),
@@ -246,6 +570,17 @@ impl CompositorBackend {
         }
     }
 
+    /// Returns the framebuffer flutter renders into, 0 for the default framebuffer of the current
+    /// surface.
+    ///
+    /// Must be called with the rendering context current.
+    pub fn framebuffer(&self) -> u32 {
+        match &self.kind {
+            CompositorBackendKind::WInit(_inner) => 0,
+            CompositorBackendKind::TtyUDev(inner) => inner.framebuffer(),
+        }
+    }
+
     pub fn make_resource_current(&self) -> bool {
         match &self.kind {
             CompositorBackendKind::WInit(inner) => inner.make_resource_current(),
@@ -260,6 +595,26 @@ impl CompositorBackend {
         }
     }
 
+    pub fn egl_buffer_contents(
+        &self,
+        buffer: wl_buffer::WlBuffer,
+    ) -> Result<EGLImages, BufferAccessError> {
+        match &self.kind {
+            CompositorBackendKind::WInit(inner) => inner.egl_buffer_contents(buffer),
+            CompositorBackendKind::TtyUDev(inner) => inner.egl_buffer_contents(buffer),
+        }
+    }
+
+    pub fn with_upload_context<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&gl::Gl) -> T,
+    {
+        match &self.kind {
+            CompositorBackendKind::WInit(inner) => inner.with_upload_context(f),
+            CompositorBackendKind::TtyUDev(inner) => inner.with_upload_context(f),
+        }
+    }
+
     pub fn gl_proc_resolver(&self, proc: &str) -> *mut c_void {
         match &self.kind {
             CompositorBackendKind::WInit(inner) => inner.gl_proc_resolver(proc),
@@ -273,4 +628,316 @@ impl CompositorBackend {
             CompositorBackendKind::TtyUDev(inner) => inner.get_framebuffer_size(),
         }
     }
+
+    /// Returns the size of the primary monitor in millimetres, or zero if unknown.
+    pub fn get_physical_size(&self) -> (u32, u32) {
+        self.output_infos()
+            .first()
+            .map_or((0, 0), |info| info.size_mm)
+    }
+
+    /// Describes the monitors, the primary one first.
+    pub(crate) fn output_infos(&self) -> Vec<OutputInfo> {
+        let refresh = self.scheduler.refresh_rate() as i32;
+        match &self.kind {
+            CompositorBackendKind::WInit(inner) => {
+                let size = inner.get_framebuffer_size();
+                vec![OutputInfo {
+                    name: WINIT_OUTPUT.into(),
+                    make: "Flutter-Compositor".into(),
+                    model: "Winit".into(),
+                    serial: String::new(),
+                    region: Rectangle {
+                        x: 0,
+                        y: 0,
+                        width: size.0 as i32,
+                        height: size.1 as i32,
+                    },
+                    size,
+                    size_mm: inner.get_physical_size(),
+                    refresh,
+                    transform: wl_output::Transform::Normal,
+                    modes: vec![Mode {
+                        width: size.0 as i32,
+                        height: size.1 as i32,
+                        refresh,
+                    }],
+                    powered: true,
+                }]
+            }
+            CompositorBackendKind::TtyUDev(inner) => inner.output_infos(refresh),
+        }
+    }
+
+    /// Advertises the connected monitors to clients, removing the globals of monitors that went
+    /// away, and queues the changes for flutter.
+    pub(crate) fn refresh_outputs(&self) {
+        let infos = self.output_infos();
+        let scale = self.get_scale_factor();
+        self.fractional_scales.update(scale);
+
+        // Flutter only renders while a monitor shows it
+        let blanked = !infos.is_empty() && infos.iter().all(|info| !info.powered);
+        self.send_vsync(self.scheduler.set_blanked(blanked));
+
+        let mut display = self.display.borrow_mut();
+        let display = match display.as_mut() {
+            Some(display) => display,
+            None => return,
+        };
+        let mut outputs = self.outputs.borrow_mut();
+        let mut events = self.output_events.lock().unwrap();
+
+        let (kept, removed): (Vec<OutputGlobal>, Vec<OutputGlobal>) = outputs
+            .drain(..)
+            .partition(|output| infos.iter().any(|info| info.name == output.info.name));
+        for OutputGlobal {
+            info,
+            output,
+            global,
+        } in removed
+        {
+            info!("Removing output {}", info.name);
+            self.output_powers.remove(&output);
+            global.destroy();
+            events.push(OutputEvent::Removed(info.name));
+        }
+        *outputs = kept;
+
+        // The primary monitor paces flutter frames
+        if let Some(primary) = infos.first() {
+            self.scheduler.set_refresh_rate(primary.refresh as u32);
+        }
+
+        // Smithay does not expose the position of outputs, they are only placed on the flutter
+        // surface
+        for info in infos {
+            match outputs
+                .iter_mut()
+                .find(|output| output.info.name == info.name)
+            {
+                Some(output) => {
+                    output.update(&info, scale);
+                    self.xdg_outputs.update(&output.output, &info, scale);
+                    if output.info.powered != info.powered {
+                        self.output_powers.update(&output.output, &info);
+                    }
+                    if output.info != info {
+                        info!("Output {} changed", info.name);
+                        output.info = info.clone();
+                        events.push(OutputEvent::Changed(info));
+                    }
+                }
+                None => {
+                    info!("Adding output {}", info.name);
+                    outputs.push(OutputGlobal::new(display, &info, scale));
+                    events.push(OutputEvent::Added(info));
+                }
+            }
+        }
+    }
+
+    /// Maps a position on an absolute input device, like a touchscreen, onto the flutter surface.
+    ///
+    /// `position_transformed` scales the position on the device to a size.
+    pub(crate) fn absolute_location<F>(&self, position_transformed: F) -> (f64, f64)
+    where
+        F: FnOnce((u32, u32)) -> (f64, f64),
+    {
+        match &self.kind {
+            CompositorBackendKind::WInit(inner) => {
+                position_transformed(inner.get_framebuffer_size())
+            }
+            CompositorBackendKind::TtyUDev(inner) => inner
+                .absolute_location(position_transformed)
+                .unwrap_or((0.0, 0.0)),
+        }
+    }
+
+    /// Describes the monitor advertised through a `wl_output`.
+    pub(crate) fn output_info(&self, output: &wl_output::WlOutput) -> Option<OutputInfo> {
+        self.outputs
+            .borrow()
+            .iter()
+            .find(|global| global.output.owns(output))
+            .map(|global| global.info.clone())
+    }
+
+    /// Switches a monitor to the closest available mode, returning whether it has a matching one.
+    ///
+    /// The mode is kept for the monitor if it is reconnected.
+    pub(crate) fn set_output_mode(&self, output: &str, mode: ModeSelection) -> bool {
+        let changed = match &self.kind {
+            CompositorBackendKind::WInit(_inner) => {
+                debug!("Ignoring mode of {}, the window keeps its size", output);
+                false
+            }
+            CompositorBackendKind::TtyUDev(inner) => inner.set_output_mode(output, mode),
+        };
+        if changed {
+            self.refresh_outputs();
+        }
+        changed
+    }
+
+    /// Turns a monitor on or off through DPMS, returning whether it supports power management.
+    pub(crate) fn set_output_power(&self, output: &str, on: bool) -> bool {
+        let applied = match &self.kind {
+            CompositorBackendKind::WInit(_inner) => {
+                debug!(
+                    "Ignoring power mode of {}, the window cannot be turned off",
+                    output
+                );
+                false
+            }
+            CompositorBackendKind::TtyUDev(inner) => inner.set_output_power(output, on),
+        };
+        if !applied {
+            return false;
+        }
+
+        // The page flip on a primary monitor turned off never completes
+        let primary = self
+            .outputs
+            .borrow()
+            .first()
+            .map_or(false, |global| global.info.name == output);
+        if primary && !on {
+            self.send_vsync(self.scheduler.flip_cancelled());
+        }
+        self.refresh_outputs();
+        true
+    }
+
+    /// Starts the frame flutter is waiting for, if any.
+    fn send_vsync(&self, times: Option<FrameTimes>) {
+        let times = match times {
+            Some(times) => times,
+            None => return,
+        };
+        if let Some(compositor_ref) = self.compositor.borrow().upgrade() {
+            compositor_ref
+                .get()
+                .engine
+                .on_vsync(times.baton, times.start, times.target);
+        }
+    }
+
+    /// Returns the monitors connected, disconnected or switching modes since the last call.
+    pub(crate) fn take_output_events(&self) -> Vec<OutputEvent> {
+        self.output_events.lock().unwrap().drain(..).collect()
+    }
+
+    /// Returns the configured scale factor, falling back to the one reported by the backend or
+    /// the one derived from the pixel density of the output.
+    pub fn get_scale_factor(&self) -> f64 {
+        if let Some(scale) = self.scale_factor {
+            return scale;
+        }
+
+        let native = match &self.kind {
+            CompositorBackendKind::WInit(inner) => inner.get_scale_factor(),
+            CompositorBackendKind::TtyUDev(inner) => inner.get_scale_factor(),
+        };
+
+        // The density of the primary monitor decides, flutter has a single scale
+        native
+            .or_else(|| {
+                let info = self.output_infos().into_iter().next()?;
+                scale_from_density(info.size, info.size_mm)
+            })
+            .unwrap_or(1.0)
+    }
+}
+
+/// A monitor advertised to clients through `wl_output`.
+struct OutputGlobal {
+    /// What was last advertised and reported to flutter.
+    info: OutputInfo,
+    output: Output,
+    global: Global<wl_output::WlOutput>,
+}
+
+impl OutputGlobal {
+    fn new(display: &mut Display, info: &OutputInfo, scale: f64) -> Self {
+        let (output, global) = Output::new(
+            display,
+            info.name.clone(),
+            PhysicalProperties {
+                width: info.size_mm.0 as i32,
+                height: info.size_mm.1 as i32,
+                subpixel: wl_output::Subpixel::Unknown,
+                make: info.make.clone(),
+                model: info.model.clone(),
+            },
+            None,
+        );
+
+        for mode in &info.modes {
+            output.add_mode(*mode);
+        }
+        if let Some(preferred) = info.modes.first() {
+            output.set_preferred(*preferred);
+        }
+
+        let output = Self {
+            info: info.clone(),
+            output,
+            global,
+        };
+        output.update(info, scale);
+        output
+    }
+
+    /// Advertises the current mode and scale.
+    fn update(&self, info: &OutputInfo, scale: f64) {
+        let mode = Mode {
+            width: info.size.0 as i32,
+            height: info.size.1 as i32,
+            refresh: info.refresh,
+        };
+        // wl_output only supports integer scales, clients render at the next scale up
+        self.output.change_current_state(
+            Some(mode),
+            Some(info.transform),
+            Some(scale.ceil() as i32),
+        );
+    }
+}
+
+/// Scale factor giving roughly 96 logical pixels per inch, rounded to a quarter.
+fn scale_from_density(size: (u32, u32), size_mm: (u32, u32)) -> Option<f64> {
+    // Projectors and some broken EDIDs report tiny or zero sizes
+    if size_mm.0 < 100 || size_mm.1 < 100 {
+        return None;
+    }
+
+    let dpi = size.0 as f64 * 25.4 / size_mm.0 as f64;
+    let scale = (dpi / 96.0 * 4.0).round() / 4.0;
+    Some(scale.max(1.0).min(4.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_from_typical_densities() {
+        assert_eq!(scale_from_density((1920, 1080), (527, 296)), Some(1.0));
+        assert_eq!(scale_from_density((3840, 2160), (600, 340)), Some(1.75));
+        assert_eq!(scale_from_density((2560, 1600), (286, 179)), Some(2.25));
+    }
+
+    #[test]
+    fn scale_from_density_is_clamped() {
+        assert_eq!(scale_from_density((1024, 768), (400, 300)), Some(1.0));
+        assert_eq!(scale_from_density((7680, 4320), (100, 100)), Some(4.0));
+    }
+
+    #[test]
+    fn scale_from_density_ignores_bogus_sizes() {
+        assert_eq!(scale_from_density((1920, 1080), (0, 0)), None);
+        assert_eq!(scale_from_density((1920, 1080), (16, 9)), None);
+        assert_eq!(scale_from_density((1920, 1080), (527, 99)), None);
+    }
 }
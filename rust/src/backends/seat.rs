@@ -1,12 +1,13 @@
 use log::debug;
 
+use crate::backends::cursor::CursorStatus;
 use crate::FlutterCompositorWeakRef;
 use smithay::wayland::compositor::roles::Role;
 use smithay::wayland::compositor::CompositorToken;
 use smithay::wayland::seat::CursorImageRole;
 
 use wayland_server::{
-    protocol::{wl_seat, wl_surface},
+    protocol::{wl_pointer, wl_seat, wl_surface},
     Display, Global, NewResource,
 };
 
@@ -57,7 +58,7 @@ impl FlutterSeat {
 fn implement_seat<R>(
     compositor: FlutterCompositorWeakRef,
     new_seat: NewResource<wl_seat::WlSeat>,
-    _token: CompositorToken<R>,
+    token: CompositorToken<R>,
 ) -> wl_seat::WlSeat
 where
     R: Role<CursorImageRole> + 'static,
@@ -65,18 +66,15 @@ where
     let dest_comp = compositor.clone();
     new_seat.implement_closure(
         move |request, seat| {
-            let _compositor_weak = seat
+            let compositor_weak = seat
                 .as_ref()
                 .user_data::<FlutterCompositorWeakRef>()
                 .unwrap();
             //            let inner = arc.inner.borrow_mut();
             match request {
-                wl_seat::Request::GetPointer { id: _ } => {
+                wl_seat::Request::GetPointer { id } => {
                     debug!("GetPointer");
-                    // let pointer = self::pointer::implement_pointer(id, inner.pointer.as_ref(), token.clone());
-                    // if let Some(ref ptr_handle) = inner.pointer {
-                    //     ptr_handle.new_pointer(pointer);
-                    // }
+                    implement_pointer(compositor_weak.clone(), id, token);
                 }
                 wl_seat::Request::GetKeyboard { id: _ } => {
                     debug!("GetKeyboard");
@@ -108,3 +106,59 @@ where
         compositor,
     )
 }
+
+fn implement_pointer<R>(
+    compositor: FlutterCompositorWeakRef,
+    new_pointer: NewResource<wl_pointer::WlPointer>,
+    token: CompositorToken<R>,
+) -> wl_pointer::WlPointer
+where
+    R: Role<CursorImageRole> + 'static,
+{
+    new_pointer.implement_closure(
+        move |request, pointer: wl_pointer::WlPointer| match request {
+            wl_pointer::Request::SetCursor {
+                serial: _,
+                surface,
+                hotspot_x,
+                hotspot_y,
+            } => {
+                // TODO: Only accept cursors from the client with pointer focus, once clients
+                // receive pointer events
+                let status = match surface {
+                    Some(surface) => {
+                        let hotspot = (hotspot_x, hotspot_y);
+                        let updated = token
+                            .with_role_data(&surface, |role: &mut CursorImageRole| {
+                                role.hotspot = hotspot;
+                            })
+                            .is_ok();
+                        if !updated
+                            && token
+                                .give_role_with(&surface, CursorImageRole { hotspot })
+                                .is_err()
+                        {
+                            pointer.as_ref().post_error(
+                                wl_pointer::Error::Role as u32,
+                                "Given wl_surface has another role.".into(),
+                            );
+                            return;
+                        }
+                        CursorStatus::Surface(surface)
+                    }
+                    None => CursorStatus::Hidden,
+                };
+
+                if let Some(compositor_ref) = compositor.upgrade() {
+                    compositor_ref.get().backend.set_cursor(status);
+                }
+            }
+            wl_pointer::Request::Release => {
+                // Our destructors already handle it
+            }
+            _ => unreachable!(),
+        },
+        None::<fn(_)>,
+        (),
+    )
+}
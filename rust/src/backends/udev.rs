@@ -1,9 +1,10 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::HashMap,
-    io::Error as IoError,
+    fs,
+    io::{Error as IoError, ErrorKind},
     os::unix::io::{AsRawFd, RawFd},
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -11,7 +12,8 @@ use std::{
     },
 };
 
-use smithay::backend::egl::{EGLDisplay, EGLGraphicsBackend};
+use smithay::backend::egl::{BufferAccessError, EGLDisplay, EGLGraphicsBackend, EGLImages};
+use smithay::utils::Rectangle;
 use smithay::{
     backend::{
         drm::{
@@ -26,25 +28,31 @@ use smithay::{
         libinput::{libinput_bind, LibinputInputBackend, LibinputSessionInterface},
         session::{
             auto::{auto_session_bind, AutoSession},
-            notify_multiplexer, AsSessionObserver, Session, SessionNotifier,
+            notify_multiplexer, AsSessionObserver, Session, SessionNotifier, SessionObserver,
         },
         udev::{primary_gpu, udev_backend_bind, UdevBackend, UdevHandler},
     },
     reexports::{
         drm::control::{
-            connector::{Info as ConnectorInfo, State as ConnectorState},
+            connector::{
+                self, Info as ConnectorInfo, State as ConnectorState, Type as ConnectorType,
+            },
             crtc,
             encoder::Info as EncoderInfo,
+            Mode as DrmMode, ResourceHandle, ResourceHandles,
         },
         image::{ImageBuffer, Rgba},
         input::Libinput,
-        nix::{fcntl::OFlag, sys::stat::dev_t},
+        nix::{
+            fcntl::OFlag,
+            sys::stat::{dev_t, major, minor},
+        },
         wayland_server::{
             calloop::{
                 generic::{EventedFd, Generic},
                 EventLoop, LoopHandle, Source,
             },
-            protocol::{wl_output, wl_surface},
+            protocol::{wl_buffer, wl_output, wl_surface},
             Display,
         },
     },
@@ -61,15 +69,27 @@ use smithay::{
 
 use crate::shell::{Roles};
 
+use crate::backends::cursor::CursorImage;
+use crate::backends::dpms::set_dpms;
+use crate::backends::drm_ioctl;
+use crate::backends::edid::Edid;
+use crate::backends::error::CompositorError;
 use crate::backends::input::handler::FlutterInputHandler;
+use crate::backends::layout::{self, ModeSelection, MonitorLayout, OutputInfo};
+use crate::backends::vsync::{monotonic_nanos, Vblank};
 use crate::backends::{CompositorBackendKind};
+use crate::protocols::presentation::PresentationEvent;
 use crate::renderer::egl_util::{WrappedContext, WrappedDisplay};
+use crate::renderer::gl;
+use crate::renderer::output::OutputRenderer;
 use crate::FlutterCompositorWeakRef;
 
-use log::{debug, error, info};
+use log::{debug, error, info, trace, warn};
 use smithay::backend::graphics::gl::GLGraphicsBackend;
-use smithay::backend::session::auto::{AutoSessionNotifier, BoundAutoSession};
+use smithay::backend::session::auto::{AutoId, AutoSessionNotifier, BoundAutoSession};
 use std::ffi::c_void;
+use std::ptr;
+use wayland_protocols::presentation_time::server::wp_presentation_feedback::Kind;
 
 pub struct SessionFd(RawFd);
 impl AsRawFd for SessionFd {
@@ -78,6 +98,9 @@ impl AsRawFd for SessionFd {
     }
 }
 
+/// Width and height of the cursor buffer, most drivers only support 64x64 cursors.
+const CURSOR_PLANE_SIZE: u32 = 64;
+
 type RenderDevice =
     EglDevice<EglGbmBackend<LegacyDrmDevice<SessionFd>>, GbmDevice<LegacyDrmDevice<SessionFd>>>;
 type RenderSurface =
@@ -88,10 +111,70 @@ pub struct UdevInner {
     active_egl_context: RefCell<Option<EGLDisplay>>,
     session: RefCell<Option<AutoSession>>,
     notifier: RefCell<Option<AutoSessionNotifier>>,
-    surface: RefCell<Option<RenderSurface>>,
+    /// GPU to render on instead of the primary one, like `/dev/dri/card1`.
+    gpu: RefCell<Option<PathBuf>>,
+    /// Device number of the GPU flutter renders on.
+    render_device: Cell<Option<dev_t>>,
+    /// Whether the session is active, nothing is presented while switched to another VT.
+    active: Cell<bool>,
+    /// Connected monitors, the first one is the primary monitor pacing flutter frames.
+    outputs: RefCell<Vec<UdevOutput>>,
+    layout: Cell<MonitorLayout>,
+    /// Modes to use instead of the preferred ones, by output name.
+    mode_selections: RefCell<HashMap<String, ModeSelection>>,
+    /// Transforms of the monitors, by output name.
+    transforms: RefCell<HashMap<String, wl_output::Transform>>,
+    /// Size of the flutter surface covering all monitors.
+    size: Cell<(u32, u32)>,
+    /// Only used with more than one monitor, flutter renders straight into a single one.
+    renderer: RefCell<Option<OutputRenderer>>,
+    /// Show frames read back from the render GPU on the monitors of the other GPUs, by device.
+    copies: RefCell<HashMap<dev_t, OutputRenderer>>,
     display: RefCell<Option<WrappedDisplay>>,
     resource_context: RefCell<Option<WrappedContext>>,
+    upload_context: RefCell<Option<WrappedContext>>,
+    /// The last device that failed to be set up, reported when no device could be used.
+    device_error: RefCell<Option<CompositorError>>,
     bound_session: RefCell<Option<BoundAutoSession>>,
+    /// Observers registered on the session, unregistered on shutdown.
+    session_ids: RefCell<Vec<AutoId>>,
+    /// Removes the udev and libinput event sources.
+    remove_sources: RefCell<Option<Box<dyn FnOnce()>>>,
+    /// Whether the cursor is shown on the cursor plane, rather than composited in software.
+    cursor_plane: Cell<bool>,
+    /// The cursor plane contents with their hotspot, `None` while the cursor is hidden.
+    cursor_buffer: RefCell<Option<(CursorBuffer, (u32, u32))>>,
+    /// Position of the top left corner of the cursor on the flutter surface.
+    cursor_position: Cell<(i32, i32)>,
+}
+
+/// A monitor driven by a CRTC of a DRM device.
+struct UdevOutput {
+    name: String,
+    /// The DRM device driving the monitor.
+    device: dev_t,
+    /// File descriptor of the device, valid as long as the output.
+    fd: RawFd,
+    connector: connector::Handle,
+    crtc: crtc::Handle,
+    /// Index of the CRTC in the resources of the device, which vblank queries identify it by.
+    pipe: u32,
+    surface: RenderSurface,
+    /// Modes of the connector, the preferred one first.
+    modes: Vec<DrmMode>,
+    /// Size of the monitor in millimetres.
+    physical_size: (u32, u32),
+    edid: Option<Edid>,
+    /// Rotation and flip of the monitor, applied when showing flutter on it.
+    transform: wl_output::Transform,
+    /// Region of the flutter surface shown on the monitor.
+    source: Rectangle,
+    /// Whether a page flip is queued, new frames are not shown until it completes.
+    flip_pending: Cell<bool>,
+    /// Whether the cursor plane of the monitor currently shows the cursor.
+    cursor_shown: Cell<bool>,
+    /// Whether the monitor is on, nothing is presented on it while it is off.
+    powered: Cell<bool>,
 }
 
 impl Default for UdevInner {
@@ -101,10 +184,26 @@ impl Default for UdevInner {
             active_egl_context: RefCell::new(None),
             session: RefCell::new(None),
             notifier: RefCell::new(None),
-            surface: RefCell::new(None),
+            gpu: RefCell::new(None),
+            render_device: Cell::new(None),
+            active: Cell::new(true),
+            outputs: RefCell::new(vec![]),
+            layout: Cell::new(MonitorLayout::default()),
+            mode_selections: RefCell::new(HashMap::new()),
+            transforms: RefCell::new(HashMap::new()),
+            size: Cell::new((0, 0)),
+            renderer: RefCell::new(None),
+            copies: RefCell::new(HashMap::new()),
             display: RefCell::new(None),
             resource_context: RefCell::new(None),
+            upload_context: RefCell::new(None),
+            device_error: RefCell::new(None),
             bound_session: RefCell::new(None),
+            session_ids: RefCell::new(vec![]),
+            remove_sources: RefCell::new(None),
+            cursor_plane: Cell::new(true),
+            cursor_buffer: RefCell::new(None),
+            cursor_position: Cell::new((0, 0)),
         }
     }
 }
@@ -114,21 +213,363 @@ impl UdevInner {
         self.compositor.replace(compositor);
     }
 
-    pub fn init_session(&self) {
+    pub fn set_layout(&self, layout: MonitorLayout) {
+        self.layout.set(layout);
+    }
+
+    pub fn set_mode_selections(&self, selections: HashMap<String, ModeSelection>) {
+        self.mode_selections.replace(selections);
+    }
+
+    pub fn set_transforms(&self, transforms: HashMap<String, wl_output::Transform>) {
+        self.transforms.replace(transforms);
+    }
+
+    pub fn set_gpu(&self, gpu: Option<PathBuf>) {
+        self.gpu.replace(gpu);
+    }
+
+    /// Whether flutter renders offscreen, which is needed to show it on several monitors or
+    /// transformed.
+    fn renders_offscreen(&self) -> bool {
+        let outputs = self.outputs.borrow();
+        outputs.len() > 1
+            || outputs
+                .iter()
+                .any(|output| output.transform != wl_output::Transform::Normal)
+    }
+
+    /// Maps a position on an absolute input device, like a touchscreen, onto the flutter surface.
+    ///
+    /// The device is assumed to cover the primary monitor, and `position_transformed` scales its
+    /// position to a size.
+    pub fn absolute_location<F>(&self, position_transformed: F) -> Option<(f64, f64)>
+    where
+        F: FnOnce((u32, u32)) -> (f64, f64),
+    {
+        let outputs = self.outputs.borrow();
+        let primary = outputs.first()?;
+
+        let size = primary.surface.get_framebuffer_dimensions();
+        let position = position_transformed(size);
+        let (x, y) = layout::transform_point(
+            primary.transform,
+            position,
+            (f64::from(size.0), f64::from(size.1)),
+        );
+
+        // Mirrored monitors may show the flutter surface scaled
+        let (width, height) = layout::transformed_size(primary.transform, size);
+        let source = primary.source;
+        Some((
+            f64::from(source.x) + x * f64::from(source.width) / f64::from(width.max(1)),
+            f64::from(source.y) + y * f64::from(source.height) / f64::from(height.max(1)),
+        ))
+    }
+
+    /// Switches a monitor to the closest available mode, returning whether it has a matching one.
+    pub fn set_output_mode(&self, name: &str, selection: ModeSelection) -> bool {
+        let mode = {
+            let outputs = self.outputs.borrow();
+            let output = match outputs.iter().find(|output| output.name == name) {
+                Some(output) => output,
+                None => {
+                    warn!("No output named {}", name);
+                    return false;
+                }
+            };
+
+            let mode = match find_mode(&output.modes, &selection) {
+                Some(mode) => mode,
+                None => {
+                    warn!("{} has no mode matching {:?}", name, selection);
+                    return false;
+                }
+            };
+            if let Err(err) = output.surface.use_mode(Some(mode)) {
+                warn!("Failed to set the mode of {}: {}", name, err);
+                return false;
+            }
+            mode
+        };
+
+        info!("Switching {} to {:?}", name, mode);
+        self.mode_selections
+            .borrow_mut()
+            .insert(name.to_string(), selection);
+        self.arrange();
+        self.update_cursor_planes();
+        true
+    }
+
+    /// Turns a monitor on or off, returning whether it succeeded.
+    pub fn set_output_power(&self, name: &str, on: bool) -> bool {
+        let outputs = self.outputs.borrow();
+        let output = match outputs.iter().find(|output| output.name == name) {
+            Some(output) => output,
+            None => {
+                warn!("No output named {}", name);
+                return false;
+            }
+        };
+        if output.powered.get() == on {
+            return true;
+        }
+
+        let state = if on { "on" } else { "off" };
+        if let Err(err) = set_dpms(output.fd, output.connector.as_raw(), on) {
+            warn!("Failed to turn {} {}: {}", name, state, err);
+            return false;
+        }
+        info!("Turned {} {}", name, state);
+        output.powered.set(on);
+        // Flips pending when the monitor was turned off never complete
+        output.flip_pending.set(false);
+        true
+    }
+
+    /// Places the monitors on the flutter surface.
+    fn arrange(&self) {
+        let mut outputs = self.outputs.borrow_mut();
+        let sizes: Vec<(u32, u32)> = outputs
+            .iter()
+            .map(|output| {
+                layout::transformed_size(
+                    output.transform,
+                    output.surface.get_framebuffer_dimensions(),
+                )
+            })
+            .collect();
+        let (sources, size) = layout::arrange(self.layout.get(), &sizes);
+        for (output, source) in outputs.iter_mut().zip(sources) {
+            info!(
+                "Showing {:?} of the flutter surface on {}",
+                source, output.name
+            );
+            output.source = source;
+        }
+        self.size.set(size);
+    }
+
+    fn primary_crtc(&self) -> Option<crtc::Handle> {
+        self.outputs.borrow().first().map(|output| output.crtc)
+    }
+
+    /// Creates outputs for newly connected monitors of a device and drops those of disconnected
+    /// ones, returning whether the outputs changed.
+    ///
+    /// The outputs of the render GPU come first, so that the primary monitor is one of them.
+    /// Monitors of other GPUs are only driven while the render GPU drives one.
+    fn update_outputs(&self, device: &mut RenderDevice, card: &str) -> bool {
+        let device_id = device.device_id();
+        let render = self.render_device.get() == Some(device_id);
+        if !render && self.outputs.borrow().is_empty() {
+            return false;
+        }
+
+        let (res_handles, connectors) = match connectors(device) {
+            Ok(connectors) => connectors,
+            Err(err) => {
+                warn!("Failed to rescan connectors of {}: {}", card, err);
+                return false;
+            }
+        };
+        let connected: Vec<&(String, ConnectorInfo)> = connectors
+            .iter()
+            .filter(|(_, info)| info.connection_state() == ConnectorState::Connected)
+            .collect();
+
+        let mut outputs = self.outputs.borrow_mut();
+        let count = outputs.len();
+        // Dropping the surface turns the CRTC off
+        outputs.retain(|output| {
+            let connected = output.device != device_id
+                || connected
+                    .iter()
+                    .any(|(_, info)| info.handle() == output.connector);
+            if !connected {
+                info!("{} disconnected", output.name);
+            }
+            connected
+        });
+        let mut changed = outputs.len() != count;
+
+        for (name, info) in connected {
+            let crtcs: Vec<crtc::Handle> = outputs
+                .iter()
+                .filter(|output| output.device == device_id)
+                .map(|output| output.crtc)
+                .collect();
+            if outputs
+                .iter()
+                .any(|output| output.device == device_id && output.connector == info.handle())
+            {
+                continue;
+            }
+            let selections = self.mode_selections.borrow();
+            let transform = self
+                .transforms
+                .borrow()
+                .get(name)
+                .cloned()
+                .unwrap_or(wl_output::Transform::Normal);
+            match create_output(
+                device,
+                &res_handles,
+                card,
+                name,
+                info,
+                selections.get(name),
+                transform,
+                &crtcs,
+            ) {
+                Ok(Some(output)) => {
+                    // Keep the monitors of the render GPU ahead of the others
+                    let index = if render {
+                        outputs
+                            .iter()
+                            .position(|output| output.device != device_id)
+                            .unwrap_or_else(|| outputs.len())
+                    } else {
+                        outputs.len()
+                    };
+                    outputs.insert(index, output);
+                    changed = true;
+                }
+                Ok(None) => {}
+                Err(err) => warn!("Failed to set up {}: {}", name, err),
+            }
+        }
+
+        // Frames for the other GPUs are rendered with the context of the primary monitor
+        if render && outputs.iter().all(|output| output.device != device_id) && !outputs.is_empty()
+        {
+            info!("No monitor left on the render GPU, leaving the other GPUs off");
+            outputs.clear();
+            changed = true;
+        }
+        drop(outputs);
+
+        if changed {
+            self.arrange();
+            self.update_cursor_planes();
+        }
+        changed
+    }
+
+    /// Drops the outputs of a device other than the render GPU, which went away.
+    fn remove_outputs(&self, device: dev_t) {
+        self.outputs
+            .borrow_mut()
+            .retain(|output| output.device != device);
+        // The GL objects went away with the device
+        self.copies.borrow_mut().remove(&device);
+        self.arrange();
+        self.update_cursor_planes();
+    }
+
+    /// Forgets the outputs and contexts of the render GPU, which went away, and the outputs of
+    /// the other GPUs showing its frames.
+    fn remove_device(&self) {
+        self.render_device.set(None);
+        self.outputs.borrow_mut().clear();
+        self.size.set((0, 0));
+        // The GL objects went away with the device
+        self.renderer.replace(None);
+        self.copies.borrow_mut().clear();
+        self.resource_context.replace(None);
+        self.upload_context.replace(None);
+        self.display.replace(None);
+        self.active_egl_context.replace(None);
+    }
+
+    /// Whether a session event for the given device, or for the whole session if `None`, affects
+    /// the render GPU.
+    fn affects_device(&self, device: Option<(u32, u32)>) -> bool {
+        match (device, self.render_device.get()) {
+            (None, _) => true,
+            (Some(device), Some(id)) => device == (major(id) as u32, minor(id) as u32),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Stops presenting, returning whether the session was active.
+    fn pause(&self) -> bool {
+        self.active.replace(false)
+    }
+
+    /// Presents again, returning whether the session was paused.
+    ///
+    /// Modes and cursor planes are restored by the device when it gets DRM master back, but page
+    /// flips queued before the switch never complete.
+    fn resume(&self) -> bool {
+        if self.active.replace(true) {
+            return false;
+        }
+        for output in self.outputs.borrow().iter() {
+            output.flip_pending.set(false);
+            // Restoring the mode turns monitors back on
+            if !output.powered.get() {
+                if let Err(err) = set_dpms(output.fd, output.connector.as_raw(), false) {
+                    warn!("Failed to turn {} off again: {}", output.name, err);
+                }
+            }
+        }
+        true
+    }
+
+    pub fn init_session(&self) -> Result<(), CompositorError> {
         debug!("Initialising session");
-        let (session, mut notifier) = AutoSession::new(None).unwrap();
-        let (udev_observer, _udev_notifier) = notify_multiplexer();
-        let _udev_session_id = notifier.register(udev_observer);
+        let (session, notifier) = AutoSession::new(None).ok_or(CompositorError::NoSession)?;
 
         self.session.replace(Some(session));
         self.notifier.replace(Some(notifier));
+        Ok(())
     }
 
-    pub fn init_io(&self, compositor_token: CompositorToken<Roles>, event_loop: &EventLoop<()>) {
-        let context = ::smithay::reexports::udev::Context::new().unwrap();
+    pub fn init_io(
+        &self,
+        compositor_token: CompositorToken<Roles>,
+        event_loop: &EventLoop<()>,
+    ) -> Result<(), CompositorError> {
+        let context = ::smithay::reexports::udev::Context::new()
+            .map_err(|err| CompositorError::Udev(err.into()))?;
         let seat = self.session.borrow().as_ref().unwrap().seat();
 
-        let primary_gpu = primary_gpu(&context, &seat).unwrap_or_default();
+        // Hybrid laptops list the discrete GPU too, render on the one booted with by default
+        let render_gpu = match self.gpu.borrow().as_ref() {
+            Some(gpu) => Some(gpu.clone()),
+            None => primary_gpu(&context, &seat).unwrap_or_default(),
+        }
+        .and_then(|gpu| match gpu.canonicalize() {
+            Ok(gpu) => Some(gpu),
+            Err(err) => {
+                warn!("Failed to find GPU {:?}: {}", gpu, err);
+                None
+            }
+        });
+        info!("Rendering on {:?}", render_gpu);
+
+        // Devices come and go, so they register with their own notifier
+        let (udev_observer, udev_notifier) = notify_multiplexer();
+        let udev_session_id = self
+            .notifier
+            .borrow_mut()
+            .as_mut()
+            .unwrap()
+            .register(udev_observer);
+        self.session_ids.borrow_mut().push(udev_session_id);
+
+        let session_observer = UdevSessionObserver {
+            compositor: self.compositor.borrow().clone(),
+        };
+        let session_id = self
+            .notifier
+            .borrow_mut()
+            .as_mut()
+            .unwrap()
+            .register(session_observer);
+        self.session_ids.borrow_mut().push(session_id);
 
         // Init UDev backend
         let udev_backend = UdevBackend::new(
@@ -138,79 +579,231 @@ impl UdevInner {
                 compositor_token,
                 session: self.session.borrow().as_ref().unwrap().clone(),
                 //                display: display.clone(),
-                primary_gpu,
+                render_gpu,
                 loop_handle: event_loop.handle(),
-                //                notifier: udev_notifier,
+                notifier: udev_notifier,
+                devices: HashMap::new(),
             },
             seat.clone(),
             None,
         )
-        .unwrap();
+        .map_err(CompositorError::Udev)?;
 
-        let _udev_event_source = udev_backend_bind(udev_backend, &event_loop.handle())
+        let udev_event_source = udev_backend_bind(udev_backend, &event_loop.handle())
             .map_err(|e| -> IoError { e.into() })
-            .unwrap();
+            .map_err(CompositorError::Udev)?;
+
+        // Devices are added while binding the udev backend
+        if self.outputs.borrow().is_empty() {
+            let err = self.device_error.replace(None);
+            return Err(err.unwrap_or(CompositorError::NoDrmDevice));
+        }
+        if let Some(err) = self.device_error.replace(None) {
+            warn!("Ignoring failed device, another one is in use: {}", err);
+        }
 
         // Init libinput
         let mut libinput_context = Libinput::new_from_udev::<LibinputSessionInterface<AutoSession>>(
             self.session.borrow().as_ref().unwrap().clone().into(),
             &context,
         );
-        let _libinput_session_id = self
+        let libinput_session_id = self
             .notifier
             .borrow_mut()
             .as_mut()
             .unwrap()
             .register(libinput_context.observer());
-        libinput_context.udev_assign_seat(&seat).unwrap();
+        self.session_ids.borrow_mut().push(libinput_session_id);
+        libinput_context.udev_assign_seat(&seat).map_err(|_| {
+            CompositorError::Input(IoError::new(
+                ErrorKind::Other,
+                format!("Failed to assign seat {}", seat),
+            ))
+        })?;
         let mut libinput_backend = LibinputInputBackend::new(libinput_context, None);
         libinput_backend.set_handler(FlutterInputHandler::new(self.compositor.borrow().clone()));
 
-        let _libinput_event_source = libinput_bind(libinput_backend, event_loop.handle())
+        let libinput_event_source = libinput_bind(libinput_backend, event_loop.handle())
             .map_err(|e| -> IoError { e.into() })
-            .unwrap();
+            .map_err(CompositorError::Input)?;
+
+        self.remove_sources.replace(Some(Box::new(move || {
+            libinput_event_source.remove();
+            // Dropping the handler releases the devices
+            udev_event_source.remove();
+        })));
 
-        info!("Done init_io")
+        info!("Done init_io");
+        Ok(())
     }
 
     pub fn seat_name(&self) -> String {
         self.session.borrow().as_ref().unwrap().seat()
     }
 
-    pub fn run(&self, _display: &RefCell<Option<Display>>, event_loop: &mut EventLoop<()>) {
+    pub fn run(
+        &self,
+        _display: &RefCell<Option<Display>>,
+        event_loop: &mut EventLoop<()>,
+    ) -> Result<(), CompositorError> {
         let session_event_source =
             auto_session_bind(self.notifier.replace(None).unwrap(), &event_loop.handle())
-                .map_err(|(e, _)| e)
-                .unwrap();
+                .map_err(|(e, _)| CompositorError::Session(e))?;
 
         self.bound_session.replace(Some(session_event_source));
+        Ok(())
+    }
 
-        // Cleanup stuff
-        //        window_map.borrow_mut().clear();
-        //
-        //        let mut notifier = session_event_source.unbind();
-        //        notifier.unregister(libinput_session_id);
-        //        notifier.unregister(udev_session_id);
-        //
-        //        libinput_event_source.remove();
-        //        udev_event_source.remove();
-        //
-        //        Ok(())
+    /// Releases the devices and hands the VT back, once the compositor stopped or failed to start.
+    pub fn shutdown(&self) {
+        // The surfaces have to go before the devices they belong to
+        self.remove_device();
+        if let Some(remove_sources) = self.remove_sources.replace(None) {
+            remove_sources();
+        }
+
+        let notifier = match self.bound_session.replace(None) {
+            Some(bound_session) => Some(bound_session.unbind()),
+            // Starting up failed before the session was bound
+            None => self.notifier.replace(None),
+        };
+        if let Some(mut notifier) = notifier {
+            for id in self.session_ids.replace(vec![]) {
+                notifier.unregister(id);
+            }
+            // Dropping the notifier restores the VT
+            drop(notifier);
+        }
+        self.session.replace(None);
+        info!("Session closed");
     }
 
+    /// Shows the frame flutter rendered on every monitor, returning whether the primary monitor
+    /// queued a page flip.
     pub fn present(&self) -> bool {
-        match self.surface.borrow().as_ref().unwrap().swap_buffers() {
-            Ok(_) => true,
-            Err(_) => {
-                error!("Failed to present");
-                false
+        if !self.active.get() {
+            return false;
+        }
+
+        let outputs = self.outputs.borrow();
+        let primary = match outputs.first() {
+            Some(primary) => primary,
+            None => return false,
+        };
+
+        // A single untransformed monitor is rendered to directly
+        if !self.renders_offscreen() {
+            return primary.powered.get() && swap_buffers(primary);
+        }
+
+        let renderer = self.renderer.borrow();
+        let renderer = match renderer.as_ref() {
+            Some(renderer) => renderer,
+            None => return false,
+        };
+
+        // Monitors of other GPUs cannot sample the framebuffer, they get a copy of the frame
+        let copied = outputs.iter().any(|output| {
+            output.device != primary.device && output.powered.get() && !output.flip_pending.get()
+        });
+        let frame = if copied { renderer.read_frame() } else { None };
+        let mut uploaded = vec![];
+
+        let mut presented = false;
+        for (index, output) in outputs.iter().enumerate() {
+            // Monitors running at a lower refresh rate skip frames
+            if output.flip_pending.get() {
+                trace!("Page flip pending on {}, skipping frame", output.name);
+                continue;
+            }
+            if !output.powered.get() {
+                continue;
+            }
+
+            unsafe {
+                if output.surface.make_current().is_err() {
+                    error!("Failed to make {} current", output.name);
+                    continue;
+                }
+            }
+            let size = output.surface.get_framebuffer_dimensions();
+            if output.device == primary.device {
+                renderer.draw(output.source, output.transform, size);
+            } else {
+                let (frame_size, data) = match &frame {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                let mut copies = self.copies.borrow_mut();
+                let copy = copies.entry(output.device).or_insert_with(|| {
+                    OutputRenderer::new(gl::Gl::load_with(|proc| unsafe {
+                        output.surface.get_proc_address(proc) as *const _
+                    }))
+                });
+                // Monitors of a GPU share its context
+                if !uploaded.contains(&output.device) {
+                    copy.upload_frame(*frame_size, data);
+                    uploaded.push(output.device);
+                }
+                copy.draw(output.source, output.transform, size);
+            }
+            if swap_buffers(output) && index == 0 {
+                presented = true;
+            }
+        }
+
+        // Flutter keeps rendering with the surface of the primary monitor
+        unsafe {
+            if primary.surface.make_current().is_err() {
+                error!("Failed to make current");
+            }
+        }
+        presented
+    }
+
+    /// Notes that the page flip on a CRTC of a device completed, returning whether it drives the
+    /// primary monitor.
+    pub fn page_flipped(&self, device: dev_t, crtc: crtc::Handle) -> bool {
+        let outputs = self.outputs.borrow();
+        for (index, output) in outputs.iter().enumerate() {
+            if output.device == device && output.crtc == crtc {
+                output.flip_pending.set(false);
+                return index == 0;
+            }
+        }
+        false
+    }
+
+    /// Returns the name of the primary monitor.
+    pub fn primary_name(&self) -> Option<String> {
+        self.outputs
+            .borrow()
+            .first()
+            .map(|primary| primary.name.clone())
+    }
+
+    /// Returns the latest vblank of the primary monitor.
+    pub fn last_vblank(&self) -> Option<Vblank> {
+        let outputs = self.outputs.borrow();
+        let primary = outputs.first()?;
+        match drm_ioctl::last_vblank(primary.fd, primary.pipe) {
+            Ok(vblank) => Some(vblank),
+            Err(err) => {
+                debug!("Failed to query the vblank of {}: {}", primary.name, err);
+                None
             }
         }
     }
 
     pub fn make_current(&self) -> bool {
+        let outputs = self.outputs.borrow();
+        let primary = match outputs.first() {
+            Some(primary) => primary,
+            None => return false,
+        };
+
         unsafe {
-            match self.surface.borrow().as_ref().unwrap().make_current() {
+            match primary.surface.make_current() {
                 Ok(_) => true,
                 Err(_val) => {
                     error!("Failed to make current");
@@ -220,15 +813,34 @@ impl UdevInner {
         }
     }
 
+    /// Returns the framebuffer flutter renders into, the default framebuffer of the primary
+    /// monitor unless there are several or it is transformed.
+    ///
+    /// Must be called with the rendering context current.
+    pub fn framebuffer(&self) -> u32 {
+        if !self.renders_offscreen() {
+            return 0;
+        }
+
+        let mut renderer = self.renderer.borrow_mut();
+        let renderer = renderer.get_or_insert_with(|| {
+            OutputRenderer::new(gl::Gl::load_with(|proc| {
+                self.gl_proc_resolver(proc) as *const _
+            }))
+        });
+        renderer.framebuffer(self.size.get()).unwrap_or(0)
+    }
+
     pub fn make_resource_current(&self) -> bool {
+        let context_ref = self.resource_context.borrow();
+        let display_ref = self.display.borrow();
+        let (context, display) = match (context_ref.as_ref(), display_ref.as_ref()) {
+            (Some(context), Some(display)) => (context, display),
+            _ => return false,
+        };
+
         unsafe {
-            if !self
-                .resource_context
-                .borrow()
-                .as_ref()
-                .unwrap()
-                .apply_context(self.display.borrow().as_ref().unwrap())
-            {
+            if !context.apply_context(display) {
                 error!("Failed to make resource current");
                 return false;
             }
@@ -243,184 +855,914 @@ impl UdevInner {
         }
     }
 
-    pub fn gl_proc_resolver(&self, proc: &str) -> *mut c_void {
+    pub fn egl_buffer_contents(
+        &self,
+        buffer: wl_buffer::WlBuffer,
+    ) -> Result<EGLImages, BufferAccessError> {
+        match self.active_egl_context.borrow().as_ref() {
+            Some(display) => display.egl_buffer_contents(buffer),
+            None => Err(BufferAccessError::NotManaged(buffer)),
+        }
+    }
+
+    pub fn with_upload_context<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&gl::Gl) -> T,
+    {
+        let context_ref = self.upload_context.borrow();
+        let context = context_ref.as_ref()?;
+        let display_ref = self.display.borrow();
+        let display = display_ref.as_ref()?;
+
         unsafe {
-            self.surface
-                .borrow()
-                .as_ref()
-                .unwrap()
-                .get_proc_address(proc) as *mut c_void
+            if !context.apply_context(display) {
+                error!("Failed to make upload context current");
+                return None;
+            }
+
+            let result = f(&context.get_gl());
+            display.release_context();
+            Some(result)
+        }
+    }
+
+    pub fn gl_proc_resolver(&self, proc: &str) -> *mut c_void {
+        match self.outputs.borrow().first() {
+            Some(primary) => unsafe { primary.surface.get_proc_address(proc) as *mut c_void },
+            None => ptr::null_mut(),
         }
     }
 
     pub fn get_framebuffer_size(&self) -> (u32, u32) {
-        self.surface
+        self.size.get()
+    }
+
+    /// Describes the connected monitors, the primary one first.
+    pub fn output_infos(&self, default_refresh: i32) -> Vec<OutputInfo> {
+        self.outputs
             .borrow()
-            .as_ref()
-            .unwrap()
-            .get_framebuffer_dimensions()
+            .iter()
+            .map(|output| {
+                let mut modes: Vec<Mode> = vec![];
+                for mode in output.modes.iter().map(output_mode) {
+                    // Modes differing only in flags look the same to clients
+                    if !modes.contains(&mode) {
+                        modes.push(mode);
+                    }
+                }
+
+                // Monitors without EDID are told apart by their connector
+                let (make, model, serial) = match &output.edid {
+                    Some(edid) => (edid.make.clone(), edid.model.clone(), edid.serial.clone()),
+                    None => ("Unknown".into(), output.name.clone(), String::new()),
+                };
+
+                OutputInfo {
+                    name: output.name.clone(),
+                    make,
+                    model,
+                    serial,
+                    region: output.source,
+                    transform: output.transform,
+                    size: output.surface.get_framebuffer_dimensions(),
+                    size_mm: output.physical_size,
+                    refresh: output
+                        .surface
+                        .pending_mode()
+                        .map_or(default_refresh, |mode| refresh_rate(&mode)),
+                    modes,
+                    powered: output.powered.get(),
+                }
+            })
+            .collect()
     }
-}
 
-struct UdevHandlerImpl<Data: 'static> /*<S: SessionNotifier, Data: 'static>*/ {
-    compositor: FlutterCompositorWeakRef,
-    compositor_token: CompositorToken<Roles>,
-    session: AutoSession,
-    //    display: Rc<RefCell<Display>>,
-    primary_gpu: Option<PathBuf>,
-    loop_handle: LoopHandle<Data>,
-    //    notifier: S,
-}
+    pub fn get_scale_factor(&self) -> Option<f64> {
+        None
+    }
+
+    pub fn has_cursor_plane(&self) -> bool {
+        self.cursor_plane.get()
+    }
 
-impl</*S: SessionNotifier,*/ Data: 'static> UdevHandlerImpl</*S,*/ Data> {
-    pub fn scan_connectors(device: &mut RenderDevice) -> RenderSurface {
-        // Get a set of all modesetting resource handles (excluding planes):
-        let res_handles = device.resource_handles().unwrap();
+    /// Shows the image on the cursor planes, falling back to software composition if the planes
+    /// cannot show it.
+    pub fn set_cursor_image(&self, cursor: Option<&CursorImage>) {
+        // Applied again once the session is active, planes cannot be set without DRM master
+        if !self.active.get() {
+            return;
+        }
 
-        // Use first connected connector
-        let connector_infos: Vec<ConnectorInfo> = res_handles
-            .connectors()
+        let fits = cursor.map_or(true, |cursor| {
+            cursor.image.width() <= CURSOR_PLANE_SIZE && cursor.image.height() <= CURSOR_PLANE_SIZE
+        });
+        // The plane is not rotated with the monitor
+        let transformed = self
+            .outputs
+            .borrow()
             .iter()
-            .map(|conn| device.resource_info::<ConnectorInfo>(*conn).unwrap())
-            .filter(|conn| conn.connection_state() == ConnectorState::Connected)
-            .inspect(|conn| info!("Connected: {:?}", conn.connector_type()))
-            .collect();
+            .any(|output| output.transform != wl_output::Transform::Normal);
 
-        // very naive way of finding good crtc/encoder/connector combinations. This problem is np-complete
-        for connector_info in connector_infos {
-            let encoder_infos = connector_info
-                .encoders()
-                .iter()
-                .flat_map(|encoder_handle| device.resource_info::<EncoderInfo>(*encoder_handle))
-                .collect::<Vec<EncoderInfo>>();
-            for encoder_info in encoder_infos {
-                for crtc in res_handles.filter_crtcs(encoder_info.possible_crtcs()) {
-                    //                    if !backends.contains_key(&crtc) {
-                    info!("Found surface");
-                    let surface = device.create_surface(crtc).unwrap();
-                    return surface;
-                    //                    }
+        let buffer = match cursor {
+            Some(cursor) if fits && !transformed => {
+                // The plane is ARGB8888, which is BGRA in memory
+                let mut buffer = empty_cursor_buffer();
+                for (x, y, pixel) in cursor.image.enumerate_pixels() {
+                    let [r, g, b, a] = pixel.0;
+                    buffer.put_pixel(x, y, Rgba([b, g, r, a]));
                 }
+                let hotspot = (
+                    cursor.hotspot.0.max(0) as u32,
+                    cursor.hotspot.1.max(0) as u32,
+                );
+                Some((buffer, hotspot))
+            }
+            _ => None,
+        };
+        self.cursor_buffer.replace(buffer);
+
+        // Planes showing the cursor get the new image, or are cleared if there is none
+        let mut failed = false;
+        for output in self.outputs.borrow().iter() {
+            if output.cursor_shown.get() {
+                failed |= !self.show_cursor(output, true);
             }
         }
 
-        panic!("No surface found");
+        if failed {
+            if self.cursor_plane.replace(false) {
+                warn!("Failed to set the cursor plane, compositing the cursor in software");
+            }
+        } else if !fits {
+            debug!("Cursor image too large for the cursor plane, compositing it in software");
+            self.cursor_plane.set(false);
+        } else if transformed {
+            debug!("Monitors are transformed, compositing the cursor in software");
+            self.cursor_plane.set(false);
+        } else if !self.cursor_plane.replace(true) {
+            info!("Showing the cursor on the cursor plane");
+        }
+
+        self.update_cursor_planes();
+    }
+
+    /// Moves the cursor planes so that the top left corner of the image is at `position` on the
+    /// flutter surface.
+    pub fn move_cursor(&self, position: (i32, i32)) {
+        self.cursor_position.set(position);
+        self.update_cursor_planes();
+    }
+
+    /// Shows the cursor on the monitors it is over, and hides it on the others.
+    fn update_cursor_planes(&self) {
+        if !self.cursor_plane.get() || !self.active.get() {
+            return;
+        }
+
+        let (x, y) = self.cursor_position.get();
+        let size = CURSOR_PLANE_SIZE as i32;
+        let has_image = self.cursor_buffer.borrow().is_some();
+        for output in self.outputs.borrow().iter() {
+            // Mirrored monitors may show the flutter surface scaled
+            let (width, height) = output.surface.get_framebuffer_dimensions();
+            let source = output.source;
+            let local = (
+                (x - source.x) * width as i32 / source.width.max(1),
+                (y - source.y) * height as i32 / source.height.max(1),
+            );
+
+            let visible = has_image
+                && local.0 + size > 0
+                && local.1 + size > 0
+                && local.0 < width as i32
+                && local.1 < height as i32;
+            if visible != output.cursor_shown.get() && !self.show_cursor(output, visible) {
+                continue;
+            }
+
+            if visible {
+                // Smithay only takes unsigned positions, so the cursor stops at the top and left
+                // edges
+                let (x, y) = (local.0.max(0) as u32, local.1.max(0) as u32);
+                if let Err(err) = output.surface.set_cursor_position(x, y) {
+                    warn!(
+                        "Failed to move the cursor plane of {}: {:?}",
+                        output.name, err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Shows or hides the cursor on the cursor plane of a monitor, returning whether it succeeded.
+    fn show_cursor(&self, output: &UdevOutput, show: bool) -> bool {
+        let cursor_buffer = self.cursor_buffer.borrow();
+        // A transparent image hides the cursor, as the plane cannot be disabled through smithay
+        let hidden = (empty_cursor_buffer(), (0, 0));
+        let (buffer, hotspot) = match cursor_buffer.as_ref() {
+            Some(cursor) if show => cursor,
+            _ => &hidden,
+        };
+
+        match output.surface.set_cursor_representation(buffer, *hotspot) {
+            Ok(()) => {
+                output.cursor_shown.set(show && cursor_buffer.is_some());
+                true
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to set the cursor plane of {}: {:?}",
+                    output.name, err
+                );
+                false
+            }
+        }
     }
 }
 
-impl</*S: SessionNotifier,*/ Data: 'static> UdevHandler for UdevHandlerImpl</*S,*/ Data> {
-    fn device_added(&mut self, _device: dev_t, path: PathBuf) {
-        info!("device_added");
+type CursorBuffer = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+fn empty_cursor_buffer() -> CursorBuffer {
+    ImageBuffer::from_pixel(CURSOR_PLANE_SIZE, CURSOR_PLANE_SIZE, Rgba([0u8, 0, 0, 0]))
+}
+
+/// Swaps the buffers of a monitor, which queues a page flip.
+fn swap_buffers(output: &UdevOutput) -> bool {
+    match output.surface.swap_buffers() {
+        Ok(_) => {
+            output.flip_pending.set(true);
+            true
+        }
+        Err(_) => {
+            error!("Failed to present on {}", output.name);
+            false
+        }
+    }
+}
+
+/// Lists the connectors of a device, named like the kernel does after their type and numbered
+/// per type, like `HDMI-A-1`.
+fn connectors(
+    device: &RenderDevice,
+) -> Result<(ResourceHandles, Vec<(String, ConnectorInfo)>), CompositorError> {
+    // Get a set of all modesetting resource handles (excluding planes):
+    let res_handles = device
+        .resource_handles()
+        .map_err(|err| CompositorError::Drm(err.to_string()))?;
+
+    // Names stay the same while other connectors come and go
+    let mut counts: HashMap<&str, u32> = HashMap::new();
+    let connectors = res_handles
+        .connectors()
+        .iter()
+        .flat_map(|conn| device.resource_info::<ConnectorInfo>(*conn))
+        .map(|info| {
+            let kind = connector_type_name(info.connector_type());
+            let count = counts.entry(kind).or_insert(0);
+            *count += 1;
+            (format!("{}-{}", kind, count), info)
+        })
+        .collect();
+    Ok((res_handles, connectors))
+}
+
+fn connector_type_name(kind: ConnectorType) -> &'static str {
+    match kind {
+        ConnectorType::VGA => "VGA",
+        ConnectorType::DVII => "DVI-I",
+        ConnectorType::DVID => "DVI-D",
+        ConnectorType::DVIA => "DVI-A",
+        ConnectorType::Composite => "Composite",
+        ConnectorType::SVideo => "SVIDEO",
+        ConnectorType::LVDS => "LVDS",
+        ConnectorType::Component => "Component",
+        ConnectorType::NinePinDIN => "DIN",
+        ConnectorType::DisplayPort => "DP",
+        ConnectorType::HDMIA => "HDMI-A",
+        ConnectorType::HDMIB => "HDMI-B",
+        ConnectorType::TV => "TV",
+        ConnectorType::EmbeddedDisplayPort => "eDP",
+        ConnectorType::Virtual => "Virtual",
+        ConnectorType::DSI => "DSI",
+        _ => "Unknown",
+    }
+}
+
+/// Reads the EDID of a connector from sysfs, where the kernel keeps the last one it read.
+fn read_edid(card: &str, name: &str) -> Option<Edid> {
+    let path = format!("/sys/class/drm/{}-{}/edid", card, name);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            debug!("Failed to read {}: {}", path, err);
+            return None;
+        }
+    };
+
+    let edid = Edid::parse(&data);
+    if edid.is_none() {
+        warn!("Ignoring invalid EDID of {}", name);
+    }
+    edid
+}
+
+/// Creates a surface for every connected connector, each on its own CRTC.
+fn scan_connectors(
+    device: &mut RenderDevice,
+    card: &str,
+    selections: &HashMap<String, ModeSelection>,
+    transforms: &HashMap<String, wl_output::Transform>,
+) -> Result<Vec<UdevOutput>, CompositorError> {
+    let (res_handles, connectors) = connectors(device)?;
+
+    let mut outputs: Vec<UdevOutput> = vec![];
+    for (name, info) in &connectors {
+        if info.connection_state() != ConnectorState::Connected {
+            continue;
+        }
+        info!("Connected: {}", name);
+        let selection = selections.get(name);
+        let transform = transforms
+            .get(name)
+            .cloned()
+            .unwrap_or(wl_output::Transform::Normal);
+        let output = create_output(
+            device,
+            &res_handles,
+            card,
+            name,
+            info,
+            selection,
+            transform,
+            &outputs.iter().map(|output| output.crtc).collect::<Vec<_>>(),
+        )?;
+        if let Some(output) = output {
+            outputs.push(output);
+        }
+    }
+
+    if outputs.is_empty() {
+        return Err(CompositorError::NoConnectedMonitor);
+    }
+    Ok(outputs)
+}
+
+/// Creates a surface for a connector on a CRTC not used yet, or `None` if all CRTCs it can use are
+/// taken.
+fn create_output(
+    device: &mut RenderDevice,
+    res_handles: &ResourceHandles,
+    card: &str,
+    name: &str,
+    connector_info: &ConnectorInfo,
+    selection: Option<&ModeSelection>,
+    transform: wl_output::Transform,
+    used_crtcs: &[crtc::Handle],
+) -> Result<Option<UdevOutput>, CompositorError> {
+    // very naive way of finding good crtc/encoder/connector combinations. This problem is np-complete
+    let encoder_infos = connector_info
+        .encoders()
+        .iter()
+        .flat_map(|encoder_handle| device.resource_info::<EncoderInfo>(*encoder_handle))
+        .collect::<Vec<EncoderInfo>>();
+    let crtc = encoder_infos
+        .iter()
+        .flat_map(|encoder_info| res_handles.filter_crtcs(encoder_info.possible_crtcs()))
+        .find(|crtc| !used_crtcs.contains(crtc));
+    let crtc = match crtc {
+        Some(crtc) => crtc,
+        None => {
+            warn!("No CRTC left for {}, leaving it off", name);
+            return Ok(None);
+        }
+    };
+
+    let pipe = res_handles
+        .crtcs()
+        .iter()
+        .position(|handle| *handle == crtc)
+        .unwrap_or(0) as u32;
+
+    let surface = device
+        .create_surface(crtc)
+        .map_err(|err| CompositorError::Drm(err.to_string()))?;
+
+    // Connectors not lit up by the console still need to be attached to their CRTC
+    if !surface
+        .current_connectors()
+        .contains(&connector_info.handle())
+    {
+        surface
+            .add_connector(connector_info.handle())
+            .map_err(|err| CompositorError::Drm(err.to_string()))?;
+    }
+
+    // The kernel lists the preferred mode first
+    let modes = connector_info.modes().to_vec();
+    let selected = selection.and_then(|selection| {
+        let mode = find_mode(&modes, selection);
+        if mode.is_none() {
+            warn!("{} has no mode matching {:?}", name, selection);
+        }
+        mode
+    });
+    let mode = selected.or_else(|| modes.first().cloned());
+    info!("Using {:?} on {}", mode, name);
+    surface
+        .use_mode(mode)
+        .map_err(|err| CompositorError::Drm(err.to_string()))?;
+
+    let edid = read_edid(card, name);
+    if let Some(edid) = &edid {
+        info!(
+            "{} is a {} {} ({})",
+            name, edid.make, edid.model, edid.serial
+        );
+    }
+    // Fall back to the EDID in case the kernel does not report the size
+    let physical_size = match (connector_info.size(), &edid) {
+        ((0, 0), Some(edid)) => edid.size_mm,
+        (size, _) => size,
+    };
+
+    info!("Found surface for {}", name);
+    Ok(Some(UdevOutput {
+        name: name.to_string(),
+        device: device.device_id(),
+        fd: device.as_raw_fd(),
+        connector: connector_info.handle(),
+        crtc,
+        pipe,
+        surface,
+        modes,
+        physical_size,
+        edid,
+        transform,
+        source: Rectangle::default(),
+        flip_pending: Cell::new(false),
+        cursor_shown: Cell::new(false),
+        powered: Cell::new(true),
+    }))
+}
+
+/// Picks the mode of the selected size with the refresh rate closest to the selected one, or the
+/// highest one if none was selected.
+fn find_mode(modes: &[DrmMode], selection: &ModeSelection) -> Option<DrmMode> {
+    let candidates: Vec<Mode> = modes.iter().map(output_mode).collect();
+    closest_mode(&candidates, selection).and_then(|index| modes.get(index).cloned())
+}
+
+fn closest_mode(modes: &[Mode], selection: &ModeSelection) -> Option<usize> {
+    modes
+        .iter()
+        .enumerate()
+        .filter(|(_, mode)| {
+            (mode.width as u32, mode.height as u32) == (selection.width, selection.height)
+        })
+        .min_by_key(|(_, mode)| match selection.refresh {
+            Some(refresh) => (mode.refresh - refresh).abs(),
+            None => -mode.refresh,
+        })
+        .map(|(index, _)| index)
+}
+
+/// Returns the refresh rate of a mode in mHz, computed from its timings as `vrefresh` is rounded
+/// to whole hertz.
+fn refresh_rate(mode: &DrmMode) -> i32 {
+    let (_, _, htotal) = mode.hsync();
+    let (_, _, vtotal) = mode.vsync();
+    timing_refresh_rate(mode.clock(), htotal, vtotal, mode.vscan())
+        .unwrap_or_else(|| mode.vrefresh() as i32 * 1000)
+}
+
+/// Refresh rate in mHz of a pixel clock in kHz and the total size of a frame, if it is known.
+fn timing_refresh_rate(clock: u32, htotal: u16, vtotal: u16, vscan: u16) -> Option<i32> {
+    if htotal == 0 || vtotal == 0 {
+        return None;
+    }
+
+    let (htotal, vtotal) = (u64::from(htotal), u64::from(vtotal));
+    let mut refresh = (u64::from(clock) * 1_000_000 / htotal + vtotal / 2) / vtotal;
+    if vscan > 1 {
+        refresh /= u64::from(vscan);
+    }
+    Some(refresh as i32)
+}
+
+fn output_mode(mode: &DrmMode) -> Mode {
+    let (width, height) = mode.size();
+    Mode {
+        width: i32::from(width),
+        height: i32::from(height),
+        refresh: refresh_rate(mode),
+    }
+}
+
+/// Stops presenting while the session is inactive, like after switching to another VT, and tells
+/// flutter it is paused.
+///
+/// DRM master and input devices are released by the observers of the device and libinput.
+struct UdevSessionObserver {
+    compositor: FlutterCompositorWeakRef,
+}
+
+impl SessionObserver for UdevSessionObserver {
+    fn pause(&mut self, device: Option<(u32, u32)>) {
+        let compositor_ref = match self.compositor.upgrade() {
+            Some(compositor_ref) => compositor_ref,
+            None => return,
+        };
+        let compositor = compositor_ref.get();
+
+        if let CompositorBackendKind::TtyUDev(inner) = &compositor.backend.kind {
+            if inner.affects_device(device) && inner.pause() {
+                info!("Session paused");
+                compositor.lifecycle.paused();
+            }
+        }
+    }
+
+    fn activate(&mut self, device: Option<(u32, u32, Option<RawFd>)>) {
+        let compositor_ref = match self.compositor.upgrade() {
+            Some(compositor_ref) => compositor_ref,
+            None => return,
+        };
+        let compositor = compositor_ref.get();
+        let backend = &compositor.backend;
+
+        let inner = match &backend.kind {
+            CompositorBackendKind::TtyUDev(inner) => inner,
+            CompositorBackendKind::WInit(_) => return,
+        };
+        let device = device.map(|(major, minor, _)| (major, minor));
+        if !inner.affects_device(device) || !inner.resume() {
+            return;
+        }
+        info!("Session resumed");
+
+        if let Some(times) = backend.scheduler.flip_cancelled() {
+            compositor
+                .engine
+                .on_vsync(times.baton, times.start, times.target);
+        }
+
+        // Keys released on the other VT were never seen
+        if let Some(input) = backend.input.borrow().as_ref() {
+            input.reset();
+        }
+
+        inner.set_cursor_image(backend.cursor.image().as_ref());
+        if let Some(position) = backend.cursor.position() {
+            inner.move_cursor(position);
+        }
+
+        compositor.lifecycle.resumed();
+
+        // Flutter only renders a new frame once something changed
+        let size = backend.get_framebuffer_size();
+        compositor.engine.send_window_metrics_event(
+            size.0 as i32,
+            size.1 as i32,
+            backend.get_scale_factor(),
+        );
+    }
+}
+
+struct UdevHandlerImpl<S: SessionNotifier, Data: 'static> {
+    compositor: FlutterCompositorWeakRef,
+    compositor_token: CompositorToken<Roles>,
+    session: AutoSession,
+    //    display: Rc<RefCell<Display>>,
+    /// The GPU to render on, the first one found if `None`.
+    render_gpu: Option<PathBuf>,
+    loop_handle: LoopHandle<Data>,
+    notifier: S,
+    /// The driven devices.
+    devices: HashMap<dev_t, DrmDevice<S::Id>>,
+}
+
+/// A driven DRM device, with its name in sysfs, like `card0`.
+struct DrmDevice<Id> {
+    card: String,
+    session_id: Id,
+    event_source: Source<Generic<EventedFd<RenderDevice>>>,
+}
+
+impl<S: SessionNotifier, Data: 'static> UdevHandlerImpl<S, Data> {
+    fn add_device(&mut self, path: &Path) -> Result<(), CompositorError> {
         // Try to open the device
-        if let Some(mut device) = self
+        let fd = self
             .session
             .open(
-                &path,
+                path,
                 OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
             )
-            .ok()
-            .and_then(|fd| LegacyDrmDevice::new(SessionFd(fd), None).ok())
-            .and_then(|drm| GbmDevice::new(drm, None).ok())
-            .and_then(|gbm| EglDevice::new(gbm, None).ok())
-        {
+            .map_err(|err| CompositorError::Drm(err.to_string()))?;
+        let mut device = LegacyDrmDevice::new(SessionFd(fd), None)
+            .map_err(|err| CompositorError::Drm(err.to_string()))
+            .and_then(|drm| {
+                GbmDevice::new(drm, None).map_err(|err| CompositorError::Drm(err.to_string()))
+            })
+            .and_then(|gbm| {
+                EglDevice::new(gbm, None).map_err(|err| CompositorError::Drm(err.to_string()))
+            })?;
+
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+
+        if let CompositorBackendKind::TtyUDev(inner) = &compositor.backend.kind {
+            let card = path
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+            // Contexts and textures are tied to one device, so flutter renders on a single GPU
+            let render = match &self.render_gpu {
+                Some(gpu) => path.canonicalize().ok().as_ref() == Some(gpu),
+                None => inner.render_device.get().is_none(),
+            };
             info!(
-                "primary_gpu={:?}   path={:?}",
-                self.primary_gpu,
-                path.canonicalize().ok()
+                "Adding {:?}, {}",
+                path,
+                if render {
+                    "rendering on it"
+                } else {
+                    "copying frames to it"
+                }
             );
 
-            //            if path.canonicalize().ok() != self.primary_gpu {
-            //                return;
-            //            }
+            if render {
+                self.init_render_device(&mut device, &card)?;
+            }
 
-            let compositor_ref = self.compositor.upgrade().unwrap();
-            let compositor = compositor_ref.get();
+            // Set the handler.
+            // Note: if you replicate this (very simple) structure, it is rather easy
+            // to introduce reference cycles with Rc. Be sure about your drop order
+            let dev_id = device.device_id();
+            device.set_handler(DrmHandlerImpl {
+                compositor: self.compositor.clone(),
+                compositor_token: self.compositor_token,
+                device: dev_id,
+                //                backends: backends.clone(),
+                //                window_map: self.window_map.clone(),
+                //                pointer_location: self.pointer_location.clone(),
+                //                cursor_status: self.cursor_status.clone(),
+                //                dnd_icon: self.dnd_icon.clone(),
+                //                logger: self.logger.clone(),
+            });
 
-            if let CompositorBackendKind::TtyUDev(inner) = &compositor.backend.kind {
-                inner.active_egl_context.replace(Some(
-                    device
-                        .bind_wl_display(compositor.backend.display.borrow().as_ref().unwrap())
-                        .unwrap(),
-                ));
+            let session_id = self.notifier.register(device.observer());
+            let event_source = match device_bind(&self.loop_handle, device) {
+                Ok(event_source) => event_source,
+                Err(err) => {
+                    self.notifier.unregister(session_id);
+                    if render {
+                        inner.remove_device();
+                    }
+                    let err: IoError = err.into();
+                    return Err(CompositorError::Drm(err.to_string()));
+                }
+            };
+            self.devices.insert(
+                dev_id,
+                DrmDevice {
+                    card,
+                    session_id,
+                    event_source,
+                },
+            );
 
-                let surface = UdevHandlerImpl::<Data>::scan_connectors(&mut device);
+            // Monitors of other GPUs wait for the render GPU to drive one
+            self.update_copied_outputs();
 
-                debug!("Creating extra EGL contexts");
-                unsafe {
-                    surface.make_current();
-                    let display = WrappedDisplay::new();
+            // Show the current cursor on the new surfaces
+            inner.set_cursor_image(compositor.backend.cursor.image().as_ref());
+            if let Some(position) = compositor.backend.cursor.position() {
+                inner.move_cursor(position);
+            }
 
-                    let resource_context = WrappedContext::create_context();
-                    inner.resource_context.replace(Some(resource_context));
+            // Monitors of a device added after startup still need to be advertised
+            compositor.backend.refresh_outputs();
+        }
+        Ok(())
+    }
 
-                    display.release_context();
+    /// Drives the monitors of the GPU flutter renders on, and creates the contexts flutter uses.
+    fn init_render_device(
+        &self,
+        device: &mut RenderDevice,
+        card: &str,
+    ) -> Result<(), CompositorError> {
+        let compositor_ref = self.compositor.upgrade().unwrap();
+        let compositor = compositor_ref.get();
+        let inner = match &compositor.backend.kind {
+            CompositorBackendKind::TtyUDev(inner) => inner,
+            CompositorBackendKind::WInit(_) => return Ok(()),
+        };
 
-                    inner.display.replace(Some(display));
-                    //                    self.display.replace(Some(display));
-                }
+        if inner.render_device.get().is_some() {
+            info!("Already rendering on a device, copying frames to {}", card);
+            return Ok(());
+        }
 
-                inner.surface.replace(Some(surface));
-
-                info!("Surface set ");
-
-                // Set the handler.
-                // Note: if you replicate this (very simple) structure, it is rather easy
-                // to introduce reference cycles with Rc. Be sure about your drop order
-                device.set_handler(DrmHandlerImpl {
-                    compositor_token: self.compositor_token,
-                    //                backends: backends.clone(),
-                    //                window_map: self.window_map.clone(),
-                    //                pointer_location: self.pointer_location.clone(),
-                    //                cursor_status: self.cursor_status.clone(),
-                    //                dnd_icon: self.dnd_icon.clone(),
-                    //                logger: self.logger.clone(),
-                });
+        let outputs = scan_connectors(
+            device,
+            card,
+            &inner.mode_selections.borrow(),
+            &inner.transforms.borrow(),
+        )?;
 
-                let _device_session_id = inner
-                    .notifier
-                    .borrow_mut()
-                    .as_mut()
-                    .unwrap()
-                    .register(device.observer());
+        match device.bind_wl_display(compositor.backend.display.borrow().as_ref().unwrap()) {
+            Ok(egl_display) => {
+                info!("EGL hardware-acceleration enabled");
+                inner.active_egl_context.replace(Some(egl_display));
+            }
+            Err(err) => warn!("Failed to bind EGL to the display: {}", err),
+        }
 
-                //                let device_session_id = self.notifier.register(device.observer());
-                let _dev_id = device.device_id();
-                let _event_source = device_bind(&self.loop_handle, device)
-                    .map_err(|e| -> IoError { e.into() })
-                    .unwrap();
+        debug!("Creating extra EGL contexts");
+        let contexts = unsafe {
+            outputs[0].surface.make_current();
+            WrappedDisplay::new().and_then(|display| {
+                let resource_context = WrappedContext::create_context()?;
+                let upload_context = WrappedContext::create_context()?;
+                display.release_context();
+                Ok((display, resource_context, upload_context))
+            })
+        };
+        match contexts {
+            Ok((display, resource_context, upload_context)) => {
+                inner.resource_context.replace(Some(resource_context));
+                inner.upload_context.replace(Some(upload_context));
+                inner.display.replace(Some(display));
+            }
+            Err(err) => {
+                // Unbinds the EGL display again
+                inner.remove_device();
+                return Err(err.into());
+            }
+        }
+
+        inner.render_device.set(Some(device.device_id()));
+        inner.outputs.replace(outputs);
+        inner.arrange();
+
+        info!("Surface set ");
+        Ok(())
+    }
+
+    /// Drives the monitors of the GPUs flutter does not render on, returning whether their
+    /// outputs changed.
+    fn update_copied_outputs(&self) -> bool {
+        let compositor_ref = match self.compositor.upgrade() {
+            Some(compositor_ref) => compositor_ref,
+            None => return false,
+        };
+        let compositor = compositor_ref.get();
+        let inner = match &compositor.backend.kind {
+            CompositorBackendKind::TtyUDev(inner) => inner,
+            CompositorBackendKind::WInit(_) => return false,
+        };
 
-                //            for renderer in backends.borrow_mut().values() {
-                //                // create cursor
-                //                renderer
-                //                    .borrow()
-                //                    .set_cursor_representation(&self.pointer_image, (2, 2))
-                //                    .unwrap();
-                //
-                //                // render first frame
-                //                {
-                //                    let mut frame = renderer.draw();
-                //                    frame.clear_color(0.8, 0.8, 0.9, 1.0);
-                //                    frame.finish().unwrap();
-                //                }
-                //            }
-                //
-                //            self.backends
-                //                .insert(dev_id, (device_session_id, event_source, backends));
+        let mut changed = false;
+        for (dev_id, device) in &self.devices {
+            if inner.render_device.get() == Some(*dev_id) {
+                continue;
             }
+            let source = device.event_source.clone_inner();
+            let mut evented = source.borrow_mut();
+            changed |= inner.update_outputs(&mut (*evented).0, &device.card);
         }
+        changed
     }
 
-    fn device_changed(&mut self, _device: dev_t) {
-        info!("device_changed")
+    /// Follows a change of the monitors, keeping the frame pacing going if the primary monitor
+    /// changed.
+    fn outputs_changed(&self, primary: Option<crtc::Handle>) {
+        let compositor_ref = match self.compositor.upgrade() {
+            Some(compositor_ref) => compositor_ref,
+            None => return,
+        };
+        let compositor = compositor_ref.get();
+        let backend = &compositor.backend;
+
+        let inner = match &backend.kind {
+            CompositorBackendKind::TtyUDev(inner) => inner,
+            CompositorBackendKind::WInit(_) => return,
+        };
+        if inner.primary_crtc() != primary {
+            // The page flip on the old primary monitor never completes
+            if let Some(times) = backend.scheduler.flip_cancelled() {
+                compositor
+                    .engine
+                    .on_vsync(times.baton, times.start, times.target);
+            }
+        }
+
+        // New monitors may not be able to show the cursor on the cursor plane
+        inner.set_cursor_image(backend.cursor.image().as_ref());
+        backend.refresh_outputs();
     }
+}
 
-    fn device_removed(&mut self, _device: dev_t) {
-        info!("device_removed")
+impl<S: SessionNotifier, Data: 'static> Drop for UdevHandlerImpl<S, Data> {
+    fn drop(&mut self) {
+        for (_, drm_device) in self.devices.drain() {
+            drm_device.event_source.remove();
+            self.notifier.unregister(drm_device.session_id);
+        }
+    }
+}
+
+impl<S: SessionNotifier, Data: 'static> UdevHandler for UdevHandlerImpl<S, Data> {
+    fn device_added(&mut self, _device: dev_t, path: PathBuf) {
+        info!("device_added");
+        if let Err(err) = self.add_device(&path) {
+            warn!("Failed to add device {:?}: {}", path, err);
+
+            // Kept to be reported if no device can be used
+            let compositor_ref = self.compositor.upgrade().unwrap();
+            let compositor = compositor_ref.get();
+            if let CompositorBackendKind::TtyUDev(inner) = &compositor.backend.kind {
+                inner.device_error.replace(Some(err));
+            }
+        }
+    }
+
+    fn device_changed(&mut self, device: dev_t) {
+        info!("device_changed");
+        let (source, card) = match self.devices.get(&device) {
+            Some(drm_device) => (
+                drm_device.event_source.clone_inner(),
+                drm_device.card.clone(),
+            ),
+            None => return,
+        };
+
+        let (primary, render) = {
+            let compositor_ref = match self.compositor.upgrade() {
+                Some(compositor_ref) => compositor_ref,
+                None => return,
+            };
+            let compositor = compositor_ref.get();
+            let inner = match &compositor.backend.kind {
+                CompositorBackendKind::TtyUDev(inner) => inner,
+                CompositorBackendKind::WInit(_) => return,
+            };
+
+            let primary = inner.primary_crtc();
+            let mut evented = source.borrow_mut();
+            let changed = inner.update_outputs(&mut (*evented).0, &card);
+            let render = inner.render_device.get() == Some(device);
+            if !changed {
+                return;
+            }
+            (primary, render)
+        };
+
+        // The other GPUs follow the render GPU driving a monitor or not
+        if render {
+            self.update_copied_outputs();
+        }
+        self.outputs_changed(primary);
+    }
+
+    fn device_removed(&mut self, device: dev_t) {
+        info!("device_removed");
+        let drm_device = match self.devices.remove(&device) {
+            Some(drm_device) => drm_device,
+            None => return,
+        };
+
+        let primary = {
+            let compositor_ref = match self.compositor.upgrade() {
+                Some(compositor_ref) => compositor_ref,
+                None => return,
+            };
+            let compositor = compositor_ref.get();
+            match &compositor.backend.kind {
+                CompositorBackendKind::TtyUDev(inner) => {
+                    let primary = inner.primary_crtc();
+                    // The surfaces have to go before the device they belong to
+                    if inner.render_device.get() == Some(device) {
+                        inner.remove_device();
+                    } else {
+                        inner.remove_outputs(device);
+                    }
+                    primary
+                }
+                CompositorBackendKind::WInit(_) => None,
+            }
+        };
+
+        drm_device.event_source.remove();
+        self.notifier.unregister(drm_device.session_id);
+        self.outputs_changed(primary);
     }
 }
 
 pub struct DrmHandlerImpl {
+    compositor: FlutterCompositorWeakRef,
     compositor_token: CompositorToken<Roles>,
+    device: dev_t,
     //    backends: Rc<RefCell<HashMap<crtc::Handle, GliumDrawer<RenderSurface>>>>,
     //    window_map: Rc<RefCell<MyWindowMap>>,
     //    pointer_location: Rc<RefCell<(f64, f64)>>,
@@ -432,58 +1774,97 @@ pub struct DrmHandlerImpl {
 impl DeviceHandler for DrmHandlerImpl {
     type Device = RenderDevice;
 
-    fn vblank(&mut self, _crtc: crtc::Handle) {
-        //        if let Some(drawer) = self.backends.borrow().get(&crtc) {
-        //            {
-        //                let (x, y) = *self.pointer_location.borrow();
-        //                let _ = drawer
-        //                    .borrow()
-        //                    .set_cursor_position(x.trunc().abs() as u32, y.trunc().abs() as u32);
-        //            }
-        //
-        //            // and draw in sync with our monitor
-        //            let mut frame = drawer.draw();
-        //            frame.clear(None, Some((0.8, 0.8, 0.9, 1.0)), false, Some(1.0), None);
-        //            // draw the surfaces
-        //            drawer.draw_windows(&mut frame, &*self.window_map.borrow(), self.compositor_token);
-        //            let (x, y) = *self.pointer_location.borrow();
-        //            // draw the dnd icon if applicable
-        //            {
-        //                let guard = self.dnd_icon.lock().unwrap();
-        //                if let Some(ref surface) = *guard {
-        //                    if surface.as_ref().is_alive() {
-        //                        drawer.draw_dnd_icon(
-        //                            &mut frame,
-        //                            surface,
-        //                            (x as i32, y as i32),
-        //                            self.compositor_token,
-        //                        );
-        //                    }
-        //                }
-        //            }
-        //            // draw the cursor as relevant
-        //            {
-        //                let mut guard = self.cursor_status.lock().unwrap();
-        //                // reset the cursor if the surface is no longer alive
-        //                let mut reset = false;
-        //                if let CursorImageStatus::Image(ref surface) = *guard {
-        //                    reset = !surface.as_ref().is_alive();
-        //                }
-        //                if reset {
-        //                    *guard = CursorImageStatus::Default;
-        //                }
-        //                if let CursorImageStatus::Image(ref surface) = *guard {
-        //                    drawer.draw_cursor(&mut frame, surface, (x as i32, y as i32), self.compositor_token);
-        //                }
-        //            }
-        //
-        //            if let Err(err) = frame.finish() {
-        //                error!(self.logger, "Error during rendering: {:?}", err);
-        //            }
-        //        }
+    fn vblank(&mut self, crtc: crtc::Handle) {
+        let compositor_ref = match self.compositor.upgrade() {
+            Some(compositor_ref) => compositor_ref,
+            None => return,
+        };
+        let compositor = compositor_ref.get();
+        let backend = &compositor.backend;
+
+        let inner = match &backend.kind {
+            CompositorBackendKind::TtyUDev(inner) => inner,
+            CompositorBackendKind::WInit(_) => return,
+        };
+
+        // Only the primary monitor paces flutter frames
+        if !inner.page_flipped(self.device, crtc) {
+            return;
+        }
+
+        // Smithay only passes the CRTC of the page flip event on, so the time and counter of
+        // the vblank the flip completed at are read back from the kernel
+        let vblank = inner.last_vblank();
+
+        // The previous frame is on screen, start the next one if flutter asked for it
+        let times = backend.scheduler.vblank(vblank);
+
+        let mut flags = Kind::Vsync | Kind::HwCompletion;
+        if vblank.is_some() {
+            flags |= Kind::HwClock;
+        }
+        backend.push_presentation(PresentationEvent {
+            output: inner.primary_name().unwrap_or_default(),
+            time: vblank.map_or_else(monotonic_nanos, |vblank| vblank.time),
+            refresh: backend.scheduler.refresh_period() as u32,
+            sequence: backend.scheduler.sequence(),
+            flags,
+        });
+
+        if let Some(times) = times {
+            compositor
+                .engine
+                .on_vsync(times.baton, times.start, times.target);
+        }
     }
 
     fn error(&mut self, error: <RenderSurface as Surface>::Error) {
         error!("{:?}", error);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(width: i32, height: i32, refresh: i32) -> Mode {
+        Mode {
+            width,
+            height,
+            refresh,
+        }
+    }
+
+    #[test]
+    fn closest_mode_matches_size_and_refresh() {
+        let modes = [
+            mode(1920, 1080, 60_000),
+            mode(1920, 1080, 144_000),
+            mode(1920, 1080, 59_940),
+            mode(1280, 720, 60_000),
+        ];
+        let closest = |width, height, refresh| {
+            let selection = ModeSelection {
+                width,
+                height,
+                refresh,
+            };
+            closest_mode(&modes, &selection)
+        };
+
+        assert_eq!(closest(1920, 1080, None), Some(1));
+        assert_eq!(closest(1920, 1080, Some(59_900)), Some(2));
+        assert_eq!(closest(1920, 1080, Some(120_000)), Some(1));
+        assert_eq!(closest(1280, 720, Some(30_000)), Some(3));
+        assert_eq!(closest(800, 600, None), None);
+    }
+
+    #[test]
+    fn refresh_rate_from_timings() {
+        // CEA 1080p60 and its NTSC variant
+        assert_eq!(timing_refresh_rate(148_500, 2200, 1125, 0), Some(60_000));
+        assert_eq!(timing_refresh_rate(148_352, 2200, 1125, 0), Some(59_940));
+        assert_eq!(timing_refresh_rate(148_500, 2200, 1125, 2), Some(30_000));
+        assert_eq!(timing_refresh_rate(148_500, 0, 1125, 0), None);
+    }
+}
@@ -0,0 +1,312 @@
+use std::sync::Mutex;
+
+use log::trace;
+
+const DEFAULT_REFRESH_RATE: u32 = 60_000;
+
+/// The frame times reported to flutter in response to a vsync request.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTimes {
+    pub baton: isize,
+    /// Start of the frame, in nanoseconds on the monotonic clock.
+    pub start: u64,
+    /// When the frame is expected to be presented, in nanoseconds on the monotonic clock.
+    pub target: u64,
+}
+
+/// A vblank of the output, as reported by the display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Vblank {
+    /// When the vblank started, in nanoseconds on the monotonic clock.
+    pub time: u64,
+    /// Hardware vblank counter.
+    pub sequence: u64,
+}
+
+/// Paces flutter frames to the vblanks of the output.
+///
+/// While a page flip is pending, vsync requests are held back until the flip completes, and while
+/// all monitors are off until one is turned on. Otherwise they are answered straight away,
+/// targeting the next vblank predicted from the last one.
+pub struct FrameScheduler {
+    state: Mutex<SchedulerState>,
+}
+
+struct SchedulerState {
+    baton: Option<isize>,
+    flip_pending: bool,
+    /// Whether all monitors are off, frames are held back until one is turned on.
+    blanked: bool,
+    last_vblank: Option<u64>,
+    /// Vblank counter of the last flip, estimated if the display does not report it.
+    sequence: u64,
+    /// Refresh rate in mHz.
+    refresh_rate: u32,
+}
+
+impl Default for FrameScheduler {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(SchedulerState {
+                baton: None,
+                flip_pending: false,
+                blanked: false,
+                last_vblank: None,
+                sequence: 0,
+                refresh_rate: DEFAULT_REFRESH_RATE,
+            }),
+        }
+    }
+}
+
+impl FrameScheduler {
+    /// Returns the refresh rate of the output in mHz.
+    pub fn refresh_rate(&self) -> u32 {
+        self.state.lock().unwrap().refresh_rate
+    }
+
+    /// Returns the duration of a refresh cycle in nanoseconds.
+    pub fn refresh_period(&self) -> u64 {
+        self.state.lock().unwrap().period()
+    }
+
+    /// Returns the vblank counter as of the last completed flip.
+    pub fn sequence(&self) -> u64 {
+        self.state.lock().unwrap().sequence
+    }
+
+    pub fn set_refresh_rate(&self, refresh_rate: u32) {
+        if refresh_rate > 0 {
+            self.state.lock().unwrap().refresh_rate = refresh_rate;
+        }
+    }
+
+    /// Handles a vsync request from flutter, returning the frame times to reply with if the
+    /// frame can start immediately.
+    pub fn request(&self, baton: isize) -> Option<FrameTimes> {
+        let mut state = self.state.lock().unwrap();
+        if state.flip_pending {
+            trace!("Deferring vsync until the pending flip completes");
+            state.baton = Some(baton);
+            return None;
+        }
+        if state.blanked {
+            trace!("Deferring vsync until a monitor is turned on");
+            state.baton = Some(baton);
+            return None;
+        }
+
+        let now = monotonic_nanos();
+        let period = state.period();
+        let target = match state.last_vblank {
+            Some(last) if last <= now => last + ((now - last) / period + 1) * period,
+            _ => now + period,
+        };
+
+        Some(FrameTimes {
+            baton,
+            start: now,
+            target,
+        })
+    }
+
+    /// Marks that a frame was handed to the display and a page flip is pending.
+    pub fn flip_queued(&self) {
+        self.state.lock().unwrap().flip_pending = true;
+    }
+
+    /// Handles a page flip completed at the given vblank, returning the frame times for a
+    /// deferred vsync request.
+    ///
+    /// Without a vblank reported by the display, the flip is taken to have completed now and the
+    /// counter is advanced by the elapsed refresh cycles.
+    pub fn vblank(&self, vblank: Option<Vblank>) -> Option<FrameTimes> {
+        let mut state = self.state.lock().unwrap();
+        let now = monotonic_nanos();
+        let period = state.period();
+
+        let vblank = vblank.unwrap_or_else(|| {
+            let elapsed = match state.last_vblank {
+                Some(last) if last < now => ((now - last + period / 2) / period).max(1),
+                _ => 1,
+            };
+            Vblank {
+                time: now,
+                sequence: state.sequence + elapsed,
+            }
+        });
+        state.sequence = vblank.sequence;
+        state.flip_pending = false;
+        state.last_vblank = Some(vblank.time);
+        if state.blanked {
+            return None;
+        }
+
+        // The next frame is shown at the first vblank after it starts
+        let target = if vblank.time <= now {
+            vblank.time + ((now - vblank.time) / period + 1) * period
+        } else {
+            now + period
+        };
+        state.baton.take().map(|baton| FrameTimes {
+            baton,
+            start: now,
+            target,
+        })
+    }
+
+    /// Gives up on the pending page flip, whose monitor went away, returning the frame times for
+    /// a deferred vsync request.
+    pub fn flip_cancelled(&self) -> Option<FrameTimes> {
+        let mut state = self.state.lock().unwrap();
+        if !state.flip_pending {
+            return None;
+        }
+        state.flip_pending = false;
+        if state.blanked {
+            return None;
+        }
+
+        let now = monotonic_nanos();
+        let period = state.period();
+        state.baton.take().map(|baton| FrameTimes {
+            baton,
+            start: now,
+            target: now + period,
+        })
+    }
+
+    /// Holds frames back while all monitors are off, returning the frame times for a deferred
+    /// vsync request once one is turned on.
+    pub fn set_blanked(&self, blanked: bool) -> Option<FrameTimes> {
+        let mut state = self.state.lock().unwrap();
+        if state.blanked == blanked {
+            return None;
+        }
+        state.blanked = blanked;
+        if blanked || state.flip_pending {
+            return None;
+        }
+
+        let now = monotonic_nanos();
+        let period = state.period();
+        state.baton.take().map(|baton| FrameTimes {
+            baton,
+            start: now,
+            target: now + period,
+        })
+    }
+}
+
+impl SchedulerState {
+    fn period(&self) -> u64 {
+        1_000_000_000_000 / u64::from(self.refresh_rate)
+    }
+}
+
+/// The current time on the clock used by the flutter engine.
+pub fn monotonic_nanos() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_is_answered_without_pending_flip() {
+        let scheduler = FrameScheduler::default();
+        let times = scheduler.request(1).unwrap();
+        assert_eq!(times.baton, 1);
+        assert!(times.target > times.start);
+        assert!(times.target - times.start <= scheduler.refresh_period());
+    }
+
+    #[test]
+    fn request_waits_for_pending_flip() {
+        let scheduler = FrameScheduler::default();
+        scheduler.flip_queued();
+        assert!(scheduler.request(2).is_none());
+
+        let times = scheduler.vblank(None).unwrap();
+        assert_eq!(times.baton, 2);
+        assert_eq!(times.target - times.start, scheduler.refresh_period());
+        assert!(scheduler.vblank(None).is_none());
+    }
+
+    #[test]
+    fn cancelled_flip_releases_request() {
+        let scheduler = FrameScheduler::default();
+        assert!(scheduler.flip_cancelled().is_none());
+
+        scheduler.flip_queued();
+        assert!(scheduler.request(3).is_none());
+        assert_eq!(scheduler.flip_cancelled().map(|times| times.baton), Some(3));
+        assert!(scheduler.request(4).is_some());
+    }
+
+    #[test]
+    fn request_waits_while_blanked() {
+        let scheduler = FrameScheduler::default();
+        assert!(scheduler.set_blanked(true).is_none());
+        assert!(scheduler.request(5).is_none());
+
+        // Flips completing while blanked keep the request
+        scheduler.flip_queued();
+        assert!(scheduler.vblank(None).is_none());
+
+        assert_eq!(
+            scheduler.set_blanked(false).map(|times| times.baton),
+            Some(5)
+        );
+        assert!(scheduler.set_blanked(false).is_none());
+    }
+
+    #[test]
+    fn vblank_advances_sequence() {
+        let scheduler = FrameScheduler::default();
+        assert_eq!(scheduler.sequence(), 0);
+        scheduler.flip_queued();
+        scheduler.vblank(None);
+        assert_eq!(scheduler.sequence(), 1);
+    }
+
+    #[test]
+    fn vblank_reported_by_display() {
+        let scheduler = FrameScheduler::default();
+        let period = scheduler.refresh_period();
+        let time = monotonic_nanos() - period / 2;
+
+        scheduler.flip_queued();
+        assert!(scheduler.request(6).is_none());
+        let times = scheduler
+            .vblank(Some(Vblank {
+                time,
+                sequence: 1234,
+            }))
+            .unwrap();
+        assert_eq!(scheduler.sequence(), 1234);
+        assert_eq!((times.target - time) % period, 0);
+        assert!(times.target > times.start);
+        assert!(times.target - times.start <= period);
+    }
+
+    #[test]
+    fn refresh_rate_sets_period() {
+        let scheduler = FrameScheduler::default();
+        assert_eq!(scheduler.refresh_rate(), 60_000);
+
+        scheduler.set_refresh_rate(0);
+        assert_eq!(scheduler.refresh_rate(), 60_000);
+
+        scheduler.set_refresh_rate(144_000);
+        assert_eq!(scheduler.refresh_period(), 6_944_444);
+    }
+}
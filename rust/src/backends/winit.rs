@@ -1,15 +1,18 @@
-use std::{
-    cell::RefCell,
-    rc::Rc,
-};
+use std::cell::RefCell;
 
 use smithay::{
     backend::{
-        egl::EGLGraphicsBackend, graphics::gl::GLGraphicsBackend, input::InputBackend, winit,
+        egl::{BufferAccessError, EGLDisplay, EGLGraphicsBackend, EGLImages},
+        graphics::gl::GLGraphicsBackend,
+        input::InputBackend,
+        winit,
     },
     reexports::{
         calloop::EventLoop,
-        wayland_server::{protocol::wl_output, Display},
+        wayland_server::{
+            protocol::{wl_buffer, wl_output},
+            Display,
+        },
     },
     wayland::{
         data_device::{
@@ -27,18 +30,23 @@ use log::{debug, error, info};
 
 use smithay::backend::winit::{WinitGraphicsBackend, WinitInputBackend};
 
+use crate::backends::cursor::CursorImage;
+use crate::backends::error::CompositorError;
 use crate::backends::input::handler::FlutterInputHandler;
 use crate::renderer::egl_util::{WrappedContext, WrappedDisplay};
+use crate::renderer::gl;
 use crate::FlutterCompositorWeakRef;
-use ::winit::{dpi::LogicalSize, WindowBuilder};
+use ::winit::{dpi::LogicalSize, MouseCursor, WindowBuilder};
 use std::ffi::c_void;
 
 pub struct WInitInner {
     compositor: RefCell<FlutterCompositorWeakRef>,
     renderer: RefCell<Option<WinitGraphicsBackend>>,
+    active_egl_context: RefCell<Option<EGLDisplay>>,
     input: RefCell<Option<WinitInputBackend>>,
     display: RefCell<Option<WrappedDisplay>>,
     resource_context: RefCell<Option<WrappedContext>>,
+    upload_context: RefCell<Option<WrappedContext>>,
 }
 
 impl Default for WInitInner {
@@ -46,9 +54,11 @@ impl Default for WInitInner {
         Self {
             compositor: RefCell::new(FlutterCompositorWeakRef::default()),
             renderer: RefCell::new(None),
+            active_egl_context: RefCell::new(None),
             input: RefCell::new(None),
             display: RefCell::new(None),
             resource_context: RefCell::new(None),
+            upload_context: RefCell::new(None),
         }
     }
 }
@@ -58,7 +68,7 @@ impl WInitInner {
         self.compositor.replace(compositor);
     }
 
-    pub fn create_window(&self) {
+    pub fn create_window(&self) -> Result<(), CompositorError> {
         info!("Creating winit window");
         let (renderer, input) = winit::init_from_builder(
             WindowBuilder::new()
@@ -68,13 +78,14 @@ impl WInitInner {
                 .with_visibility(true),
             None,
         )
-        .unwrap();
+        .map_err(|err| CompositorError::Window(err.to_string()))?;
 
         self.renderer.replace(Some(renderer));
         self.input.replace(Some(input));
+        Ok(())
     }
 
-    pub fn init_io(&self, display: &Display) {
+    pub fn init_io(&self, display: &Display) -> Result<(), CompositorError> {
         debug!("Initialising I/O");
 
         let mut renderer_borrow = self.renderer.borrow_mut();
@@ -84,23 +95,22 @@ impl WInitInner {
         let input = input_borrow.as_mut().unwrap();
 
         debug!("Binding EGL to display");
-        let _egl_display = Rc::new(RefCell::new(
-            if let Ok(egl_display) = renderer.bind_wl_display(&display) {
-                info!("EGL hardware-acceleration enabled");
-                Some(egl_display)
-            } else {
-                None
-            },
-        ));
+        if let Ok(egl_display) = renderer.bind_wl_display(&display) {
+            info!("EGL hardware-acceleration enabled");
+            self.active_egl_context.replace(Some(egl_display));
+        }
 
         debug!("Creating extra EGL contexts");
         unsafe {
             renderer.make_current();
-            let display = WrappedDisplay::new();
+            let display = WrappedDisplay::new()?;
 
-            let resource_context = WrappedContext::create_context();
+            let resource_context = WrappedContext::create_context()?;
             self.resource_context.replace(Some(resource_context));
 
+            let upload_context = WrappedContext::create_context()?;
+            self.upload_context.replace(Some(upload_context));
+
             display.release_context();
             self.display.replace(Some(display));
         }
@@ -109,15 +119,24 @@ impl WInitInner {
         input.set_handler(FlutterInputHandler::new(self.compositor.borrow().clone()));
 
         debug!("Done?");
+        Ok(())
     }
 
     pub fn update(&self) {
-        self.input
+        let result = self
+            .input
             .borrow_mut()
             .as_mut()
             .unwrap()
-            .dispatch_new_events()
-            .unwrap();
+            .dispatch_new_events();
+
+        // The only error is the window being closed
+        if let Err(err) = result {
+            info!("Stopping: {}", err);
+            if let Some(compositor_ref) = self.compositor.borrow().upgrade() {
+                compositor_ref.stop();
+            }
+        }
     }
 
     pub fn present(&self) -> bool {
@@ -143,14 +162,15 @@ impl WInitInner {
     }
 
     pub fn make_resource_current(&self) -> bool {
+        let context_ref = self.resource_context.borrow();
+        let display_ref = self.display.borrow();
+        let (context, display) = match (context_ref.as_ref(), display_ref.as_ref()) {
+            (Some(context), Some(display)) => (context, display),
+            _ => return false,
+        };
+
         unsafe {
-            if !self
-                .resource_context
-                .borrow()
-                .as_ref()
-                .unwrap()
-                .apply_context(self.display.borrow().as_ref().unwrap())
-            {
+            if !context.apply_context(display) {
                 error!("Failed to make resource current");
                 return false;
             }
@@ -165,6 +185,37 @@ impl WInitInner {
         }
     }
 
+    pub fn egl_buffer_contents(
+        &self,
+        buffer: wl_buffer::WlBuffer,
+    ) -> Result<EGLImages, BufferAccessError> {
+        match self.active_egl_context.borrow().as_ref() {
+            Some(display) => display.egl_buffer_contents(buffer),
+            None => Err(BufferAccessError::NotManaged(buffer)),
+        }
+    }
+
+    pub fn with_upload_context<F, T>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&gl::Gl) -> T,
+    {
+        let context_ref = self.upload_context.borrow();
+        let context = context_ref.as_ref()?;
+        let display_ref = self.display.borrow();
+        let display = display_ref.as_ref()?;
+
+        unsafe {
+            if !context.apply_context(display) {
+                error!("Failed to make upload context current");
+                return None;
+            }
+
+            let result = f(&context.get_gl());
+            display.release_context();
+            Some(result)
+        }
+    }
+
     pub fn gl_proc_resolver(&self, proc: &str) -> *mut c_void {
         unsafe {
             self.renderer
@@ -182,4 +233,76 @@ impl WInitInner {
             .unwrap()
             .get_framebuffer_dimensions()
     }
+
+    pub fn get_physical_size(&self) -> (u32, u32) {
+        // The size of the window in millimetres is not known
+        (0, 0)
+    }
+
+    /// Shows the cursor through the host, which draws its own theme for the given cursor name.
+    pub fn set_cursor_image(&self, cursor: Option<&CursorImage>) {
+        let renderer = self.renderer.borrow();
+        let window = match renderer.as_ref() {
+            Some(renderer) => renderer.window(),
+            None => return,
+        };
+
+        match cursor {
+            Some(cursor) => {
+                window.hide_cursor(false);
+                window.set_cursor(host_cursor(cursor.name.unwrap_or("default")));
+            }
+            None => window.hide_cursor(true),
+        }
+    }
+
+    pub fn get_scale_factor(&self) -> Option<f64> {
+        Some(
+            self.renderer
+                .borrow()
+                .as_ref()
+                .unwrap()
+                .window()
+                .get_hidpi_factor(),
+        )
+    }
+}
+
+fn host_cursor(name: &str) -> MouseCursor {
+    match name {
+        "pointer" => MouseCursor::Hand,
+        "not-allowed" => MouseCursor::NotAllowed,
+        "wait" => MouseCursor::Wait,
+        "progress" => MouseCursor::Progress,
+        "context-menu" => MouseCursor::ContextMenu,
+        "help" => MouseCursor::Help,
+        "text" => MouseCursor::Text,
+        "vertical-text" => MouseCursor::VerticalText,
+        "cell" => MouseCursor::Cell,
+        "crosshair" => MouseCursor::Crosshair,
+        "move" => MouseCursor::Move,
+        "grab" => MouseCursor::Grab,
+        "grabbing" => MouseCursor::Grabbing,
+        "no-drop" => MouseCursor::NoDrop,
+        "alias" => MouseCursor::Alias,
+        "copy" => MouseCursor::Copy,
+        "all-scroll" => MouseCursor::AllScroll,
+        "ew-resize" => MouseCursor::EwResize,
+        "ns-resize" => MouseCursor::NsResize,
+        "nwse-resize" => MouseCursor::NwseResize,
+        "nesw-resize" => MouseCursor::NeswResize,
+        "n-resize" => MouseCursor::NResize,
+        "s-resize" => MouseCursor::SResize,
+        "w-resize" => MouseCursor::WResize,
+        "e-resize" => MouseCursor::EResize,
+        "nw-resize" => MouseCursor::NwResize,
+        "ne-resize" => MouseCursor::NeResize,
+        "sw-resize" => MouseCursor::SwResize,
+        "se-resize" => MouseCursor::SeResize,
+        "col-resize" => MouseCursor::ColResize,
+        "row-resize" => MouseCursor::RowResize,
+        "zoom-in" => MouseCursor::ZoomIn,
+        "zoom-out" => MouseCursor::ZoomOut,
+        _ => MouseCursor::Default,
+    }
 }
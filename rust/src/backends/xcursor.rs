@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use log::{debug, warn};
+use smithay::reexports::image::{Rgba, RgbaImage};
+
+use crate::backends::cursor::CursorImage;
+
+const DEFAULT_THEME: &str = "default";
+const DEFAULT_SIZE: u32 = 24;
+/// Search path used by libXcursor when `XCURSOR_PATH` is not set.
+const DEFAULT_PATH: &str =
+    "~/.local/share/icons:~/.icons:/usr/share/icons:/usr/share/pixmaps:/usr/X11R6/lib/X11/icons";
+
+const FILE_MAGIC: &[u8] = b"Xcur";
+const IMAGE_TYPE: u32 = 0xfffd_0002;
+/// Themes may inherit from each other, stop following a broken chain after this many steps.
+const MAX_INHERITANCE_DEPTH: usize = 8;
+
+/// Cursor images of an XCursor theme, loaded from disk on demand.
+pub(crate) struct CursorTheme {
+    name: String,
+    size: u32,
+    search_path: Vec<PathBuf>,
+    cache: HashMap<&'static str, Option<CursorImage>>,
+}
+
+impl CursorTheme {
+    /// Uses the theme and size given by `XCURSOR_THEME` and `XCURSOR_SIZE`, with the size
+    /// multiplied by the output scale.
+    pub fn from_env(scale: f64) -> Self {
+        let name = env::var("XCURSOR_THEME").unwrap_or_else(|_| DEFAULT_THEME.into());
+        let size = env::var("XCURSOR_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_SIZE);
+        let size = (f64::from(size) * scale).round() as u32;
+
+        let path = env::var("XCURSOR_PATH").unwrap_or_else(|_| DEFAULT_PATH.into());
+        let home = env::var("HOME").ok();
+        let search_path = path
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .filter_map(|dir| match (dir.starts_with('~'), &home) {
+                (true, Some(home)) => Some(PathBuf::from(dir.replacen('~', home, 1))),
+                (true, None) => None,
+                (false, _) => Some(PathBuf::from(dir)),
+            })
+            .collect();
+
+        debug!("Using cursor theme {} at size {}", name, size);
+        Self {
+            name,
+            size,
+            search_path,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Returns the first of the given cursors provided by the theme.
+    ///
+    /// The cursor is named after the first name, so backends showing cursors natively do not
+    /// have to know the legacy names.
+    pub fn load(&mut self, names: &[&'static str]) -> Option<CursorImage> {
+        let cursor = names.iter().filter_map(|name| self.load_one(name)).next()?;
+        Some(CursorImage {
+            name: names.first().cloned(),
+            ..cursor
+        })
+    }
+
+    fn load_one(&mut self, name: &'static str) -> Option<CursorImage> {
+        if let Some(cursor) = self.cache.get(name) {
+            return cursor.clone();
+        }
+
+        let cursor = self.find_file(name).and_then(|path| {
+            let cursor = fs::read(&path)
+                .ok()
+                .and_then(|data| parse_cursor(&data, self.size));
+            if cursor.is_none() {
+                warn!("Failed to load cursor {:?}", path);
+            }
+            cursor
+        });
+
+        self.cache.insert(name, cursor.clone());
+        cursor
+    }
+
+    /// Looks for the cursor in the theme, then in the themes it inherits from.
+    fn find_file(&self, name: &str) -> Option<PathBuf> {
+        let mut themes = vec![self.name.clone()];
+        let mut index = 0;
+        while index < themes.len() && index < MAX_INHERITANCE_DEPTH {
+            for dir in &self.search_path {
+                let path = dir.join(&themes[index]).join("cursors").join(name);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+
+            for parent in self.inherits(&themes[index]) {
+                if !themes.contains(&parent) {
+                    themes.push(parent);
+                }
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// Returns the themes listed in the `Inherits` key of the index of a theme.
+    fn inherits(&self, theme: &str) -> Vec<String> {
+        for dir in &self.search_path {
+            let index = match fs::read_to_string(dir.join(theme).join("index.theme")) {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+
+            for line in index.lines() {
+                let mut parts = line.splitn(2, '=');
+                if parts.next().map(str::trim) != Some("Inherits") {
+                    continue;
+                }
+
+                return parts
+                    .next()
+                    .unwrap_or("")
+                    .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                    .filter(|parent| !parent.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+        }
+        vec![]
+    }
+}
+
+/// Reads the image closest to the given size out of an XCursor file.
+///
+/// Animated cursors only show their first frame.
+fn parse_cursor(data: &[u8], size: u32) -> Option<CursorImage> {
+    if data.get(0..4)? != FILE_MAGIC {
+        return None;
+    }
+
+    let header_size = read_u32(data, 4)? as usize;
+    let entries = read_u32(data, 12)? as usize;
+
+    // Table of contents entries are (type, nominal size, position)
+    let mut best: Option<(u32, usize)> = None;
+    for entry in 0..entries {
+        let offset = header_size + entry * 12;
+        if read_u32(data, offset)? != IMAGE_TYPE {
+            continue;
+        }
+
+        let nominal = read_u32(data, offset + 4)?;
+        let position = read_u32(data, offset + 8)? as usize;
+        let closer = match best {
+            Some((best_size, _)) => {
+                (nominal as i64 - size as i64).abs() < (best_size as i64 - size as i64).abs()
+            }
+            None => true,
+        };
+        if closer {
+            best = Some((nominal, position));
+        }
+    }
+
+    let (_, position) = best?;
+    parse_image(data, position)
+}
+
+/// Reads an image chunk, made of a header followed by premultiplied ARGB pixels.
+fn parse_image(data: &[u8], position: usize) -> Option<CursorImage> {
+    let header_size = read_u32(data, position)? as usize;
+    let width = read_u32(data, position + 16)?;
+    let height = read_u32(data, position + 20)?;
+    let hotspot_x = read_u32(data, position + 24)?;
+    let hotspot_y = read_u32(data, position + 28)?;
+
+    let start = position + header_size;
+    let end = start + (width as usize) * (height as usize) * 4;
+    let pixels = data.get(start..end)?;
+
+    let mut image = RgbaImage::new(width, height);
+    for (pixel, bgra) in image.pixels_mut().zip(pixels.chunks(4)) {
+        // Pixels are little endian, so the bytes are in BGRA order
+        *pixel = Rgba([bgra[2], bgra[1], bgra[0], bgra[3]]);
+    }
+
+    Some(CursorImage {
+        image,
+        hotspot: (hotspot_x as i32, hotspot_y as i32),
+        name: None,
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(
+        u32::from(bytes[0])
+            | u32::from(bytes[1]) << 8
+            | u32::from(bytes[2]) << 16
+            | u32::from(bytes[3]) << 24,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_SIZE: u32 = 16;
+    const IMAGE_HEADER_SIZE: u32 = 36;
+
+    /// Builds a file holding a 1x1 image for each size, with the size as hotspot and blue value.
+    fn cursor_file(sizes: &[u32]) -> Vec<u8> {
+        let mut data = FILE_MAGIC.to_vec();
+        let push = |data: &mut Vec<u8>, value: u32| data.extend(&value.to_le_bytes());
+        push(&mut data, HEADER_SIZE);
+        push(&mut data, 0x1_0000);
+        push(&mut data, sizes.len() as u32);
+
+        let images = HEADER_SIZE + sizes.len() as u32 * 12;
+        for (index, &size) in sizes.iter().enumerate() {
+            push(&mut data, IMAGE_TYPE);
+            push(&mut data, size);
+            push(&mut data, images + index as u32 * (IMAGE_HEADER_SIZE + 4));
+        }
+        for &size in sizes {
+            for &value in &[IMAGE_HEADER_SIZE, IMAGE_TYPE, size, 1, 1, 1, size, size, 0] {
+                push(&mut data, value);
+            }
+            data.extend(&[size as u8, 2, 3, 4]);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_cursor_picks_closest_size() {
+        let data = cursor_file(&[16, 32, 48]);
+
+        let cursor = parse_cursor(&data, 30).unwrap();
+        assert_eq!(cursor.hotspot, (32, 32));
+        assert_eq!(*cursor.image.get_pixel(0, 0), Rgba([3, 2, 32, 4]));
+
+        assert_eq!(parse_cursor(&data, 100).unwrap().hotspot, (48, 48));
+        assert_eq!(parse_cursor(&data, 24).unwrap().hotspot, (16, 16));
+    }
+
+    #[test]
+    fn parse_cursor_rejects_invalid_files() {
+        let data = cursor_file(&[24]);
+        assert!(parse_cursor(&data[..data.len() - 1], 24).is_none());
+        assert!(parse_cursor(&data[..20], 24).is_none());
+        assert!(parse_cursor(b"Xcub", 24).is_none());
+        assert!(parse_cursor(&cursor_file(&[]), 24).is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+
+use log::{debug, error};
+use smithay::reexports::image::{png::PNGEncoder, ColorType};
+
+use crate::flutter::channel::{BasicMessageChannel, ChannelRegistry, MessageHandler};
+use crate::flutter::codec::{json_codec, Value};
+use crate::flutter::error::MessageError;
+use crate::renderer::gl;
+use crate::{json_value, FlutterCompositorRef, FlutterCompositorWeakRef};
+
+const SCREENSHOT_CHANNEL_NAME: &str = "flutter_compositor/screenshot";
+
+/// A frame read back from the framebuffer, as tightly packed RGBA rows from top to bottom.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns the part of the frame within the given region, which must lie within the frame.
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Frame {
+        let mut data = Vec::with_capacity((width * height * 4) as usize);
+        for row in y..y + height {
+            let start = ((row * self.width + x) * 4) as usize;
+            data.extend_from_slice(&self.data[start..start + (width * 4) as usize]);
+        }
+
+        Frame {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Alpha blends a premultiplied image onto the frame, with its top left corner at `position`.
+    pub fn draw_over(&mut self, image: &Frame, position: (i32, i32)) {
+        for iy in 0..image.height as i32 {
+            let y = position.1 + iy;
+            if y < 0 || y >= self.height as i32 {
+                continue;
+            }
+
+            for ix in 0..image.width as i32 {
+                let x = position.0 + ix;
+                if x < 0 || x >= self.width as i32 {
+                    continue;
+                }
+
+                let src = ((iy * image.width as i32 + ix) * 4) as usize;
+                let dst = ((y * self.width as i32 + x) * 4) as usize;
+                let alpha = u32::from(image.data[src + 3]);
+                for c in 0..4 {
+                    let under = u32::from(self.data[dst + c]) * (255 - alpha) / 255;
+                    self.data[dst + c] = (u32::from(image.data[src + c]) + under).min(255) as u8;
+                }
+            }
+        }
+    }
+
+    pub fn to_png(&self) -> io::Result<Vec<u8>> {
+        let mut png = vec![];
+        PNGEncoder::new(&mut png).encode(
+            &self.data,
+            self.width,
+            self.height,
+            ColorType::RGBA(8),
+        )?;
+        Ok(png)
+    }
+}
+
+/// A cursor image to composite onto captured frames, as it is not part of the flutter frame.
+pub(crate) struct CursorOverlay {
+    pub image: Frame,
+    /// Location of the top left corner of the image, in framebuffer pixels.
+    pub position: (i32, i32),
+}
+
+/// Captures waiting for the next frame to be presented.
+#[derive(Default)]
+pub(crate) struct CaptureQueue {
+    requests: Mutex<Vec<Sender<Frame>>>,
+}
+
+impl CaptureQueue {
+    pub fn request(&self) -> Receiver<Frame> {
+        let (sender, receiver) = mpsc::channel();
+        self.requests.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn is_pending(&self) -> bool {
+        !self.requests.lock().unwrap().is_empty()
+    }
+
+    /// Reads back the framebuffer flutter renders into and hands it to every waiting capture.
+    ///
+    /// Must be called with the rendering context current, before the buffers are swapped.
+    pub fn fulfil(&self, gl: &gl::Gl, framebuffer: u32, width: u32, height: u32) {
+        let requests: Vec<Sender<Frame>> = self.requests.lock().unwrap().drain(..).collect();
+        if requests.is_empty() {
+            return;
+        }
+
+        debug!("Capturing frame {}x{}", width, height);
+        let data = read_pixels(gl, framebuffer, width, height);
+        for request in requests {
+            let _ = request.send(Frame {
+                width,
+                height,
+                data: data.clone(),
+            });
+        }
+    }
+}
+
+fn read_pixels(gl: &gl::Gl, framebuffer: u32, width: u32, height: u32) -> Vec<u8> {
+    let stride = width as usize * 4;
+    let mut data = vec![0u8; stride * height as usize];
+
+    unsafe {
+        gl.BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl.ReadPixels(
+            0,
+            0,
+            width as i32,
+            height as i32,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            data.as_mut_ptr() as *mut c_void,
+        );
+    }
+
+    // GL returns the bottom row first
+    let mut flipped = Vec::with_capacity(data.len());
+    for row in data.chunks(stride).rev() {
+        flipped.extend_from_slice(row);
+    }
+    flipped
+}
+
+/// Serves screenshot requests from flutter.
+///
+/// A request is a map with an `id`, and captures the next presented frame. The frame is encoded
+/// as a PNG on its own thread, then sent back as a message with the same `id`, the size of the
+/// frame and the base64 encoded PNG, or an `error` if it could not be encoded.
+#[derive(Default)]
+pub(crate) struct ScreenshotManager {
+    handler: Arc<RwLock<ScreenshotHandler>>,
+    channel: RefCell<Weak<BasicMessageChannel>>,
+    encoding: RefCell<Vec<EncodingScreenshot>>,
+}
+
+/// A captured frame being encoded.
+struct EncodingScreenshot {
+    id: Value,
+    width: u32,
+    height: u32,
+    png: Receiver<io::Result<String>>,
+}
+
+impl ScreenshotManager {
+    pub fn register_channels(
+        &self,
+        registry: &ChannelRegistry,
+        compositor: FlutterCompositorWeakRef,
+    ) {
+        let handler = Arc::downgrade(&self.handler);
+
+        self.channel
+            .replace(registry.register_channel(BasicMessageChannel::new(
+                SCREENSHOT_CHANNEL_NAME,
+                handler,
+                &json_codec::CODEC,
+                compositor,
+            )));
+    }
+
+    /// Starts encoding the frames captured since the last call, and sends those encoded.
+    pub fn poll(&self) {
+        let mut encoding = self.encoding.borrow_mut();
+        for (id, frame) in self.handler.write().unwrap().take_frames() {
+            let (sender, png) = mpsc::channel();
+            encoding.push(EncodingScreenshot {
+                id,
+                width: frame.width,
+                height: frame.height,
+                png,
+            });
+            thread::spawn(move || {
+                let _ = sender.send(frame.to_png().map(|png| base64::encode(&png)));
+            });
+        }
+        if encoding.is_empty() {
+            return;
+        }
+
+        let channel = self.channel.borrow().upgrade();
+        let mut remaining = vec![];
+        for screenshot in encoding.drain(..) {
+            let png = match screenshot.png.try_recv() {
+                Ok(png) => png,
+                Err(TryRecvError::Empty) => {
+                    remaining.push(screenshot);
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    Err(io::Error::new(io::ErrorKind::Other, "encoder panicked"))
+                }
+            };
+
+            let mut json = match png {
+                Ok(png) => json_value!({
+                    "width": screenshot.width as i32,
+                    "height": screenshot.height as i32,
+                    "png": png.as_str()
+                }),
+                Err(err) => {
+                    error!("Failed to encode screenshot: {}", err);
+                    json_value!({
+                        "error": err.to_string().as_str()
+                    })
+                }
+            };
+            if let Value::Map(map) = &mut json {
+                map.insert("id".into(), screenshot.id);
+            }
+            if let Some(channel) = &channel {
+                channel.send(&json);
+            }
+        }
+        *encoding = remaining;
+    }
+}
+
+#[derive(Default)]
+struct ScreenshotHandler {
+    /// Captures waiting for a frame, with the id of their request.
+    pending: Vec<(Value, Receiver<Frame>)>,
+}
+
+impl ScreenshotHandler {
+    fn take_frames(&mut self) -> Vec<(Value, Frame)> {
+        let mut frames = vec![];
+        let mut remaining = vec![];
+        for (id, receiver) in self.pending.drain(..) {
+            match receiver.try_recv() {
+                Ok(frame) => frames.push((id, frame)),
+                Err(TryRecvError::Empty) => remaining.push((id, receiver)),
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        self.pending = remaining;
+        frames
+    }
+}
+
+impl MessageHandler for ScreenshotHandler {
+    fn on_message(
+        &mut self,
+        message: Value,
+        compositor: FlutterCompositorRef,
+    ) -> Result<Value, MessageError> {
+        let id = match message {
+            Value::Map(mut request) => request.remove("id").unwrap_or(Value::Null),
+            _ => Value::Null,
+        };
+        self.pending.push((id, compositor.capture_frame()));
+        Ok(Value::Null)
+    }
+}
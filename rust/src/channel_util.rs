@@ -0,0 +1,15 @@
+use crate::flutter::channel::MessageHandler;
+use crate::flutter::codec::Value;
+use crate::flutter::error::MessageError;
+use crate::FlutterCompositorRef;
+
+/// Handler of channels only used to send messages to flutter, answering anything sent back with
+/// `null`.
+#[derive(Default)]
+pub(crate) struct IgnoreHandler;
+
+impl MessageHandler for IgnoreHandler {
+    fn on_message(&mut self, _: Value, _: FlutterCompositorRef) -> Result<Value, MessageError> {
+        Ok(Value::Null)
+    }
+}
@@ -0,0 +1,147 @@
+//! Embedder API calls made by the compositor, and the engine callbacks it implements.
+//!
+//! The callbacks receive the pointer returned by `FlutterCompositorRef::to_mutex_ptr` as their
+//! user data. `FlutterEngine::run` installs them with `install_renderer_callbacks` and
+//! `install_project_callbacks` while it builds the engine arguments.
+
+use std::ffi::c_void;
+use std::mem;
+
+use log::{error, warn};
+
+use crate::backends::vsync::monotonic_nanos;
+use crate::flutter::{ffi, FlutterEngine};
+use crate::renderer::gl;
+use crate::{FlutterCompositor, FlutterCompositorRef};
+
+/// A pointer event at a position of the flutter surface in pixels.
+pub(crate) struct PointerEvent {
+    pub phase: ffi::FlutterPointerPhase,
+    pub kind: ffi::FlutterPointerDeviceKind,
+    pub device: i32,
+    pub location: (f64, f64),
+    /// Pressed mouse buttons, as `FlutterPointerMouseButtons` flags.
+    pub buttons: i64,
+    /// Scroll distance in pixels, making this a scroll signal if it is not zero.
+    pub scroll: (f64, f64),
+}
+
+fn check(call: &str, result: ffi::FlutterEngineResult) {
+    if result != ffi::FlutterEngineResult::kSuccess {
+        error!("{} failed: {:?}", call, result);
+    }
+}
+
+impl FlutterEngine {
+    /// Answers a vsync request made through `vsync_callback`.
+    pub(crate) fn on_vsync(&self, baton: isize, start: u64, target: u64) {
+        let result = unsafe { ffi::FlutterEngineOnVsync(self.engine_ptr(), baton, start, target) };
+        check("FlutterEngineOnVsync", result);
+    }
+
+    /// Makes a texture of the `TextureRegistry` available to `Texture` widgets.
+    pub(crate) fn register_external_texture(&self, id: i64) {
+        let result = unsafe { ffi::FlutterEngineRegisterExternalTexture(self.engine_ptr(), id) };
+        check("FlutterEngineRegisterExternalTexture", result);
+    }
+
+    pub(crate) fn unregister_external_texture(&self, id: i64) {
+        let result = unsafe { ffi::FlutterEngineUnregisterExternalTexture(self.engine_ptr(), id) };
+        check("FlutterEngineUnregisterExternalTexture", result);
+    }
+
+    /// Makes flutter fetch the texture again through `texture_frame_callback` on its next frame.
+    pub(crate) fn mark_texture_frame_available(&self, id: i64) {
+        let result =
+            unsafe { ffi::FlutterEngineMarkExternalTextureFrameAvailable(self.engine_ptr(), id) };
+        check("FlutterEngineMarkExternalTextureFrameAvailable", result);
+    }
+
+    pub(crate) fn send_pointer_event(&self, event: PointerEvent) {
+        let signal_kind = if event.scroll == (0.0, 0.0) {
+            ffi::FlutterPointerSignalKind::kFlutterPointerSignalKindNone
+        } else {
+            ffi::FlutterPointerSignalKind::kFlutterPointerSignalKindScroll
+        };
+        let event = ffi::FlutterPointerEvent {
+            struct_size: mem::size_of::<ffi::FlutterPointerEvent>(),
+            phase: event.phase,
+            // Flutter uses the monotonic clock in microseconds
+            timestamp: (monotonic_nanos() / 1000) as usize,
+            x: event.location.0,
+            y: event.location.1,
+            device: event.device,
+            signal_kind,
+            scroll_delta_x: event.scroll.0,
+            scroll_delta_y: event.scroll.1,
+            device_kind: event.kind,
+            buttons: event.buttons,
+        };
+        let result = unsafe { ffi::FlutterEngineSendPointerEvent(self.engine_ptr(), &event, 1) };
+        check("FlutterEngineSendPointerEvent", result);
+    }
+
+    /// Makes flutter render a frame even if nothing changed.
+    pub(crate) fn schedule_frame(&self) {
+        let result = unsafe { ffi::FlutterEngineScheduleFrame(self.engine_ptr()) };
+        check("FlutterEngineScheduleFrame", result);
+    }
+}
+
+/// Shuts an engine down, waiting for its threads to stop.
+///
+/// This takes the raw engine so that it can be called without holding the compositor, which the
+/// callbacks running on those threads lock.
+pub(crate) fn shutdown(engine: ffi::FlutterEngine) {
+    let result = unsafe { ffi::FlutterEngineShutdown(engine) };
+    check("FlutterEngineShutdown", result);
+}
+
+/// Sets the callbacks of the OpenGL renderer config implemented by the compositor.
+pub(crate) fn install_renderer_callbacks(config: &mut ffi::FlutterOpenGLRendererConfig) {
+    config.gl_external_texture_frame_callback = Some(texture_frame_callback);
+}
+
+/// Sets the callbacks of the project arguments implemented by the compositor.
+pub(crate) fn install_project_callbacks(args: &mut ffi::FlutterProjectArgs) {
+    args.vsync_callback = Some(vsync_callback);
+}
+
+unsafe fn compositor(
+    user_data: *mut c_void,
+) -> parking_lot::ReentrantMutexGuard<'static, FlutterCompositor> {
+    FlutterCompositorRef::get_from_mutex_ptr(user_data as *const _)
+}
+
+/// Hands the current texture of a surface to the raster thread.
+unsafe extern "C" fn texture_frame_callback(
+    user_data: *mut c_void,
+    texture_id: i64,
+    _width: usize,
+    _height: usize,
+    texture_out: *mut ffi::FlutterOpenGLTexture,
+) -> bool {
+    let compositor = compositor(user_data);
+    let info = match compositor.textures.borrow().get(texture_id) {
+        Some(info) => info,
+        None => {
+            warn!("Flutter requested unknown texture {}", texture_id);
+            return false;
+        }
+    };
+
+    let texture = &mut *texture_out;
+    texture.target = info.target;
+    texture.name = info.name;
+    texture.format = gl::RGBA8;
+    texture.user_data = std::ptr::null_mut();
+    texture.destruction_callback = None;
+    texture.width = info.width as usize;
+    texture.height = info.height as usize;
+    true
+}
+
+/// Paces flutter frames with the `FrameScheduler` of the backend.
+unsafe extern "C" fn vsync_callback(user_data: *mut c_void, baton: isize) {
+    compositor(user_data).vsync(baton);
+}
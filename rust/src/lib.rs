@@ -5,7 +5,7 @@ extern crate parking_lot;
 
 extern crate rand;
 
-use log::{info};
+use log::{error, info, warn};
 
 
 
@@ -13,15 +13,47 @@ use log::{info};
 pub mod backends;
 pub mod flutter;
 
+mod capture;
+
+mod channel_util;
+
+mod engine;
+
+mod lifecycle;
+
+mod mouse_cursor;
+
+mod outputs;
+
+mod platform;
+
+mod protocols;
+
 mod renderer;
 
 mod window_map;
 
 mod shell;
 
+mod textures;
+
 use crate::backends::CompositorBackend;
+use crate::capture::ScreenshotManager;
 use crate::flutter::channel::Channel;
 use crate::flutter::FlutterEngine;
+use crate::lifecycle::LifecycleManager;
+use crate::mouse_cursor::MouseCursorManager;
+use crate::outputs::OutputManager;
+use crate::platform::PlatformManager;
+use crate::renderer::texture::TextureRegistry;
+use crate::textures::TextureManager;
+
+pub use crate::backends::{CompositorError, ModeSelection, MonitorLayout};
+pub use crate::capture::Frame;
+pub use crate::protocols::screencopy::ScreencopyPolicy;
+pub use crate::renderer::egl_util::EglError;
+pub use crate::renderer::texture::TextureStats;
+pub use smithay::reexports::wayland_server::protocol::wl_output::Transform;
 
 
 use parking_lot::{ReentrantMutex, ReentrantMutexGuard};
@@ -30,7 +62,9 @@ use std::cell::RefCell;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc, Weak};
+use std::{io, ptr};
 use tokio::runtime::{Runtime, TaskExecutor};
+use wayland_server::calloop::signals::{Signal, Signals};
 
 pub struct FlutterCompositorRef {
     inner: Arc<ReentrantMutex<FlutterCompositor>>,
@@ -112,10 +146,22 @@ pub struct FlutterCompositor {
     main_thread_sender: Sender<MainThreadCallback>,
     main_thread_receiver: Receiver<MainThreadCallback>,
     engine: FlutterEngine,
+    textures: RefCell<TextureRegistry>,
+    texture_manager: TextureManager,
+    screenshots: ScreenshotManager,
+    mouse_cursors: MouseCursorManager,
+    outputs: OutputManager,
+    lifecycle: LifecycleManager,
+    platform: PlatformManager,
+    /// Cleared to make `start` return.
+    running: AtomicBool,
 }
 
 impl FlutterCompositor {
     pub fn new(backend: CompositorBackend) -> FlutterCompositorRef {
+        // Threads inherit the signal mask, so only the event loop receives SIGTERM and SIGINT
+        let _signals = BlockSignals::new();
+
         let runtime = Runtime::new().unwrap();
 
         let (main_tx, main_rx) = mpsc::channel();
@@ -128,6 +174,14 @@ impl FlutterCompositor {
                 main_thread_receiver: main_rx,
                 runtime,
                 engine: FlutterEngine::new(),
+                textures: RefCell::new(TextureRegistry::default()),
+                texture_manager: TextureManager::default(),
+                screenshots: ScreenshotManager::default(),
+                mouse_cursors: MouseCursorManager::default(),
+                outputs: OutputManager::default(),
+                lifecycle: LifecycleManager::default(),
+                platform: PlatformManager::default(),
+                running: AtomicBool::new(true),
             })),
         };
 
@@ -139,6 +193,33 @@ impl FlutterCompositor {
 
         compositor_ref
     }
+
+    /// Handles a vsync request from the engine, starting the frame on the next vblank.
+    pub(crate) fn vsync(&self, baton: isize) {
+        if let Some(times) = self.backend.scheduler.request(baton) {
+            self.engine.on_vsync(times.baton, times.start, times.target);
+        }
+    }
+
+    /// Deletes the textures of surfaces destroyed since the last call.
+    fn collect_textures(&self) {
+        let garbage = self.textures.borrow_mut().collect_garbage();
+        if garbage.is_empty() {
+            return;
+        }
+
+        for texture in &garbage {
+            if let Some(id) = texture.flutter_id {
+                self.engine.unregister_external_texture(id);
+            }
+        }
+
+        self.backend.with_upload_context(move |gl| {
+            for texture in garbage {
+                texture.delete(gl);
+            }
+        });
+    }
 }
 
 impl FlutterCompositorRef {
@@ -149,13 +230,44 @@ impl FlutterCompositorRef {
         self.get().engine.channel_registry.register_channel(channel)
     }
 
-    pub fn start(&self) {
+    pub fn texture_stats(&self) -> TextureStats {
+        self.get().textures.borrow().stats()
+    }
+
+    /// Captures the next frame presented by flutter.
+    ///
+    /// The frame is read back right before it is presented, so a frame is scheduled even if the
+    /// UI is static. There is no headless backend, the receiver only resolves while a window or
+    /// monitor shows flutter; it is dropped without a frame if the compositor stops first.
+    pub fn capture_frame(&self) -> Receiver<Frame> {
+        let compositor = self.get();
+        let capture = compositor.backend.captures.request();
+        compositor.engine.schedule_frame();
+        capture
+    }
+
+    /// Stops the compositor, making `start` shut it down and return.
+    pub fn stop(&self) {
+        self.get().running.store(false, Ordering::SeqCst);
+    }
+
+    /// Runs the compositor until it is stopped, by `stop`, SIGTERM, SIGINT or flutter popping its
+    /// last route.
+    ///
+    /// Before returning, clients are disconnected, the engine is shut down and the VT is handed
+    /// back.
+    pub fn start(&self) -> Result<(), CompositorError> {
         let weak = self.downgrade();
+        let _signals = BlockSignals::new();
+        let mut shutdown = Shutdown {
+            compositor: self,
+            engine: false,
+        };
 
         let event_loop = {
             let mut compositor = self.get();
 
-            compositor.backend.init(weak.clone());
+            compositor.backend.init(weak.clone())?;
 
             {
                 let mut input_ref = compositor.backend.input.borrow_mut();
@@ -165,19 +277,60 @@ impl FlutterCompositorRef {
                     .register_channels(&compositor.engine.channel_registry);
             }
 
+            compositor
+                .texture_manager
+                .register_channels(&compositor.engine.channel_registry, weak.clone());
+
+            compositor
+                .screenshots
+                .register_channels(&compositor.engine.channel_registry, weak.clone());
+
+            compositor
+                .mouse_cursors
+                .register_channels(&compositor.engine.channel_registry, weak.clone());
+            compositor.mouse_cursors.load_theme(&compositor.backend);
+
+            compositor
+                .outputs
+                .register_channels(&compositor.engine.channel_registry, weak.clone());
+
+            compositor
+                .lifecycle
+                .register_channels(&compositor.engine.channel_registry, weak.clone());
+
+            compositor
+                .platform
+                .register_channels(&compositor.engine.channel_registry, weak.clone());
+
+            let signals = Signals::new(&[Signal::SIGTERM, Signal::SIGINT])
+                .map_err(CompositorError::Signals)?;
+            let stop = weak.clone();
+            RefCell::borrow(compositor.backend.event_loop.borrow())
+                .as_ref()
+                .unwrap()
+                .handle()
+                .insert_source(signals, move |event, _| {
+                    info!("Received {:?}, stopping", event.signal());
+                    if let Some(compositor_ref) = stop.upgrade() {
+                        compositor_ref.stop();
+                    }
+                })
+                .map_err(|e| -> io::Error { e.into() })
+                .map_err(CompositorError::Signals)?;
+
             FlutterEngine::run(&mut compositor);
+            shutdown.engine = true;
 
-            compositor.backend.run();
+            compositor.backend.run()?;
 
             info!("pre get_framebuffer_size");
 
             let dims = compositor.backend.get_framebuffer_size();
-            info!("dims={:?}", dims);
-            compositor.engine.send_window_metrics_event(
-                dims.0 as i32,
-                dims.1 as i32,
-                dims.1 as f64 / 1080.0,
-            );
+            let scale = compositor.backend.get_scale_factor();
+            info!("dims={:?} scale={}", dims, scale);
+            compositor
+                .engine
+                .send_window_metrics_event(dims.0 as i32, dims.1 as i32, scale);
 
             info!("post get_framebuffer_size");
 
@@ -186,15 +339,22 @@ impl FlutterCompositorRef {
 
         info!("Starting loop");
 
-        let running = Arc::new(AtomicBool::new(true));
-
-        let mut val = 0;
-
-        while running.load(Ordering::SeqCst) {
+        loop {
             {
                 let compositor_ref = weak.upgrade().unwrap();
                 let compositor = compositor_ref.get();
+                if !compositor.running.load(Ordering::SeqCst) {
+                    break;
+                }
                 compositor.backend.update();
+                compositor.collect_textures();
+                compositor
+                    .texture_manager
+                    .poll(&mut compositor.textures.borrow_mut());
+                compositor.screenshots.poll();
+                compositor
+                    .outputs
+                    .poll(&compositor.backend, &compositor.engine);
 
                 // Process callbacks
                 let callbacks: Vec<MainThreadCallback> =
@@ -225,17 +385,65 @@ impl FlutterCompositorRef {
                 .dispatch(Some(::std::time::Duration::from_millis(16)), &mut ())
                 .is_err()
             {
-                running.store(false, Ordering::SeqCst);
-            }
-
-            val += 1;
-            if val > 60 * 10 {
-                running.store(false, Ordering::SeqCst);
+                error!("Failed to dispatch the event loop, stopping");
+                break;
             }
 
             //            else {
             //                window_map.borrow_mut().refresh();
             //            }
         }
+
+        Ok(())
+    }
+}
+
+/// Tears down what `start` brought up when dropped, so that it happens however `start` returns.
+struct Shutdown<'a> {
+    compositor: &'a FlutterCompositorRef,
+    /// Whether the engine was started.
+    engine: bool,
+}
+
+impl Drop for Shutdown<'_> {
+    fn drop(&mut self) {
+        info!("Shutting down");
+        if self.engine {
+            // The engine threads lock the compositor until they stop, so it must not be held here
+            let engine = self.compositor.get().engine.engine_ptr();
+            engine::shutdown(engine);
+        }
+        // The engine renders with the contexts of the backend
+        self.compositor.get().backend.shutdown();
+    }
+}
+
+/// Blocks SIGTERM and SIGINT on the calling thread, restoring the previous signal mask when
+/// dropped.
+struct BlockSignals(libc::sigset_t);
+
+impl BlockSignals {
+    fn new() -> Self {
+        unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            let mut previous: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            if libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut previous) != 0 {
+                warn!("Failed to block SIGTERM and SIGINT");
+            }
+            BlockSignals(previous)
+        }
+    }
+}
+
+impl Drop for BlockSignals {
+    fn drop(&mut self) {
+        unsafe {
+            if libc::pthread_sigmask(libc::SIG_SETMASK, &self.0, ptr::null_mut()) != 0 {
+                warn!("Failed to restore the signal mask");
+            }
+        }
     }
 }
@@ -0,0 +1,54 @@
+use std::cell::RefCell;
+use std::sync::{Arc, RwLock, Weak};
+
+use log::debug;
+
+use crate::channel_util::IgnoreHandler;
+use crate::flutter::channel::{BasicMessageChannel, ChannelRegistry};
+use crate::flutter::codec::{string_codec, Value};
+use crate::FlutterCompositorWeakRef;
+
+const LIFECYCLE_CHANNEL_NAME: &str = "flutter/lifecycle";
+
+const STATE_PAUSED: &str = "AppLifecycleState.paused";
+const STATE_RESUMED: &str = "AppLifecycleState.resumed";
+
+/// Tells flutter whether it is shown, so it stops animating while the session is inactive.
+#[derive(Default)]
+pub(crate) struct LifecycleManager {
+    handler: Arc<RwLock<IgnoreHandler>>,
+    channel: RefCell<Weak<BasicMessageChannel>>,
+}
+
+impl LifecycleManager {
+    pub fn register_channels(
+        &self,
+        registry: &ChannelRegistry,
+        compositor: FlutterCompositorWeakRef,
+    ) {
+        let handler = Arc::downgrade(&self.handler);
+
+        self.channel
+            .replace(registry.register_channel(BasicMessageChannel::new(
+                LIFECYCLE_CHANNEL_NAME,
+                handler,
+                &string_codec::CODEC,
+                compositor,
+            )));
+    }
+
+    pub fn paused(&self) {
+        self.send_state(STATE_PAUSED);
+    }
+
+    pub fn resumed(&self) {
+        self.send_state(STATE_RESUMED);
+    }
+
+    fn send_state(&self, state: &str) {
+        debug!("Lifecycle state {}", state);
+        if let Some(channel) = self.channel.borrow().upgrade() {
+            channel.send(&Value::String(state.into()));
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::{Arc, RwLock};
+
+use log::debug;
+
+use crate::backends::cursor::CursorStatus;
+use crate::backends::xcursor::CursorTheme;
+use crate::backends::CompositorBackend;
+use crate::flutter::channel::{ChannelRegistry, MethodCallHandler, MethodChannel};
+use crate::flutter::codec::{standard_codec, MethodCall, Value};
+use crate::flutter::error::MethodCallError;
+use crate::{FlutterCompositorRef, FlutterCompositorWeakRef};
+
+const MOUSE_CURSOR_CHANNEL_NAME: &str = "flutter/mousecursor";
+
+/// Theme cursors for the default cursor, the CSS name first followed by the X11 name.
+const DEFAULT_CURSOR: &[&str] = &["default", "left_ptr"];
+
+/// Shows the system cursors flutter asks for, using images from the XCursor theme.
+#[derive(Default)]
+pub(crate) struct MouseCursorManager {
+    handler: Arc<RwLock<MouseCursorHandler>>,
+}
+
+impl MouseCursorManager {
+    pub fn register_channels(
+        &self,
+        registry: &ChannelRegistry,
+        compositor: FlutterCompositorWeakRef,
+    ) {
+        let handler = Arc::downgrade(&self.handler);
+
+        registry.register_channel(MethodChannel::new(
+            MOUSE_CURSOR_CHANNEL_NAME,
+            handler,
+            &standard_codec::CODEC,
+            compositor,
+        ));
+    }
+
+    /// Loads the cursor theme at the scale of the output, replacing the built in default cursor.
+    pub fn load_theme(&self, backend: &CompositorBackend) {
+        let mut handler = self.handler.write().unwrap();
+        let mut theme = CursorTheme::from_env(backend.get_scale_factor());
+        if let Some(cursor) = theme.load(DEFAULT_CURSOR) {
+            backend.cursor.set_default(cursor);
+        }
+        handler.theme = Some(theme);
+    }
+}
+
+#[derive(Default)]
+struct MouseCursorHandler {
+    theme: Option<CursorTheme>,
+}
+
+impl MouseCursorHandler {
+    fn activate_system_cursor(&mut self, kind: &str, backend: &CompositorBackend) {
+        if kind == "none" {
+            backend.set_cursor(CursorStatus::Hidden);
+            return;
+        }
+
+        let names = cursor_names(kind).unwrap_or_else(|| {
+            debug!("Unknown cursor kind {}, using the default cursor", kind);
+            DEFAULT_CURSOR
+        });
+
+        let theme = match &mut self.theme {
+            Some(theme) => theme,
+            None => {
+                backend.set_cursor(CursorStatus::Default);
+                return;
+            }
+        };
+
+        let status = match theme.load(names) {
+            Some(cursor) => CursorStatus::Image(cursor),
+            None => {
+                debug!(
+                    "Cursor theme has no {} cursor, using the default cursor",
+                    kind
+                );
+                CursorStatus::Default
+            }
+        };
+        backend.set_cursor(status);
+    }
+}
+
+impl MethodCallHandler for MouseCursorHandler {
+    fn on_method_call(
+        &mut self,
+        call: MethodCall,
+        compositor: FlutterCompositorRef,
+    ) -> Result<Value, MethodCallError> {
+        match call.method.as_str() {
+            "activateSystemCursor" => {
+                let kind = match &call.args {
+                    Value::Map(args) => match args.get("kind") {
+                        Some(Value::String(kind)) => kind.clone(),
+                        _ => return Err(MethodCallError::UnspecifiedError),
+                    },
+                    _ => return Err(MethodCallError::UnspecifiedError),
+                };
+
+                let compositor = compositor.get();
+                self.activate_system_cursor(&kind, &compositor.backend);
+                Ok(Value::Null)
+            }
+            _ => Err(MethodCallError::NotImplemented),
+        }
+    }
+}
+
+/// Returns the theme cursors for a flutter cursor kind, the CSS name first followed by the X11
+/// names older themes use.
+fn cursor_names(kind: &str) -> Option<&'static [&'static str]> {
+    let names: &'static [&'static str] = match kind {
+        "basic" => DEFAULT_CURSOR,
+        "click" => &["pointer", "hand2", "hand1"],
+        "forbidden" => &["not-allowed", "crossed_circle"],
+        "wait" => &["wait", "watch"],
+        "progress" => &["progress", "left_ptr_watch"],
+        "contextMenu" => &["context-menu", "left_ptr"],
+        "help" => &["help", "question_arrow"],
+        "text" => &["text", "xterm"],
+        "verticalText" => &["vertical-text", "xterm"],
+        "cell" => &["cell", "plus"],
+        "precise" => &["crosshair", "cross"],
+        "move" => &["move", "fleur"],
+        "grab" => &["grab", "openhand", "hand1"],
+        "grabbing" => &["grabbing", "closedhand", "fleur"],
+        "noDrop" => &["no-drop", "crossed_circle"],
+        "alias" => &["alias", "dnd-link"],
+        "copy" => &["copy", "dnd-copy"],
+        "allScroll" => &["all-scroll", "fleur"],
+        "resizeLeftRight" => &["ew-resize", "sb_h_double_arrow"],
+        "resizeUpDown" => &["ns-resize", "sb_v_double_arrow"],
+        "resizeUpLeftDownRight" => &["nwse-resize", "bd_double_arrow"],
+        "resizeUpRightDownLeft" => &["nesw-resize", "fd_double_arrow"],
+        "resizeUp" => &["n-resize", "top_side"],
+        "resizeDown" => &["s-resize", "bottom_side"],
+        "resizeLeft" => &["w-resize", "left_side"],
+        "resizeRight" => &["e-resize", "right_side"],
+        "resizeUpLeft" => &["nw-resize", "top_left_corner"],
+        "resizeUpRight" => &["ne-resize", "top_right_corner"],
+        "resizeDownLeft" => &["sw-resize", "bottom_left_corner"],
+        "resizeDownRight" => &["se-resize", "bottom_right_corner"],
+        "resizeColumn" => &["col-resize", "sb_h_double_arrow"],
+        "resizeRow" => &["row-resize", "sb_v_double_arrow"],
+        "zoomIn" => &["zoom-in"],
+        "zoomOut" => &["zoom-out"],
+        _ => return None,
+    };
+    Some(names)
+}
@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock, Weak};
+
+use log::{debug, info};
+
+use crate::backends::layout::{OutputEvent, OutputInfo};
+use crate::backends::{CompositorBackend, ModeSelection};
+use crate::channel_util::IgnoreHandler;
+use crate::flutter::channel::{
+    BasicMessageChannel, ChannelRegistry, MethodCallHandler, MethodChannel,
+};
+use crate::flutter::codec::{json_codec, MethodCall, Value};
+use crate::flutter::error::MethodCallError;
+use crate::flutter::FlutterEngine;
+use crate::{json_value, FlutterCompositorRef, FlutterCompositorWeakRef};
+
+const OUTPUTS_CHANNEL_NAME: &str = "flutter_compositor/outputs";
+const DISPLAY_CHANNEL_NAME: &str = "flutter_compositor/display";
+
+/// Tells flutter about monitors being connected, disconnected or switching modes, and lets it
+/// pick their modes and turn them off.
+///
+/// Every change is sent on the outputs channel as an `added`, `changed` or `removed` event,
+/// followed by window metrics for the new size of the flutter surface. Monitors are described
+/// with their EDID identification and physical size in millimetres, zero if unknown.
+#[derive(Default)]
+pub(crate) struct OutputManager {
+    handler: Arc<RwLock<IgnoreHandler>>,
+    channel: RefCell<Weak<BasicMessageChannel>>,
+    display_handler: Arc<RwLock<DisplayHandler>>,
+}
+
+impl OutputManager {
+    pub fn register_channels(
+        &self,
+        registry: &ChannelRegistry,
+        compositor: FlutterCompositorWeakRef,
+    ) {
+        let handler = Arc::downgrade(&self.handler);
+
+        self.channel
+            .replace(registry.register_channel(BasicMessageChannel::new(
+                OUTPUTS_CHANNEL_NAME,
+                handler,
+                &json_codec::CODEC,
+                compositor.clone(),
+            )));
+
+        let handler = Arc::downgrade(&self.display_handler);
+
+        registry.register_channel(MethodChannel::new(
+            DISPLAY_CHANNEL_NAME,
+            handler,
+            &json_codec::CODEC,
+            compositor,
+        ));
+    }
+
+    /// Sends the monitor changes since the last call.
+    pub fn poll(&self, backend: &CompositorBackend, engine: &FlutterEngine) {
+        let events = backend.take_output_events();
+        if events.is_empty() {
+            return;
+        }
+
+        if let Some(channel) = self.channel.borrow().upgrade() {
+            for event in events {
+                let json = match event {
+                    OutputEvent::Added(info) => output_json("added", &info),
+                    OutputEvent::Changed(info) => output_json("changed", &info),
+                    OutputEvent::Removed(name) => json_value!({
+                        "event": "removed",
+                        "name": name.as_str()
+                    }),
+                };
+                channel.send(&json);
+            }
+        }
+
+        let size = backend.get_framebuffer_size();
+        if size == (0, 0) {
+            debug!("No monitor left, keeping the window metrics");
+            return;
+        }
+        let scale = backend.get_scale_factor();
+        info!("Flutter surface resized to {:?}, scale {}", size, scale);
+        engine.send_window_metrics_event(size.0 as i32, size.1 as i32, scale);
+    }
+}
+
+fn output_json(event: &str, info: &OutputInfo) -> Value {
+    let mut json = json_value!({
+        "event": event,
+        "name": info.name.as_str(),
+        "make": info.make.as_str(),
+        "model": info.model.as_str(),
+        "serial": info.serial.as_str(),
+        "x": info.region.x,
+        "y": info.region.y,
+        "width": info.size.0 as i32,
+        "height": info.size.1 as i32,
+        "physicalWidth": info.size_mm.0 as i32,
+        "physicalHeight": info.size_mm.1 as i32,
+        "refresh": info.refresh,
+        "transform": info.transform.to_raw() as i32
+    });
+
+    let modes = info
+        .modes
+        .iter()
+        .map(|mode| {
+            json_value!({
+                "width": mode.width,
+                "height": mode.height,
+                "refresh": mode.refresh
+            })
+        })
+        .collect();
+    if let Value::Map(map) = &mut json {
+        map.insert("modes".into(), Value::List(modes));
+        map.insert("powered".into(), Value::Boolean(info.powered));
+    }
+    json
+}
+
+#[derive(Default)]
+struct DisplayHandler;
+
+impl MethodCallHandler for DisplayHandler {
+    fn on_method_call(
+        &mut self,
+        call: MethodCall,
+        compositor: FlutterCompositorRef,
+    ) -> Result<Value, MethodCallError> {
+        match call.method.as_str() {
+            "setMode" => {
+                let args = match &call.args {
+                    Value::Map(args) => args,
+                    _ => return Err(MethodCallError::UnspecifiedError),
+                };
+                let name = match args.get("name") {
+                    Some(Value::String(name)) => name.clone(),
+                    _ => return Err(MethodCallError::UnspecifiedError),
+                };
+                let dimension =
+                    |key| int_arg(args, key).and_then(|value| u32::try_from(value).ok());
+                let (width, height) = match (dimension("width"), dimension("height")) {
+                    (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+                    _ => return Err(MethodCallError::UnspecifiedError),
+                };
+                let refresh = match int_arg(args, "refresh") {
+                    Some(refresh) => match i32::try_from(refresh) {
+                        Ok(refresh) if refresh > 0 => Some(refresh),
+                        _ => return Err(MethodCallError::UnspecifiedError),
+                    },
+                    None => None,
+                };
+                let mode = ModeSelection {
+                    width,
+                    height,
+                    refresh,
+                };
+
+                let compositor = compositor.get();
+                let changed = compositor.backend.set_output_mode(&name, mode);
+                Ok(Value::Boolean(changed))
+            }
+            "setPower" => {
+                let args = match &call.args {
+                    Value::Map(args) => args,
+                    _ => return Err(MethodCallError::UnspecifiedError),
+                };
+                let (name, on) = match (args.get("name"), args.get("on")) {
+                    (Some(Value::String(name)), Some(Value::Boolean(on))) => (name.clone(), *on),
+                    _ => return Err(MethodCallError::UnspecifiedError),
+                };
+
+                let compositor = compositor.get();
+                let applied = compositor.backend.set_output_power(&name, on);
+                Ok(Value::Boolean(applied))
+            }
+            _ => Err(MethodCallError::NotImplemented),
+        }
+    }
+}
+
+fn int_arg(args: &HashMap<String, Value>, key: &str) -> Option<i64> {
+    match args.get(key) {
+        Some(Value::I32(value)) => Some(i64::from(*value)),
+        Some(Value::I64(value)) => Some(*value),
+        _ => None,
+    }
+}
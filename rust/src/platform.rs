@@ -0,0 +1,54 @@
+use std::sync::{Arc, RwLock};
+
+use log::info;
+
+use crate::flutter::channel::{ChannelRegistry, MethodCallHandler, MethodChannel};
+use crate::flutter::codec::{json_codec, MethodCall, Value};
+use crate::flutter::error::MethodCallError;
+use crate::{FlutterCompositorRef, FlutterCompositorWeakRef};
+
+const PLATFORM_CHANNEL_NAME: &str = "flutter/platform";
+
+/// Handles the platform requests of flutter, so far only `SystemNavigator.pop`, which stops the
+/// compositor.
+#[derive(Default)]
+pub(crate) struct PlatformManager {
+    handler: Arc<RwLock<PlatformHandler>>,
+}
+
+impl PlatformManager {
+    pub fn register_channels(
+        &self,
+        registry: &ChannelRegistry,
+        compositor: FlutterCompositorWeakRef,
+    ) {
+        let handler = Arc::downgrade(&self.handler);
+
+        registry.register_channel(MethodChannel::new(
+            PLATFORM_CHANNEL_NAME,
+            handler,
+            &json_codec::CODEC,
+            compositor,
+        ));
+    }
+}
+
+#[derive(Default)]
+struct PlatformHandler;
+
+impl MethodCallHandler for PlatformHandler {
+    fn on_method_call(
+        &mut self,
+        call: MethodCall,
+        compositor: FlutterCompositorRef,
+    ) -> Result<Value, MethodCallError> {
+        match call.method.as_str() {
+            "SystemNavigator.pop" => {
+                info!("Flutter popped its last route, stopping");
+                compositor.stop();
+                Ok(Value::Null)
+            }
+            _ => Err(MethodCallError::NotImplemented),
+        }
+    }
+}
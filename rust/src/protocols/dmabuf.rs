@@ -0,0 +1,615 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+use log::{debug, warn};
+use wayland_protocols::unstable::linux_dmabuf::v1::server::{
+    zwp_linux_buffer_params_v1, zwp_linux_dmabuf_v1,
+};
+use wayland_server::protocol::wl_buffer;
+use wayland_server::{Display, Global, NewResource};
+
+use crate::renderer::egl_util::WrappedDisplay;
+use crate::FlutterCompositorWeakRef;
+
+use zwp_linux_buffer_params_v1::ZwpLinuxBufferParamsV1;
+use zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1;
+
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+const MAX_PLANES: u32 = 4;
+
+const fn fourcc(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    (a as u32) | (b as u32) << 8 | (c as u32) << 16 | (d as u32) << 24
+}
+
+/// Number of planes used by common formats when no vendor modifier adds auxiliary planes.
+const PLANE_COUNTS: [(u32, usize); 14] = [
+    (fourcc(b'A', b'R', b'2', b'4'), 1),
+    (fourcc(b'X', b'R', b'2', b'4'), 1),
+    (fourcc(b'A', b'B', b'2', b'4'), 1),
+    (fourcc(b'X', b'B', b'2', b'4'), 1),
+    (fourcc(b'R', b'G', b'1', b'6'), 1),
+    (fourcc(b'A', b'R', b'3', b'0'), 1),
+    (fourcc(b'X', b'R', b'3', b'0'), 1),
+    (fourcc(b'Y', b'U', b'Y', b'V'), 1),
+    (fourcc(b'N', b'V', b'1', b'2'), 2),
+    (fourcc(b'N', b'V', b'2', b'1'), 2),
+    (fourcc(b'P', b'0', b'1', b'0'), 2),
+    (fourcc(b'Y', b'U', b'1', b'2'), 3),
+    (fourcc(b'Y', b'V', b'1', b'2'), 3),
+    (fourcc(b'Y', b'U', b'2', b'4'), 3),
+];
+
+fn expected_planes(format: u32) -> Option<usize> {
+    PLANE_COUNTS
+        .iter()
+        .find(|(f, _)| *f == format)
+        .map(|(_, count)| *count)
+}
+
+/// A format and modifier pair that can be imported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DmabufFormat {
+    pub format: u32,
+    pub modifier: u64,
+}
+
+/// A single plane of a dmabuf. The fd is owned and closed when dropped.
+#[derive(Debug)]
+pub struct Plane {
+    pub fd: RawFd,
+    pub plane_idx: u32,
+    pub offset: u32,
+    pub stride: u32,
+    pub modifier: u64,
+    /// Size of the underlying file, if it could be determined.
+    pub size: Option<u64>,
+}
+
+impl Plane {
+    pub fn new(fd: RawFd, plane_idx: u32, offset: u32, stride: u32, modifier: u64) -> Self {
+        let size = unsafe {
+            let size = libc::lseek(fd, 0, libc::SEEK_END);
+            libc::lseek(fd, 0, libc::SEEK_SET);
+            if size < 0 {
+                None
+            } else {
+                Some(size as u64)
+            }
+        };
+
+        Self {
+            fd,
+            plane_idx,
+            offset,
+            stride,
+            modifier,
+            size,
+        }
+    }
+}
+
+impl Drop for Plane {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+/// A validated client buffer, stored as the user data of its `wl_buffer`.
+#[derive(Debug)]
+pub struct Dmabuf {
+    pub width: i32,
+    pub height: i32,
+    pub format: u32,
+    pub flags: zwp_linux_buffer_params_v1::Flags,
+    pub planes: Vec<Plane>,
+}
+
+impl Dmabuf {
+    pub fn y_inverted(&self) -> bool {
+        self.flags
+            .contains(zwp_linux_buffer_params_v1::Flags::YInvert)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamError {
+    AlreadyUsed,
+    PlaneIdx,
+    PlaneSet,
+    Incomplete,
+    InvalidFormat,
+    InvalidDimensions,
+    OutOfBounds,
+}
+
+impl ParamError {
+    fn code(self) -> u32 {
+        use zwp_linux_buffer_params_v1::Error;
+        (match self {
+            ParamError::AlreadyUsed => Error::AlreadyUsed,
+            ParamError::PlaneIdx => Error::PlaneIdx,
+            ParamError::PlaneSet => Error::PlaneSet,
+            ParamError::Incomplete => Error::Incomplete,
+            ParamError::InvalidFormat => Error::InvalidFormat,
+            ParamError::InvalidDimensions => Error::InvalidDimensions,
+            ParamError::OutOfBounds => Error::OutOfBounds,
+        }) as u32
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ParamError::AlreadyUsed => "params were already used to create a buffer",
+            ParamError::PlaneIdx => "plane index out of bounds",
+            ParamError::PlaneSet => "plane index was already set",
+            ParamError::Incomplete => "missing or unexpected planes",
+            ParamError::InvalidFormat => "format or modifier not supported",
+            ParamError::InvalidDimensions => "invalid width or height",
+            ParamError::OutOfBounds => "plane offset or stride out of bounds",
+        })
+    }
+}
+
+/// The state of a `zwp_linux_buffer_params_v1` object.
+#[derive(Default)]
+pub struct BufferParams {
+    planes: Vec<Plane>,
+    used: bool,
+}
+
+impl BufferParams {
+    pub fn add(&mut self, plane: Plane) -> Result<(), ParamError> {
+        if self.used {
+            return Err(ParamError::AlreadyUsed);
+        }
+        if plane.plane_idx >= MAX_PLANES {
+            return Err(ParamError::PlaneIdx);
+        }
+        if self.planes.iter().any(|p| p.plane_idx == plane.plane_idx) {
+            return Err(ParamError::PlaneSet);
+        }
+
+        self.planes.push(plane);
+        Ok(())
+    }
+
+    /// Consumes the added planes, returning the buffer they describe if they are valid.
+    pub fn build(
+        &mut self,
+        width: i32,
+        height: i32,
+        format: u32,
+        flags: u32,
+        formats: &[DmabufFormat],
+    ) -> Result<Dmabuf, ParamError> {
+        if self.used {
+            return Err(ParamError::AlreadyUsed);
+        }
+        self.used = true;
+
+        let mut planes = std::mem::replace(&mut self.planes, vec![]);
+        planes.sort_by_key(|p| p.plane_idx);
+        validate(&planes, width, height, format, formats)?;
+
+        Ok(Dmabuf {
+            width,
+            height,
+            format,
+            flags: zwp_linux_buffer_params_v1::Flags::from_bits_truncate(flags),
+            planes,
+        })
+    }
+}
+
+/// Checks that a set of planes, sorted by index, describes a buffer we can import.
+pub fn validate(
+    planes: &[Plane],
+    width: i32,
+    height: i32,
+    format: u32,
+    formats: &[DmabufFormat],
+) -> Result<(), ParamError> {
+    if planes.is_empty() {
+        return Err(ParamError::Incomplete);
+    }
+
+    // Planes must be set contiguously from 0
+    if planes
+        .iter()
+        .enumerate()
+        .any(|(i, p)| p.plane_idx != i as u32)
+    {
+        return Err(ParamError::Incomplete);
+    }
+
+    if width < 1 || height < 1 {
+        return Err(ParamError::InvalidDimensions);
+    }
+
+    // All planes must share the same modifier
+    let modifier = planes[0].modifier;
+    if planes.iter().any(|p| p.modifier != modifier) {
+        return Err(ParamError::InvalidFormat);
+    }
+
+    if !formats.iter().any(|f| {
+        f.format == format && (f.modifier == modifier || modifier == DRM_FORMAT_MOD_INVALID)
+    }) {
+        return Err(ParamError::InvalidFormat);
+    }
+
+    // Vendor modifiers may add auxiliary planes, such as compression metadata
+    if let Some(expected) = expected_planes(format) {
+        let explicit = modifier == DRM_FORMAT_MOD_LINEAR || modifier == DRM_FORMAT_MOD_INVALID;
+        if planes.len() < expected || (explicit && planes.len() != expected) {
+            return Err(ParamError::Incomplete);
+        }
+    }
+
+    for plane in planes {
+        let offset = u64::from(plane.offset);
+        let stride = u64::from(plane.stride);
+
+        if stride == 0 {
+            return Err(ParamError::OutOfBounds);
+        }
+
+        // Only the first plane is known to span the full height
+        let end = if plane.plane_idx == 0 {
+            offset + stride * height as u64
+        } else {
+            offset
+        };
+
+        // Offsets and strides are passed to EGL as 32 bit attributes
+        if offset + stride > u64::from(u32::MAX) || end > u64::from(u32::MAX) {
+            return Err(ParamError::OutOfBounds);
+        }
+
+        if let Some(size) = plane.size {
+            if end > size || offset >= size {
+                return Err(ParamError::OutOfBounds);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init_dmabuf_global(
+    display: &mut Display,
+    formats: Vec<DmabufFormat>,
+    compositor: FlutterCompositorWeakRef,
+) -> Global<ZwpLinuxDmabufV1> {
+    let formats = Rc::new(formats);
+
+    display.create_global(
+        3,
+        move |new_dmabuf: NewResource<ZwpLinuxDmabufV1>, version| {
+            let dmabuf_formats = formats.clone();
+            let dmabuf_compositor = compositor.clone();
+            let dmabuf = new_dmabuf.implement_closure(
+                move |request, _| match request {
+                    zwp_linux_dmabuf_v1::Request::CreateParams { params_id } => {
+                        implement_params(
+                            params_id,
+                            dmabuf_formats.clone(),
+                            dmabuf_compositor.clone(),
+                        );
+                    }
+                    zwp_linux_dmabuf_v1::Request::Destroy => {
+                        // Our destructors already handle it
+                    }
+                    _ => unreachable!(),
+                },
+                None::<fn(_)>,
+                (),
+            );
+
+            if version >= 3 {
+                for f in formats.iter() {
+                    dmabuf.modifier(f.format, (f.modifier >> 32) as u32, f.modifier as u32);
+                }
+            } else {
+                let mut sent: Vec<u32> = vec![];
+                for f in formats.iter() {
+                    if !sent.contains(&f.format) {
+                        dmabuf.format(f.format);
+                        sent.push(f.format);
+                    }
+                }
+            }
+        },
+    )
+}
+
+fn implement_params(
+    new_params: NewResource<ZwpLinuxBufferParamsV1>,
+    formats: Rc<Vec<DmabufFormat>>,
+    compositor: FlutterCompositorWeakRef,
+) {
+    let state = RefCell::new(BufferParams::default());
+
+    new_params.implement_closure(
+        move |request, params| match request {
+            zwp_linux_buffer_params_v1::Request::Add {
+                fd,
+                plane_idx,
+                offset,
+                stride,
+                modifier_hi,
+                modifier_lo,
+            } => {
+                let modifier = u64::from(modifier_hi) << 32 | u64::from(modifier_lo);
+                let plane = Plane::new(fd, plane_idx, offset, stride, modifier);
+                if let Err(err) = state.borrow_mut().add(plane) {
+                    params.as_ref().post_error(err.code(), err.to_string());
+                }
+            }
+            zwp_linux_buffer_params_v1::Request::Create {
+                width,
+                height,
+                format,
+                flags,
+            } => {
+                let dmabuf = match state
+                    .borrow_mut()
+                    .build(width, height, format, flags, &formats)
+                {
+                    Ok(dmabuf) => dmabuf,
+                    Err(err) => {
+                        params.as_ref().post_error(err.code(), err.to_string());
+                        return;
+                    }
+                };
+
+                if !test_import(&dmabuf, &compositor) {
+                    params.failed();
+                    return;
+                }
+
+                let new_buffer = params
+                    .as_ref()
+                    .client()
+                    .and_then(|client| client.create_resource::<wl_buffer::WlBuffer>(1));
+                match new_buffer {
+                    Some(new_buffer) => params.created(&implement_buffer(new_buffer, dmabuf)),
+                    None => params.failed(),
+                }
+            }
+            zwp_linux_buffer_params_v1::Request::CreateImmed {
+                buffer_id,
+                width,
+                height,
+                format,
+                flags,
+            } => {
+                let dmabuf = match state
+                    .borrow_mut()
+                    .build(width, height, format, flags, &formats)
+                {
+                    Ok(dmabuf) => dmabuf,
+                    Err(err) => {
+                        params.as_ref().post_error(err.code(), err.to_string());
+                        return;
+                    }
+                };
+
+                if !test_import(&dmabuf, &compositor) {
+                    params.as_ref().post_error(
+                        zwp_linux_buffer_params_v1::Error::InvalidWlBuffer as u32,
+                        "failed to import dmabuf".into(),
+                    );
+                    return;
+                }
+
+                implement_buffer(buffer_id, dmabuf);
+            }
+            zwp_linux_buffer_params_v1::Request::Destroy => {
+                // Our destructors already handle it
+            }
+            _ => unreachable!(),
+        },
+        None::<fn(_)>,
+        (),
+    );
+}
+
+/// Checks that EGL accepts the buffer before handing it to the client.
+fn test_import(dmabuf: &Dmabuf, compositor: &FlutterCompositorWeakRef) -> bool {
+    let compositor_ref = match compositor.upgrade() {
+        Some(compositor_ref) => compositor_ref,
+        None => return false,
+    };
+    let compositor = compositor_ref.get();
+
+    let imported = compositor
+        .backend
+        .with_upload_context(|_| {
+            unsafe {
+                WrappedDisplay::new()
+                    .ok()
+                    .and_then(|display| display.create_dmabuf_image(dmabuf))
+            }
+            .is_some()
+        })
+        .unwrap_or(false);
+
+    if !imported {
+        warn!("Failed to import dmabuf {:?}", dmabuf);
+    }
+    imported
+}
+
+fn implement_buffer(
+    new_buffer: NewResource<wl_buffer::WlBuffer>,
+    dmabuf: Dmabuf,
+) -> wl_buffer::WlBuffer {
+    debug!(
+        "Created dmabuf {}x{} format={:#x} planes={}",
+        dmabuf.width,
+        dmabuf.height,
+        dmabuf.format,
+        dmabuf.planes.len()
+    );
+
+    new_buffer.implement_closure(
+        |request, _| match request {
+            wl_buffer::Request::Destroy => {
+                // Our destructors already handle it
+            }
+            _ => unreachable!(),
+        },
+        None::<fn(_)>,
+        dmabuf,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XRGB8888: u32 = fourcc(b'X', b'R', b'2', b'4');
+    const NV12: u32 = fourcc(b'N', b'V', b'1', b'2');
+
+    const FORMATS: [DmabufFormat; 2] = [
+        DmabufFormat {
+            format: XRGB8888,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+        },
+        DmabufFormat {
+            format: NV12,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+        },
+    ];
+
+    /// A plane without a file, so dropping it closes nothing.
+    fn plane(plane_idx: u32, offset: u32, stride: u32, size: Option<u64>) -> Plane {
+        Plane {
+            fd: -1,
+            plane_idx,
+            offset,
+            stride,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+            size,
+        }
+    }
+
+    #[test]
+    fn valid_planes() {
+        let planes = [plane(0, 0, 256, Some(256 * 64))];
+        assert_eq!(validate(&planes, 64, 64, XRGB8888, &FORMATS), Ok(()));
+
+        let planes = [plane(0, 0, 64, None), plane(1, 64 * 64, 64, None)];
+        assert_eq!(validate(&planes, 64, 64, NV12, &FORMATS), Ok(()));
+    }
+
+    #[test]
+    fn missing_plane() {
+        assert_eq!(
+            validate(&[], 64, 64, XRGB8888, &FORMATS),
+            Err(ParamError::Incomplete)
+        );
+
+        let planes = [plane(0, 0, 64, None)];
+        assert_eq!(
+            validate(&planes, 64, 64, NV12, &FORMATS),
+            Err(ParamError::Incomplete)
+        );
+
+        let planes = [plane(1, 0, 256, None)];
+        assert_eq!(
+            validate(&planes, 64, 64, XRGB8888, &FORMATS),
+            Err(ParamError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn duplicate_plane_index() {
+        let mut params = BufferParams::default();
+        assert_eq!(params.add(plane(0, 0, 256, None)), Ok(()));
+        assert_eq!(
+            params.add(plane(0, 0, 256, None)),
+            Err(ParamError::PlaneSet)
+        );
+        assert_eq!(
+            params.add(plane(MAX_PLANES, 0, 256, None)),
+            Err(ParamError::PlaneIdx)
+        );
+
+        let planes = [plane(0, 0, 64, None), plane(0, 0, 64, None)];
+        assert_eq!(
+            validate(&planes, 64, 64, NV12, &FORMATS),
+            Err(ParamError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn mixed_modifiers() {
+        let mut planes = [plane(0, 0, 64, None), plane(1, 64 * 64, 64, None)];
+        planes[1].modifier = DRM_FORMAT_MOD_INVALID;
+        assert_eq!(
+            validate(&planes, 64, 64, NV12, &FORMATS),
+            Err(ParamError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn unsupported_modifier() {
+        let mut planes = [plane(0, 0, 256, None)];
+        planes[0].modifier = 1;
+        assert_eq!(
+            validate(&planes, 64, 64, XRGB8888, &FORMATS),
+            Err(ParamError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn offset_and_stride_overflow() {
+        let planes = [plane(0, u32::MAX - 16, 256, None)];
+        assert_eq!(
+            validate(&planes, 1, 1, XRGB8888, &FORMATS),
+            Err(ParamError::OutOfBounds)
+        );
+
+        let planes = [plane(0, 0, 1 << 24, None)];
+        assert_eq!(
+            validate(&planes, 64, 512, XRGB8888, &FORMATS),
+            Err(ParamError::OutOfBounds)
+        );
+
+        let planes = [plane(0, 0, 256, Some(256 * 63))];
+        assert_eq!(
+            validate(&planes, 64, 64, XRGB8888, &FORMATS),
+            Err(ParamError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn zero_size() {
+        let planes = [plane(0, 0, 256, None)];
+        assert_eq!(
+            validate(&planes, 0, 64, XRGB8888, &FORMATS),
+            Err(ParamError::InvalidDimensions)
+        );
+        assert_eq!(
+            validate(&planes, 64, 0, XRGB8888, &FORMATS),
+            Err(ParamError::InvalidDimensions)
+        );
+
+        let planes = [plane(0, 0, 0, None)];
+        assert_eq!(
+            validate(&planes, 64, 64, XRGB8888, &FORMATS),
+            Err(ParamError::OutOfBounds)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use wayland_server::protocol::wl_surface;
+use wayland_server::{Display, Global, NewResource};
+
+use crate::protocols::generated::fractional_scale_v1::{
+    wp_fractional_scale_manager_v1, wp_fractional_scale_v1,
+};
+use crate::shell::MyCompositorToken;
+use crate::FlutterCompositorWeakRef;
+
+use wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1;
+use wp_fractional_scale_v1::WpFractionalScaleV1;
+
+/// Denominator of the scale sent in `preferred_scale` events.
+const SCALE_DENOMINATOR: f64 = 120.0;
+
+/// The fractional scale object of a surface, kept in the user data of the surface.
+#[derive(Default)]
+pub struct FractionalScaleState {
+    object: Option<WpFractionalScaleV1>,
+}
+
+/// The fractional scale objects of all surfaces, told when the output scale changes.
+#[derive(Clone, Default)]
+pub(crate) struct FractionalScales {
+    objects: Rc<RefCell<Vec<WpFractionalScaleV1>>>,
+    scale: Rc<Cell<Option<f64>>>,
+}
+
+impl FractionalScales {
+    /// Sends the scale to every object if it changed since the last call.
+    pub fn update(&self, scale: f64) {
+        if self.scale.replace(Some(scale)) == Some(scale) {
+            return;
+        }
+
+        let mut objects = self.objects.borrow_mut();
+        objects.retain(|object| object.as_ref().is_alive());
+        for object in objects.iter() {
+            object.preferred_scale(preferred_scale(scale));
+        }
+    }
+}
+
+fn preferred_scale(scale: f64) -> u32 {
+    (scale * SCALE_DENOMINATOR).round() as u32
+}
+
+pub fn init_fractional_scale_global(
+    display: &mut Display,
+    token: MyCompositorToken,
+    scales: FractionalScales,
+    compositor: FlutterCompositorWeakRef,
+) -> Global<WpFractionalScaleManagerV1> {
+    display.create_global(
+        1,
+        move |new_manager: NewResource<WpFractionalScaleManagerV1>, _| {
+            let scales = scales.clone();
+            let compositor = compositor.clone();
+            new_manager.implement_closure(
+                move |request, manager| match request {
+                    wp_fractional_scale_manager_v1::Request::GetFractionalScale { id, surface } => {
+                        get_fractional_scale(&manager, id, &surface, token, &scales, &compositor);
+                    }
+                    wp_fractional_scale_manager_v1::Request::Destroy => {
+                        // Our destructors already handle it
+                    }
+                    _ => unreachable!(),
+                },
+                None::<fn(_)>,
+                (),
+            );
+        },
+    )
+}
+
+fn get_fractional_scale(
+    manager: &WpFractionalScaleManagerV1,
+    new_object: NewResource<WpFractionalScaleV1>,
+    surface: &wl_surface::WlSurface,
+    token: MyCompositorToken,
+    scales: &FractionalScales,
+    compositor: &FlutterCompositorWeakRef,
+) {
+    let exists = token.with_surface_data(surface, |attributes| {
+        attributes
+            .user_data
+            .insert_if_missing(FractionalScaleState::default);
+        let state = attributes.user_data.get::<FractionalScaleState>().unwrap();
+        state
+            .object
+            .as_ref()
+            .map_or(false, |object| object.as_ref().is_alive())
+    });
+
+    if exists {
+        manager.as_ref().post_error(
+            wp_fractional_scale_manager_v1::Error::FractionalScaleExists as u32,
+            "surface already has a fractional scale object".into(),
+        );
+        return;
+    }
+
+    let object = new_object.implement_closure(|_, _| {}, None::<fn(_)>, ());
+
+    let scale = match compositor.upgrade() {
+        Some(compositor_ref) => compositor_ref.get().backend.get_scale_factor(),
+        None => 1.0,
+    };
+    object.preferred_scale(preferred_scale(scale));
+    {
+        let mut objects = scales.objects.borrow_mut();
+        objects.retain(|object| object.as_ref().is_alive());
+        objects.push(object.clone());
+    }
+
+    token.with_surface_data(surface, |attributes| {
+        if let Some(state) = attributes.user_data.get_mut::<FractionalScaleState>() {
+            state.object = Some(object);
+        }
+    });
+}
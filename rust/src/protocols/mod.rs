@@ -0,0 +1,66 @@
+pub(crate) mod dmabuf;
+pub(crate) mod fractional_scale;
+pub(crate) mod output_power;
+pub(crate) mod presentation;
+pub(crate) mod screencopy;
+pub(crate) mod surface_damage;
+pub(crate) mod viewporter;
+pub(crate) mod xdg_output;
+
+/// Server code generated by the build script, for protocols not in wayland-protocols yet.
+#[allow(
+    dead_code,
+    non_camel_case_types,
+    unused_unsafe,
+    unused_variables,
+    non_upper_case_globals,
+    non_snake_case,
+    unused_imports,
+    clippy::all
+)]
+pub(crate) mod generated {
+    pub mod fractional_scale_v1 {
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::smallvec;
+        pub(crate) use wayland_commons::wire::{Argument, ArgumentType, Message, MessageDesc};
+        pub(crate) use wayland_commons::{AnonymousObject, Interface, MessageGroup};
+        pub(crate) use wayland_server::protocol::wl_surface;
+        pub(crate) use wayland_server::{NewResource, Resource};
+        pub(crate) use wayland_sys as sys;
+
+        include!(concat!(
+            env!("OUT_DIR"),
+            "/fractional-scale-v1_server_api.rs"
+        ));
+    }
+
+    pub mod wlr_output_power_management_unstable_v1 {
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::smallvec;
+        pub(crate) use wayland_commons::wire::{Argument, ArgumentType, Message, MessageDesc};
+        pub(crate) use wayland_commons::{AnonymousObject, Interface, MessageGroup};
+        pub(crate) use wayland_server::protocol::wl_output;
+        pub(crate) use wayland_server::{NewResource, Resource};
+        pub(crate) use wayland_sys as sys;
+
+        include!(concat!(
+            env!("OUT_DIR"),
+            "/wlr-output-power-management-unstable-v1_server_api.rs"
+        ));
+    }
+
+    pub mod wlr_screencopy_unstable_v1 {
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::smallvec;
+        pub(crate) use wayland_commons::wire::{Argument, ArgumentType, Message, MessageDesc};
+        pub(crate) use wayland_commons::{AnonymousObject, Interface, MessageGroup};
+        pub(crate) use wayland_server::protocol::{wl_buffer, wl_output, wl_shm};
+        pub(crate) use wayland_server::{NewResource, Resource};
+        pub(crate) use wayland_sys as sys;
+
+        include!(concat!(
+            env!("OUT_DIR"),
+            "/wlr-screencopy-unstable-v1_server_api.rs"
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use smithay::wayland::output::Output;
+use wayland_server::protocol::wl_output::WlOutput;
+use wayland_server::{Display, Global, NewResource};
+
+use crate::backends::layout::OutputInfo;
+use crate::protocols::generated::wlr_output_power_management_unstable_v1::{
+    zwlr_output_power_manager_v1, zwlr_output_power_v1,
+};
+use crate::FlutterCompositorWeakRef;
+
+use zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1;
+use zwlr_output_power_v1::{Mode, ZwlrOutputPowerV1};
+
+/// The output power objects of all clients, told when their monitor is turned on or off.
+#[derive(Clone, Default)]
+pub(crate) struct OutputPowers {
+    objects: Rc<RefCell<Vec<(ZwlrOutputPowerV1, WlOutput)>>>,
+}
+
+impl OutputPowers {
+    /// Sends the power mode of a monitor to the objects created for it.
+    pub fn update(&self, output: &Output, info: &OutputInfo) {
+        let mut objects = self.objects.borrow_mut();
+        objects.retain(|(object, _)| object.as_ref().is_alive());
+        for (object, wl_output) in objects.iter() {
+            if output.owns(wl_output) {
+                object.mode(mode(info));
+            }
+        }
+    }
+
+    /// Tells the objects created for a removed monitor that it can no longer be controlled.
+    pub fn remove(&self, output: &Output) {
+        self.objects.borrow_mut().retain(|(object, wl_output)| {
+            if !object.as_ref().is_alive() {
+                return false;
+            }
+            if output.owns(wl_output) {
+                object.failed();
+                return false;
+            }
+            true
+        });
+    }
+}
+
+pub fn init_output_power_global(
+    display: &mut Display,
+    powers: OutputPowers,
+    compositor: FlutterCompositorWeakRef,
+) -> Global<ZwlrOutputPowerManagerV1> {
+    display.create_global(
+        1,
+        move |new_manager: NewResource<ZwlrOutputPowerManagerV1>, _| {
+            let powers = powers.clone();
+            let compositor = compositor.clone();
+            new_manager.implement_closure(
+                move |request, _manager| match request {
+                    zwlr_output_power_manager_v1::Request::GetOutputPower { id, output } => {
+                        get_output_power(id, output, &powers, &compositor);
+                    }
+                    zwlr_output_power_manager_v1::Request::Destroy => {
+                        // Our destructors already handle it
+                    }
+                    _ => unreachable!(),
+                },
+                None::<fn(_)>,
+                (),
+            );
+        },
+    )
+}
+
+fn get_output_power(
+    new_object: NewResource<ZwlrOutputPowerV1>,
+    output: WlOutput,
+    powers: &OutputPowers,
+    compositor: &FlutterCompositorWeakRef,
+) {
+    let request_output = output.clone();
+    let request_compositor = compositor.clone();
+    let object = new_object.implement_closure(
+        move |request, object: ZwlrOutputPowerV1| match request {
+            zwlr_output_power_v1::Request::SetMode { mode } => {
+                let compositor_ref = match request_compositor.upgrade() {
+                    Some(compositor_ref) => compositor_ref,
+                    None => return,
+                };
+                let compositor = compositor_ref.get();
+                let backend = &compositor.backend;
+
+                let info = match backend.output_info(&request_output) {
+                    Some(info) => info,
+                    None => {
+                        object.failed();
+                        return;
+                    }
+                };
+                // Every object of the monitor gets the new mode once it is applied
+                if !backend.set_output_power(&info.name, mode == Mode::On) {
+                    object.failed();
+                }
+            }
+            zwlr_output_power_v1::Request::Destroy => {
+                // Our destructors already handle it
+            }
+            _ => unreachable!(),
+        },
+        None::<fn(_)>,
+        (),
+    );
+
+    let info = compositor
+        .upgrade()
+        .and_then(|compositor_ref| compositor_ref.get().backend.output_info(&output));
+    match info {
+        Some(info) => object.mode(mode(&info)),
+        // Outputs removed in the meantime cannot be controlled
+        None => {
+            object.failed();
+            return;
+        }
+    }
+
+    powers.objects.borrow_mut().push((object, output));
+}
+
+fn mode(info: &OutputInfo) -> Mode {
+    if info.powered {
+        Mode::On
+    } else {
+        Mode::Off
+    }
+}
@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use smithay::wayland::output::Output;
+use wayland_protocols::presentation_time::server::{wp_presentation, wp_presentation_feedback};
+use wayland_server::protocol::{wl_callback, wl_output, wl_surface};
+use wayland_server::{Client, Display, Global, NewResource, Resource};
+use wayland_sys::server::{wl_client, wl_resource};
+
+use crate::shell::MyCompositorToken;
+
+use wp_presentation::WpPresentation;
+use wp_presentation_feedback::{Kind, WpPresentationFeedback};
+
+/// Frame callbacks are sent anyway once they have waited this long for a presented frame, so that
+/// clients hidden by flutter keep making progress.
+const MAX_CALLBACK_DELAY: Duration = Duration::from_secs(1);
+
+/// A frame reaching the screen.
+#[derive(Clone, Debug)]
+pub struct PresentationEvent {
+    /// Name of the output the frame was flipped to.
+    pub output: String,
+    /// Time the frame was presented, in nanoseconds on the monotonic clock.
+    pub time: u64,
+    /// Duration of a refresh cycle in nanoseconds, or zero if unknown.
+    pub refresh: u32,
+    /// Vblank counter of the output, or zero if unknown.
+    pub sequence: u64,
+    pub flags: Kind,
+}
+
+/// Feedback requested since the last commit, kept in the user data of the surface.
+#[derive(Default)]
+pub struct PendingFeedback(Vec<WpPresentationFeedback>);
+
+impl PendingFeedback {
+    pub fn take(&mut self) -> Vec<WpPresentationFeedback> {
+        std::mem::replace(&mut self.0, vec![])
+    }
+}
+
+/// Frame callbacks and presentation feedback waiting for the next presented frame.
+#[derive(Clone, Default)]
+pub(crate) struct PresentationQueue {
+    inner: Rc<RefCell<QueueInner>>,
+}
+
+#[derive(Default)]
+struct QueueInner {
+    callbacks: Vec<wl_callback::WlCallback>,
+    oldest_callback: Option<Instant>,
+    /// Feedback of commits not yet sampled by flutter.
+    feedback: Vec<(wl_surface::WlSurface, WpPresentationFeedback)>,
+    /// Feedback of commits sampled into frames waiting for their flip.
+    sampled: Vec<(wl_surface::WlSurface, WpPresentationFeedback)>,
+}
+
+impl PresentationQueue {
+    pub fn frame_callback(&self, callback: wl_callback::WlCallback) {
+        let mut inner = self.inner.borrow_mut();
+        inner.callbacks.push(callback);
+        inner.oldest_callback.get_or_insert_with(Instant::now);
+    }
+
+    /// Queues the feedback of a commit, discarding the feedback of earlier contents of the
+    /// surface that never made it to the screen.
+    pub fn commit(&self, surface: &wl_surface::WlSurface, feedback: Vec<WpPresentationFeedback>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.feedback.retain(|(other, old)| {
+            if other.as_ref().equals(surface.as_ref()) {
+                old.discarded();
+                false
+            } else {
+                true
+            }
+        });
+
+        for f in feedback {
+            inner.feedback.push((surface.clone(), f));
+        }
+    }
+
+    /// Marks the contents committed so far as sampled into the frame flutter just submitted,
+    /// discarding the feedback of earlier frames showing the same surfaces that never flipped.
+    pub fn frame_submitted(&self) {
+        let mut inner = self.inner.borrow_mut();
+        let QueueInner {
+            feedback, sampled, ..
+        } = &mut *inner;
+
+        sampled.retain(|(other, old)| {
+            let superseded = feedback
+                .iter()
+                .any(|(surface, _)| surface.as_ref().equals(other.as_ref()));
+            if superseded {
+                old.discarded();
+            }
+            !superseded
+        });
+        sampled.append(feedback);
+    }
+
+    /// Notifies clients that the contents sampled into the flipped frames reached the output.
+    pub fn presented(&self, event: PresentationEvent, output: Option<&Output>) {
+        let mut inner = self.inner.borrow_mut();
+
+        let sec = event.time / 1_000_000_000;
+        let nsec = (event.time % 1_000_000_000) as u32;
+        for (surface, feedback) in inner.sampled.drain(..) {
+            if surface.as_ref().is_alive() {
+                if let (Some(output), Some(client)) = (output, surface.as_ref().client()) {
+                    for wl_output in client_outputs(&client) {
+                        if output.owns(&wl_output) {
+                            feedback.sync_output(&wl_output);
+                        }
+                    }
+                }
+                feedback.presented(
+                    (sec >> 32) as u32,
+                    sec as u32,
+                    nsec,
+                    event.refresh,
+                    (event.sequence >> 32) as u32,
+                    event.sequence as u32,
+                    event.flags,
+                );
+            } else {
+                feedback.discarded();
+            }
+        }
+
+        let time = (event.time / 1_000_000) as u32;
+        for callback in inner.callbacks.drain(..) {
+            callback.done(time);
+        }
+        inner.oldest_callback = None;
+    }
+
+    /// Sends frame callbacks that have waited too long for a presented frame.
+    pub fn flush_stale(&self, now: u64) {
+        let mut inner = self.inner.borrow_mut();
+        let stale = match inner.oldest_callback {
+            Some(oldest) => oldest.elapsed() >= MAX_CALLBACK_DELAY,
+            None => false,
+        };
+
+        if stale {
+            let time = (now / 1_000_000) as u32;
+            for callback in inner.callbacks.drain(..) {
+                callback.done(time);
+            }
+            inner.oldest_callback = None;
+        }
+    }
+}
+
+const WL_ITERATOR_CONTINUE: c_int = 1;
+
+// Not bound by wayland-sys
+#[link(name = "wayland-server")]
+extern "C" {
+    fn wl_client_for_each_resource(
+        client: *mut wl_client,
+        iterator: unsafe extern "C" fn(*mut wl_resource, *mut c_void) -> c_int,
+        user_data: *mut c_void,
+    );
+    fn wl_resource_get_class(resource: *mut wl_resource) -> *const c_char;
+}
+
+/// Returns the `wl_output` objects a client bound, which feedback has to refer to.
+fn client_outputs(client: &Client) -> Vec<wl_output::WlOutput> {
+    unsafe extern "C" fn collect(resource: *mut wl_resource, user_data: *mut c_void) -> c_int {
+        let class = wl_resource_get_class(resource);
+        if !class.is_null() && CStr::from_ptr(class).to_bytes() == b"wl_output" {
+            let outputs = &mut *(user_data as *mut Vec<wl_output::WlOutput>);
+            outputs.push(Resource::<wl_output::WlOutput>::from_c_ptr(resource).into());
+        }
+        WL_ITERATOR_CONTINUE
+    }
+
+    let mut outputs = vec![];
+    unsafe {
+        wl_client_for_each_resource(
+            client.c_ptr(),
+            collect,
+            &mut outputs as *mut Vec<wl_output::WlOutput> as *mut c_void,
+        );
+    }
+    outputs
+}
+
+pub fn init_presentation_global(
+    display: &mut Display,
+    token: MyCompositorToken,
+) -> Global<WpPresentation> {
+    display.create_global(
+        1,
+        move |new_presentation: NewResource<WpPresentation>, _| {
+            let presentation = new_presentation.implement_closure(
+                move |request, _| match request {
+                    wp_presentation::Request::Feedback { surface, callback } => {
+                        let feedback = callback.implement_closure(|_, _| {}, None::<fn(_)>, ());
+                        token.with_surface_data(&surface, |attributes| {
+                            attributes
+                                .user_data
+                                .insert_if_missing(PendingFeedback::default);
+                            let pending =
+                                attributes.user_data.get_mut::<PendingFeedback>().unwrap();
+                            pending.0.push(feedback);
+                        });
+                    }
+                    wp_presentation::Request::Destroy => {
+                        // Our destructors already handle it
+                    }
+                    _ => unreachable!(),
+                },
+                None::<fn(_)>,
+                (),
+            );
+
+            presentation.clock_id(libc::CLOCK_MONOTONIC as u32);
+        },
+    )
+}
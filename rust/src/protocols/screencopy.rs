@@ -0,0 +1,756 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fs;
+use std::mem;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Once;
+
+use log::{debug, warn};
+use smithay::utils::Rectangle;
+use smithay::wayland::shm::{with_buffer_contents as shm_buffer_contents, BufferData};
+use wayland_server::protocol::{wl_buffer, wl_output, wl_shm};
+use wayland_server::{Client, Display, Global, NewResource};
+
+use crate::backends::CompositorBackend;
+use crate::capture::Frame;
+use crate::protocols::generated::wlr_screencopy_unstable_v1::{
+    zwlr_screencopy_frame_v1, zwlr_screencopy_manager_v1,
+};
+use crate::FlutterCompositorWeakRef;
+
+use zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1;
+use zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+
+/// Decides which clients may read back the contents of the output.
+#[derive(Clone, Debug)]
+pub enum ScreencopyPolicy {
+    AllowAll,
+    DenyAll,
+    /// Only clients running one of the given executables may capture.
+    Executables(Vec<PathBuf>),
+}
+
+impl ScreencopyPolicy {
+    fn allows(&self, client: Option<Client>) -> bool {
+        match self {
+            ScreencopyPolicy::AllowAll => true,
+            ScreencopyPolicy::DenyAll => false,
+            ScreencopyPolicy::Executables(allowed) => {
+                let exe = client
+                    .map(|client| client_pid(&client))
+                    .and_then(|pid| fs::read_link(format!("/proc/{}/exe", pid)).ok());
+                match exe {
+                    Some(exe) => allowed.contains(&exe),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+fn client_pid(client: &Client) -> i32 {
+    use wayland_sys::ffi_dispatch;
+    use wayland_sys::server::*;
+
+    let (mut pid, mut uid, mut gid) = (0, 0, 0);
+    unsafe {
+        ffi_dispatch!(
+            WAYLAND_SERVER_HANDLE,
+            wl_client_get_credentials,
+            client.c_ptr(),
+            &mut pid,
+            &mut uid,
+            &mut gid
+        );
+    }
+    pid
+}
+
+/// Copies waiting for the next presented frame, completed from the main loop.
+#[derive(Clone, Default)]
+pub(crate) struct Screencopy {
+    copies: Rc<RefCell<Vec<PendingCopy>>>,
+}
+
+struct PendingCopy {
+    frame: ZwlrScreencopyFrameV1,
+    buffer: wl_buffer::WlBuffer,
+    region: Region,
+    overlay_cursor: bool,
+    with_damage: bool,
+    last: LastFrame,
+    capture: Receiver<Frame>,
+}
+
+/// The last region and frame copied by a manager from an output, used to compute damage.
+type LastFrame = Rc<RefCell<Option<(Region, Frame)>>>;
+
+/// Region of the output to copy, in framebuffer pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Screencopy {
+    /// Writes the captured frames into the client buffers.
+    pub fn poll(&self, backend: &CompositorBackend) {
+        let mut copies = self.copies.borrow_mut();
+        if copies.is_empty() {
+            return;
+        }
+
+        let mut remaining = vec![];
+        for copy in copies.drain(..) {
+            if !copy.frame.as_ref().is_alive() {
+                continue;
+            }
+
+            match copy.capture.try_recv() {
+                Ok(frame) => {
+                    if let Some(copy) = finish_copy(copy, frame, backend) {
+                        remaining.push(copy);
+                    }
+                }
+                Err(TryRecvError::Empty) => remaining.push(copy),
+                Err(TryRecvError::Disconnected) => copy.frame.failed(),
+            }
+        }
+        *copies = remaining;
+    }
+}
+
+/// Completes a copy, returning it when it has to wait for damage.
+fn finish_copy(
+    mut copy: PendingCopy,
+    frame: Frame,
+    backend: &CompositorBackend,
+) -> Option<PendingCopy> {
+    let region = copy.region;
+    if region.x + region.width > frame.width || region.y + region.height > frame.height {
+        // the output was resized since the frame was created
+        copy.frame.failed();
+        return None;
+    }
+
+    let mut image = frame.crop(region.x, region.y, region.width, region.height);
+    if copy.overlay_cursor {
+        if let Some(cursor) = backend.cursor.overlay() {
+            let position = (
+                cursor.position.0 - region.x as i32,
+                cursor.position.1 - region.y as i32,
+            );
+            image.draw_over(&cursor.image, position);
+        }
+    }
+
+    let damage = {
+        let last = copy.last.borrow();
+        let last = match last.as_ref() {
+            Some((last_region, last)) if *last_region == region => Some(last),
+            _ => None,
+        };
+        damage_between(last, &image)
+    };
+    if copy.with_damage && damage.is_none() {
+        copy.capture = backend.captures.request();
+        return Some(copy);
+    }
+
+    if !write_shm_buffer(&copy.buffer, &image) {
+        copy.frame.failed();
+        return None;
+    }
+
+    copy.frame.flags(zwlr_screencopy_frame_v1::Flags::empty());
+    if copy.with_damage {
+        let damage = damage.unwrap();
+        copy.frame
+            .damage(damage.x, damage.y, damage.width, damage.height);
+    }
+
+    let (sec, nsec) = monotonic_time();
+    copy.frame.ready((sec >> 32) as u32, sec as u32, nsec);
+
+    copy.last.replace(Some((region, image)));
+    None
+}
+
+/// Returns the bounding box of the pixels that differ between two frames.
+fn damage_between(last: Option<&Frame>, current: &Frame) -> Option<Region> {
+    let full = Region {
+        x: 0,
+        y: 0,
+        width: current.width,
+        height: current.height,
+    };
+
+    let last = match last {
+        Some(last) if last.width == current.width && last.height == current.height => last,
+        _ => return Some(full),
+    };
+
+    let stride = current.width as usize * 4;
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (y, (old, new)) in last
+        .data
+        .chunks(stride)
+        .zip(current.data.chunks(stride))
+        .enumerate()
+    {
+        if old == new {
+            continue;
+        }
+
+        let changed = old
+            .chunks(4)
+            .zip(new.chunks(4))
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(x, _)| x as u32);
+        let x1 = changed.clone().min().unwrap();
+        let x2 = changed.max().unwrap() + 1;
+        let y = y as u32;
+
+        bounds = Some(match bounds {
+            Some((bx1, by1, bx2, _)) => (bx1.min(x1), by1, bx2.max(x2), y + 1),
+            None => (x1, y, x2, y + 1),
+        });
+    }
+
+    bounds.map(|(x1, y1, x2, y2)| Region {
+        x: x1,
+        y: y1,
+        width: x2 - x1,
+        height: y2 - y1,
+    })
+}
+
+fn monotonic_time() -> (u64, u32) {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    (time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+fn buffer_matches(data: &BufferData, region: Region) -> bool {
+    (data.format == wl_shm::Format::Argb8888 || data.format == wl_shm::Format::Xrgb8888)
+        && data.width as u32 == region.width
+        && data.height as u32 == region.height
+        && data.stride as u32 >= region.width * 4
+}
+
+fn write_shm_buffer(buffer: &wl_buffer::WlBuffer, image: &Frame) -> bool {
+    let region = Region {
+        x: 0,
+        y: 0,
+        width: image.width,
+        height: image.height,
+    };
+
+    shm_buffer_contents(buffer, |pool, data| {
+        if !buffer_matches(&data, region) {
+            return false;
+        }
+
+        // Smithay maps shm pools read-only, so write through a mapping of our own
+        match unsafe { PoolMapping::new(pool) } {
+            Some(mut mapping) => mapping.write(|pool| write_pool(pool, &data, image)),
+            None => {
+                warn!("Failed to map shm pool writable, the client may have sent a read-only fd");
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// A writable mapping of the pages of an shm pool, unmapped when dropped.
+struct PoolMapping {
+    ptr: *mut c_void,
+    len: usize,
+    /// Offset of the pool from the start of the first page.
+    offset: usize,
+}
+
+impl PoolMapping {
+    /// Maps the pages of the pool a second time and makes the new mapping writable, leaving the
+    /// mapping of smithay untouched.
+    ///
+    /// The pool must be a shared mapping, which shm pools always are.
+    unsafe fn new(pool: &[u8]) -> Option<Self> {
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let start = pool.as_ptr() as usize & !(page - 1);
+        let offset = pool.as_ptr() as usize - start;
+        let len = offset + pool.len();
+
+        // An old size of zero duplicates a shared mapping instead of moving it
+        let ptr = libc::mremap(start as *mut c_void, 0, len, libc::MREMAP_MAYMOVE);
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        let mapping = Self { ptr, len, offset };
+
+        if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+            return None;
+        }
+        Some(mapping)
+    }
+
+    /// Runs `f` on the pool, returning false if the pool turned out to be smaller than announced
+    /// by the client.
+    ///
+    /// Smithay only guards its own mapping against SIGBUS, so accesses to this one are guarded
+    /// the same way: the pages are replaced by anonymous memory on a fault, and the write fails.
+    fn write<F: FnOnce(&mut [u8]) -> bool>(&mut self, f: F) -> bool {
+        place_sigbus_handler();
+
+        let pool = unsafe {
+            slice::from_raw_parts_mut(
+                (self.ptr as *mut u8).add(self.offset),
+                self.len - self.offset,
+            )
+        };
+        GUARDED_LEN.store(self.len, Ordering::SeqCst);
+        GUARDED_START.store(self.ptr as usize, Ordering::SeqCst);
+        let written = f(pool);
+        GUARDED_START.store(0, Ordering::SeqCst);
+        let faulted = FAULTED.swap(false, Ordering::SeqCst);
+
+        if faulted {
+            warn!("The client sent an shm pool smaller than announced");
+        }
+        written && !faulted
+    }
+}
+
+impl Drop for PoolMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// The mapping being written, if any, and whether the write faulted.
+static GUARDED_START: AtomicUsize = AtomicUsize::new(0);
+static GUARDED_LEN: AtomicUsize = AtomicUsize::new(0);
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+static SIGBUS_INIT: Once = Once::new();
+static mut PREVIOUS_SIGBUS_HANDLER: Option<libc::sigaction> = None;
+
+/// Installs the SIGBUS handler of our mappings in front of the existing one.
+///
+/// Only called while smithay accesses a pool, after smithay placed its own handler, which
+/// faults outside of our mappings are forwarded to.
+fn place_sigbus_handler() {
+    SIGBUS_INIT.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = sigbus_handler;
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = mem::zeroed();
+        if libc::sigaction(libc::SIGBUS, &action, &mut previous) == 0 {
+            PREVIOUS_SIGBUS_HANDLER = Some(previous);
+        }
+    });
+}
+
+extern "C" fn sigbus_handler(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let address = unsafe { (*info).si_addr() } as usize;
+    let start = GUARDED_START.load(Ordering::SeqCst);
+    let len = GUARDED_LEN.load(Ordering::SeqCst);
+    let guarded = start != 0 && address >= start && address < start + len;
+
+    // Let the write continue on memory nobody reads
+    let handled = guarded
+        && unsafe {
+            libc::mmap(
+                start as *mut c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        } != libc::MAP_FAILED;
+
+    if handled {
+        FAULTED.store(true, Ordering::SeqCst);
+    } else {
+        unsafe { forward_sigbus(signum, info, context) };
+    }
+}
+
+unsafe fn forward_sigbus(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let previous = PREVIOUS_SIGBUS_HANDLER;
+    match previous {
+        Some(previous) if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                mem::transmute(previous.sa_sigaction);
+            handler(signum, info, context);
+        }
+        Some(previous)
+            if previous.sa_sigaction != libc::SIG_DFL && previous.sa_sigaction != libc::SIG_IGN =>
+        {
+            let handler: extern "C" fn(c_int) = mem::transmute(previous.sa_sigaction);
+            handler(signum);
+        }
+        _ => {
+            libc::signal(libc::SIGBUS, libc::SIG_DFL);
+            libc::raise(libc::SIGBUS);
+        }
+    }
+}
+
+/// Copies the RGBA image into the pool as ARGB8888.
+fn write_pool(pool: &mut [u8], data: &BufferData, image: &Frame) -> bool {
+    let row = image.width as usize * 4;
+    let height = image.height as usize;
+    if data.offset < 0 || data.stride < 0 || height == 0 {
+        return false;
+    }
+    let offset = data.offset as usize;
+    let stride = data.stride as usize;
+
+    let end = (height - 1)
+        .checked_mul(stride)
+        .and_then(|last| last.checked_add(offset))
+        .and_then(|last| last.checked_add(row));
+    match end {
+        Some(end) if end <= pool.len() => (),
+        _ => return false,
+    }
+
+    for (y, src) in image.data.chunks(row).take(height).enumerate() {
+        let start = offset + y * stride;
+        let dst = &mut pool[start..start + row];
+        for (dst, src) in dst.chunks_mut(4).zip(src.chunks(4)) {
+            dst.copy_from_slice(&[src[2], src[1], src[0], src[3]]);
+        }
+    }
+    true
+}
+
+pub fn init_screencopy_global(
+    display: &mut Display,
+    screencopy: Screencopy,
+    policy: ScreencopyPolicy,
+    compositor: FlutterCompositorWeakRef,
+) -> Global<ZwlrScreencopyManagerV1> {
+    display.create_global(
+        3,
+        move |new_manager: NewResource<ZwlrScreencopyManagerV1>, _| {
+            let screencopy = screencopy.clone();
+            let policy = policy.clone();
+            let compositor = compositor.clone();
+            let mut last = HashMap::new();
+
+            new_manager.implement_closure(
+                move |request, manager| {
+                    let (frame, overlay_cursor, output, region) = match request {
+                        zwlr_screencopy_manager_v1::Request::CaptureOutput {
+                            frame,
+                            overlay_cursor,
+                            output,
+                        } => (frame, overlay_cursor, output, None),
+                        zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+                            frame,
+                            overlay_cursor,
+                            output,
+                            x,
+                            y,
+                            width,
+                            height,
+                        } => (frame, overlay_cursor, output, Some((x, y, width, height))),
+                        zwlr_screencopy_manager_v1::Request::Destroy => return,
+                        _ => unreachable!(),
+                    };
+
+                    let allowed = policy.allows(manager.as_ref().client());
+                    let region = frame_region(&compositor, &output, region);
+                    implement_frame(
+                        frame,
+                        overlay_cursor != 0,
+                        region,
+                        allowed,
+                        screencopy.clone(),
+                        last.entry(output.as_ref().id()).or_default().clone(),
+                        compositor.clone(),
+                    );
+                },
+                None::<fn(_)>,
+                (),
+            );
+        },
+    )
+}
+
+/// Returns the region of the framebuffer to copy for a capture of an output.
+fn frame_region(
+    compositor: &FlutterCompositorWeakRef,
+    output: &wl_output::WlOutput,
+    logical_region: Option<(i32, i32, i32, i32)>,
+) -> Region {
+    // Outputs removed in the meantime have nothing left to copy
+    let empty = Rectangle {
+        x: 0,
+        y: 0,
+        width: 0,
+        height: 0,
+    };
+    let (output_region, scale) = match compositor.upgrade() {
+        Some(compositor_ref) => {
+            let compositor = compositor_ref.get();
+            (
+                compositor
+                    .backend
+                    .output_info(output)
+                    .map_or(empty, |info| info.region),
+                compositor.backend.get_scale_factor(),
+            )
+        }
+        None => (empty, 1.0),
+    };
+    capture_region(logical_region, output_region, scale)
+}
+
+fn implement_frame(
+    new_frame: NewResource<ZwlrScreencopyFrameV1>,
+    overlay_cursor: bool,
+    region: Region,
+    allowed: bool,
+    screencopy: Screencopy,
+    last: LastFrame,
+    compositor: FlutterCompositorWeakRef,
+) {
+    let used = RefCell::new(false);
+    let frame = new_frame.implement_closure(
+        move |request, frame| {
+            let (buffer, with_damage) = match request {
+                zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+                zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+                zwlr_screencopy_frame_v1::Request::Destroy => return,
+                _ => unreachable!(),
+            };
+
+            if used.replace(true) {
+                frame.as_ref().post_error(
+                    zwlr_screencopy_frame_v1::Error::AlreadyUsed as u32,
+                    "frame was already used to copy a buffer".into(),
+                );
+                return;
+            }
+
+            let valid = shm_buffer_contents(&buffer, |_, data| buffer_matches(&data, region))
+                .unwrap_or(false);
+            if !valid {
+                frame.as_ref().post_error(
+                    zwlr_screencopy_frame_v1::Error::InvalidBuffer as u32,
+                    "buffer does not match the advertised parameters".into(),
+                );
+                return;
+            }
+
+            let capture = match compositor.upgrade() {
+                Some(compositor_ref) => compositor_ref.capture_frame(),
+                None => {
+                    frame.failed();
+                    return;
+                }
+            };
+
+            debug!("Screencopy of {:?} requested", region);
+            screencopy.copies.borrow_mut().push(PendingCopy {
+                frame,
+                buffer,
+                region,
+                overlay_cursor,
+                with_damage,
+                last: last.clone(),
+                capture,
+            });
+        },
+        None::<fn(_)>,
+        (),
+    );
+
+    if !allowed || region.width == 0 || region.height == 0 {
+        frame.failed();
+        return;
+    }
+
+    frame.buffer(
+        wl_shm::Format::Argb8888,
+        region.width,
+        region.height,
+        region.width * 4,
+    );
+    if frame.as_ref().version() >= 3 {
+        frame.buffer_done();
+    }
+}
+
+/// Converts a region in logical coordinates of the output into framebuffer pixels, clipped to
+/// the region of the framebuffer shown on the output.
+fn capture_region(
+    logical_region: Option<(i32, i32, i32, i32)>,
+    output: Rectangle,
+    scale: f64,
+) -> Region {
+    let (x, y, w, h) = match logical_region {
+        Some((x, y, w, h)) => (
+            (f64::from(x) * scale).round() as i64,
+            (f64::from(y) * scale).round() as i64,
+            (f64::from(w) * scale).round() as i64,
+            (f64::from(h) * scale).round() as i64,
+        ),
+        None => (0, 0, i64::from(output.width), i64::from(output.height)),
+    };
+
+    let (left, top) = (i64::from(output.x), i64::from(output.y));
+    let right = left + i64::from(output.width);
+    let bottom = top + i64::from(output.height);
+
+    let x1 = (left + x).max(left).min(right).max(0);
+    let y1 = (top + y).max(top).min(bottom).max(0);
+    let x2 = (left + x + w).max(left).min(right).max(0);
+    let y2 = (top + y + h).max(top).min(bottom).max(0);
+
+    Region {
+        x: x1 as u32,
+        y: y1 as u32,
+        width: (x2 - x1).max(0) as u32,
+        height: (y2 - y1).max(0) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32, data: Vec<u8>) -> Frame {
+        Frame {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn fields(region: Region) -> (u32, u32, u32, u32) {
+        (region.x, region.y, region.width, region.height)
+    }
+
+    #[test]
+    fn damage_of_first_or_resized_frame_is_full() {
+        let current = frame(2, 2, vec![0; 16]);
+        let damage = damage_between(None, &current).map(fields);
+        assert_eq!(damage, Some((0, 0, 2, 2)));
+
+        let last = frame(1, 1, vec![0; 4]);
+        let damage = damage_between(Some(&last), &current).map(fields);
+        assert_eq!(damage, Some((0, 0, 2, 2)));
+    }
+
+    #[test]
+    fn damage_of_identical_frames_is_none() {
+        let last = frame(2, 2, vec![7; 16]);
+        let current = frame(2, 2, vec![7; 16]);
+        assert!(damage_between(Some(&last), &current).is_none());
+    }
+
+    #[test]
+    fn damage_bounds_changed_pixels() {
+        let last = frame(4, 3, vec![0; 48]);
+        let mut data = vec![0; 48];
+        // pixel (1, 0) and pixel (2, 2)
+        data[4] = 1;
+        data[2 * 16 + 2 * 4 + 3] = 1;
+        let current = frame(4, 3, data);
+
+        let damage = damage_between(Some(&last), &current).map(fields);
+        assert_eq!(damage, Some((1, 0, 2, 3)));
+    }
+
+    #[test]
+    fn capture_whole_output() {
+        let output = rect(1920, 0, 1280, 1024);
+        let region = capture_region(None, output, 1.0);
+        assert_eq!(fields(region), (1920, 0, 1280, 1024));
+    }
+
+    #[test]
+    fn capture_region_of_scaled_output() {
+        let output = rect(1920, 0, 2560, 1440);
+        let region = capture_region(Some((10, 20, 100, 50)), output, 2.0);
+        assert_eq!(fields(region), (1940, 40, 200, 100));
+    }
+
+    #[test]
+    fn capture_region_is_clipped_to_output() {
+        let output = rect(1920, 0, 1280, 1024);
+        let region = capture_region(Some((-10, 1000, 100, 100)), output, 1.0);
+        assert_eq!(fields(region), (1920, 1000, 90, 24));
+
+        let region = capture_region(Some((2000, 0, 100, 100)), output, 1.0);
+        assert_eq!(fields(region).2, 0);
+    }
+
+    #[test]
+    fn write_pool_converts_to_argb() {
+        let image = frame(1, 2, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        let data = BufferData {
+            offset: 4,
+            width: 1,
+            height: 2,
+            stride: 8,
+            format: wl_shm::Format::Argb8888,
+        };
+
+        let mut pool = vec![0; 20];
+        assert!(write_pool(&mut pool, &data, &image));
+        assert_eq!(
+            pool,
+            vec![0, 0, 0, 0, 3, 2, 1, 4, 0, 0, 0, 0, 7, 6, 5, 8, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn write_pool_rejects_small_pools() {
+        let image = frame(1, 2, vec![0; 8]);
+        let data = BufferData {
+            offset: 4,
+            width: 1,
+            height: 2,
+            stride: 8,
+            format: wl_shm::Format::Argb8888,
+        };
+
+        let mut pool = vec![0; 15];
+        assert!(!write_pool(&mut pool, &data, &image));
+    }
+}
@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
+use std::rc::Rc;
+use std::slice;
+
+use smithay::utils::Rectangle;
+use smithay::wayland::compositor::Damage;
+use wayland_server::protocol::wl_surface::WlSurface;
+use wayland_server::Display;
+use wayland_sys::common::{wl_argument, wl_message};
+use wayland_sys::server::{wl_display, wl_resource};
+
+const LOGGER_REQUEST: c_int = 0;
+
+/// `wl_protocol_logger_message`.
+#[repr(C)]
+struct LoggerMessage {
+    resource: *mut wl_resource,
+    message_opcode: c_int,
+    message: *const wl_message,
+    arguments_count: c_int,
+    arguments: *const wl_argument,
+}
+
+type LoggerFunc = unsafe extern "C" fn(*mut c_void, c_int, *const LoggerMessage);
+
+// Not bound by wayland-sys
+#[link(name = "wayland-server")]
+extern "C" {
+    fn wl_display_add_protocol_logger(
+        display: *mut wl_display,
+        func: LoggerFunc,
+        user_data: *mut c_void,
+    ) -> *mut c_void;
+    fn wl_resource_get_class(resource: *mut wl_resource) -> *const c_char;
+}
+
+/// Every `damage` and `damage_buffer` request made on surfaces since their last commit.
+///
+/// Smithay only keeps the last damage request of a commit, so the requests are collected from
+/// the protocol logger of libwayland instead, which sees each request before it is dispatched.
+#[derive(Clone, Default)]
+pub(crate) struct SurfaceDamage {
+    pending: Rc<RefCell<HashMap<usize, Vec<Damage>>>>,
+}
+
+impl SurfaceDamage {
+    /// Starts collecting the damage requests of the surfaces of the display, for as long as the
+    /// display exists.
+    pub fn install(&self, display: &mut Display) {
+        let user_data = Box::into_raw(Box::new(self.clone()));
+        unsafe {
+            wl_display_add_protocol_logger(display.c_ptr(), log_request, user_data as *mut c_void);
+        }
+    }
+
+    /// Returns the damage requested on a surface since the last call, in request order.
+    pub fn take(&self, surface: &WlSurface) -> Vec<Damage> {
+        self.pending
+            .borrow_mut()
+            .remove(&(surface.as_ref().c_ptr() as usize))
+            .unwrap_or_default()
+    }
+
+    fn request(&self, resource: *mut wl_resource, name: &[u8], arguments: &[wl_argument]) {
+        let key = resource as usize;
+        let region = || unsafe {
+            Rectangle {
+                x: arguments[0].i,
+                y: arguments[1].i,
+                width: arguments[2].i,
+                height: arguments[3].i,
+            }
+        };
+
+        let damage = match (name, arguments.len()) {
+            (b"damage", 4) => Damage::Surface(region()),
+            (b"damage_buffer", 4) => Damage::Buffer(region()),
+            // The address may be reused by another surface
+            (b"destroy", _) => {
+                self.pending.borrow_mut().remove(&key);
+                return;
+            }
+            _ => return,
+        };
+        self.pending
+            .borrow_mut()
+            .entry(key)
+            .or_default()
+            .push(damage);
+    }
+}
+
+unsafe extern "C" fn log_request(
+    user_data: *mut c_void,
+    direction: c_int,
+    message: *const LoggerMessage,
+) {
+    if direction != LOGGER_REQUEST {
+        return;
+    }
+
+    let message = &*message;
+    let class = wl_resource_get_class(message.resource);
+    if class.is_null() || CStr::from_ptr(class).to_bytes() != b"wl_surface" {
+        return;
+    }
+
+    let damage = &*(user_data as *const SurfaceDamage);
+    let name = CStr::from_ptr((*message.message).name).to_bytes();
+    let arguments = match message.arguments_count {
+        count if count > 0 => slice::from_raw_parts(message.arguments, count as usize),
+        _ => &[],
+    };
+    damage.request(message.resource, name, arguments);
+}
@@ -0,0 +1,175 @@
+use log::debug;
+use wayland_protocols::viewporter::server::{wp_viewport, wp_viewporter};
+use wayland_server::protocol::wl_surface;
+use wayland_server::{Display, Global, NewResource};
+
+use crate::shell::MyCompositorToken;
+
+use wp_viewport::WpViewport;
+use wp_viewporter::WpViewporter;
+
+/// Crop and scale applied to a surface, in surface coordinates.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Viewport {
+    /// Region of the buffer to show, as (x, y, width, height).
+    pub source: Option<(f64, f64, f64, f64)>,
+    /// Size the source region is scaled to.
+    pub destination: Option<(i32, i32)>,
+}
+
+/// Double-buffered viewport state, kept in the user data of the surface.
+#[derive(Default)]
+pub struct ViewportState {
+    pending: Viewport,
+    object: Option<WpViewport>,
+}
+
+impl ViewportState {
+    /// Returns the state to apply for the current commit.
+    pub fn commit(&self) -> Viewport {
+        self.pending
+    }
+
+    /// Checks that the source rectangle lies within the buffer, whose size is given in surface
+    /// coordinates, posting `out_of_buffer` otherwise.
+    pub fn check_source(&self, buffer_size: (f64, f64)) {
+        let (x, y, width, height) = match self.pending.source {
+            Some(source) => source,
+            None => return,
+        };
+        if x + width <= buffer_size.0 && y + height <= buffer_size.1 {
+            return;
+        }
+
+        if let Some(object) = &self.object {
+            object.as_ref().post_error(
+                wp_viewport::Error::OutOfBuffer as u32,
+                "source rectangle extends outside of the buffer".into(),
+            );
+        }
+    }
+}
+
+pub fn init_viewporter_global(
+    display: &mut Display,
+    token: MyCompositorToken,
+) -> Global<WpViewporter> {
+    display.create_global(1, move |new_viewporter: NewResource<WpViewporter>, _| {
+        new_viewporter.implement_closure(
+            move |request, viewporter| match request {
+                wp_viewporter::Request::GetViewport { id, surface } => {
+                    let exists = token.with_surface_data(&surface, |attributes| {
+                        attributes
+                            .user_data
+                            .insert_if_missing(ViewportState::default);
+                        let state = attributes.user_data.get::<ViewportState>().unwrap();
+                        state.object.is_some()
+                    });
+
+                    if exists {
+                        viewporter.as_ref().post_error(
+                            wp_viewporter::Error::ViewportExists as u32,
+                            "surface already has a viewport".into(),
+                        );
+                        return;
+                    }
+
+                    let viewport = implement_viewport(id, surface.clone(), token);
+                    with_state(&surface, token, |state| state.object = Some(viewport));
+                }
+                wp_viewporter::Request::Destroy => {
+                    // Our destructors already handle it
+                }
+                _ => unreachable!(),
+            },
+            None::<fn(_)>,
+            (),
+        );
+    })
+}
+
+fn implement_viewport(
+    new_viewport: NewResource<WpViewport>,
+    surface: wl_surface::WlSurface,
+    token: MyCompositorToken,
+) -> WpViewport {
+    let destructor_surface = surface.clone();
+
+    new_viewport.implement_closure(
+        move |request, viewport| {
+            if !surface.as_ref().is_alive() {
+                viewport.as_ref().post_error(
+                    wp_viewport::Error::NoSurface as u32,
+                    "the surface was destroyed".into(),
+                );
+                return;
+            }
+
+            match request {
+                wp_viewport::Request::SetSource {
+                    x,
+                    y,
+                    width,
+                    height,
+                } => {
+                    let source = if x == -1.0 && y == -1.0 && width == -1.0 && height == -1.0 {
+                        None
+                    } else if x < 0.0 || y < 0.0 || width <= 0.0 || height <= 0.0 {
+                        viewport.as_ref().post_error(
+                            wp_viewport::Error::BadValue as u32,
+                            "invalid source rectangle".into(),
+                        );
+                        return;
+                    } else {
+                        Some((x, y, width, height))
+                    };
+
+                    debug!("Viewport source {:?}", source);
+                    with_state(&surface, token, |state| state.pending.source = source);
+                }
+                wp_viewport::Request::SetDestination { width, height } => {
+                    let destination = if width == -1 && height == -1 {
+                        None
+                    } else if width <= 0 || height <= 0 {
+                        viewport.as_ref().post_error(
+                            wp_viewport::Error::BadValue as u32,
+                            "invalid destination size".into(),
+                        );
+                        return;
+                    } else {
+                        Some((width, height))
+                    };
+
+                    debug!("Viewport destination {:?}", destination);
+                    with_state(&surface, token, |state| {
+                        state.pending.destination = destination
+                    });
+                }
+                wp_viewport::Request::Destroy => {
+                    // Handled by the destructor
+                }
+                _ => unreachable!(),
+            }
+        },
+        Some(move |_| {
+            // The surface goes back to its normal size on the next commit
+            if destructor_surface.as_ref().is_alive() {
+                with_state(&destructor_surface, token, |state| {
+                    *state = ViewportState::default();
+                });
+            }
+        }),
+        (),
+    )
+}
+
+fn with_state<F>(surface: &wl_surface::WlSurface, token: MyCompositorToken, f: F)
+where
+    F: FnOnce(&mut ViewportState),
+{
+    token.with_surface_data(surface, |attributes| {
+        if let Some(state) = attributes.user_data.get_mut::<ViewportState>() {
+            f(state);
+        }
+    });
+}
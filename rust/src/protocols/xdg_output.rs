@@ -0,0 +1,84 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use smithay::wayland::output::Output;
+use wayland_protocols::unstable::xdg_output::v1::server::{zxdg_output_manager_v1, zxdg_output_v1};
+use wayland_server::protocol::wl_output::WlOutput;
+use wayland_server::{Display, Global, NewResource};
+
+use crate::backends::layout::OutputInfo;
+use crate::FlutterCompositorWeakRef;
+
+use zxdg_output_manager_v1::ZxdgOutputManagerV1;
+use zxdg_output_v1::ZxdgOutputV1;
+
+/// The xdg_output objects of all clients, updated when their monitor changes.
+#[derive(Clone, Default)]
+pub(crate) struct XdgOutputs {
+    objects: Rc<RefCell<Vec<(ZxdgOutputV1, WlOutput)>>>,
+}
+
+impl XdgOutputs {
+    /// Sends the new description of a monitor to the objects created for it.
+    pub fn update(&self, output: &Output, info: &OutputInfo, scale: f64) {
+        let mut objects = self.objects.borrow_mut();
+        objects.retain(|(object, _)| object.as_ref().is_alive());
+        for (object, wl_output) in objects.iter() {
+            if output.owns(wl_output) {
+                send_output(object, info, scale);
+            }
+        }
+    }
+}
+
+pub fn init_xdg_output_global(
+    display: &mut Display,
+    outputs: XdgOutputs,
+    compositor: FlutterCompositorWeakRef,
+) -> Global<ZxdgOutputManagerV1> {
+    display.create_global(
+        2,
+        move |new_manager: NewResource<ZxdgOutputManagerV1>, _| {
+            let outputs = outputs.clone();
+            let compositor = compositor.clone();
+            new_manager.implement_closure(
+                move |request, _manager| match request {
+                    zxdg_output_manager_v1::Request::GetXdgOutput { id, output } => {
+                        let object = id.implement_closure(|_, _| {}, None::<fn(_)>, ());
+
+                        if let Some(compositor_ref) = compositor.upgrade() {
+                            let compositor = compositor_ref.get();
+                            let backend = &compositor.backend;
+                            // Outputs removed in the meantime are never described
+                            if let Some(info) = backend.output_info(&output) {
+                                send_output(&object, &info, backend.get_scale_factor());
+                            }
+                        }
+
+                        outputs.objects.borrow_mut().push((object, output));
+                    }
+                    zxdg_output_manager_v1::Request::Destroy => {
+                        // Our destructors already handle it
+                    }
+                    _ => unreachable!(),
+                },
+                None::<fn(_)>,
+                (),
+            );
+        },
+    )
+}
+
+/// Describes a monitor in logical coordinates, where it is placed on the flutter surface.
+fn send_output(object: &ZxdgOutputV1, info: &OutputInfo, scale: f64) {
+    let logical = |value: i32| (f64::from(value) / scale).round() as i32;
+    let region = info.region;
+    object.logical_position(logical(region.x), logical(region.y));
+    object.logical_size(logical(region.width), logical(region.height));
+
+    if object.as_ref().version() >= 2 {
+        object.name(info.name.clone());
+        object.description(format!("{} {} ({})", info.make, info.model, info.name));
+    }
+    object.done();
+}
@@ -0,0 +1,276 @@
+use smithay::backend::egl::{EGLImages, Format, TextureCreationError};
+
+use crate::renderer::gl_state::{SavedAttribute, SavedState, SavedTexture};
+use crate::renderer::{gl, gl_util};
+use log::warn;
+
+use std::ffi::CString;
+
+const VERTEX_SHADER: &str = r#"
+#version 100
+attribute vec2 position;
+uniform bool y_inverted;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_Position = vec4(position * 2.0 - 1.0, 0.0, 1.0);
+    v_tex_coords = vec2(position.x, y_inverted ? position.y : 1.0 - position.y);
+}
+"#;
+
+const FRAGMENT_RGBA: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_FragColor = texture2D(tex0, v_tex_coords);
+}
+"#;
+
+const FRAGMENT_RGB: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_FragColor = vec4(texture2D(tex0, v_tex_coords).rgb, 1.0);
+}
+"#;
+
+const FRAGMENT_Y_UV: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+uniform sampler2D tex1;
+varying vec2 v_tex_coords;
+
+void main() {
+    float y = 1.16438356 * (texture2D(tex0, v_tex_coords).r - 0.0625);
+    float u = texture2D(tex1, v_tex_coords).r - 0.5;
+    float v = texture2D(tex1, v_tex_coords).g - 0.5;
+    gl_FragColor = vec4(
+        y + 1.59602678 * v,
+        y - 0.39176229 * u - 0.81296764 * v,
+        y + 2.01723214 * u,
+        1.0
+    );
+}
+"#;
+
+const FRAGMENT_Y_U_V: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+uniform sampler2D tex1;
+uniform sampler2D tex2;
+varying vec2 v_tex_coords;
+
+void main() {
+    float y = 1.16438356 * (texture2D(tex0, v_tex_coords).r - 0.0625);
+    float u = texture2D(tex1, v_tex_coords).r - 0.5;
+    float v = texture2D(tex2, v_tex_coords).r - 0.5;
+    gl_FragColor = vec4(
+        y + 1.59602678 * v,
+        y - 0.39176229 * u - 0.81296764 * v,
+        y + 2.01723214 * u,
+        1.0
+    );
+}
+"#;
+
+const FRAGMENT_Y_XUXV: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+uniform sampler2D tex1;
+varying vec2 v_tex_coords;
+
+void main() {
+    float y = 1.16438356 * (texture2D(tex0, v_tex_coords).r - 0.0625);
+    float u = texture2D(tex1, v_tex_coords).g - 0.5;
+    float v = texture2D(tex1, v_tex_coords).a - 0.5;
+    gl_FragColor = vec4(
+        y + 1.59602678 * v,
+        y - 0.39176229 * u - 0.81296764 * v,
+        y + 2.01723214 * u,
+        1.0
+    );
+}
+"#;
+
+const QUAD: [f32; 8] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+
+#[derive(Debug)]
+pub enum ConvertError {
+    UnsupportedFormat(Format),
+    MissingProgram(Format),
+    Texture(TextureCreationError),
+    IncompleteFramebuffer(u32),
+}
+
+/// Renders EGL buffers that flutter cannot sample directly into plain RGBA textures.
+///
+/// This covers multi-planar YUV buffers, as well as RGB buffers that are not y-inverted.
+pub struct Converter {
+    rgba: Option<u32>,
+    rgb: Option<u32>,
+    y_uv: Option<u32>,
+    y_u_v: Option<u32>,
+    y_xuxv: Option<u32>,
+}
+
+impl Converter {
+    pub fn new(gl: &gl::Gl) -> Self {
+        Self {
+            rgba: gl_util::create_program(gl, VERTEX_SHADER, FRAGMENT_RGBA),
+            rgb: gl_util::create_program(gl, VERTEX_SHADER, FRAGMENT_RGB),
+            y_uv: gl_util::create_program(gl, VERTEX_SHADER, FRAGMENT_Y_UV),
+            y_u_v: gl_util::create_program(gl, VERTEX_SHADER, FRAGMENT_Y_U_V),
+            y_xuxv: gl_util::create_program(gl, VERTEX_SHADER, FRAGMENT_Y_XUXV),
+        }
+    }
+
+    fn program(&self, format: Format) -> Result<u32, ConvertError> {
+        let program = match format {
+            Format::RGBA => self.rgba,
+            Format::RGB => self.rgb,
+            Format::Y_UV => self.y_uv,
+            Format::Y_U_V => self.y_u_v,
+            Format::Y_XUXV => self.y_xuxv,
+            // External images can only be sampled through samplerExternalOES
+            Format::External => return Err(ConvertError::UnsupportedFormat(format)),
+        };
+        program.ok_or(ConvertError::MissingProgram(format))
+    }
+
+    /// Draws the contents of the given images into `target`, which must already have storage
+    /// matching the image size.
+    pub fn convert(
+        &self,
+        gl: &gl::Gl,
+        images: &EGLImages,
+        target: u32,
+    ) -> Result<(), ConvertError> {
+        let planes: Vec<u32> = (0..images.num_planes())
+            .map(|_| gl_util::gen_texture(gl))
+            .collect();
+
+        let result = planes
+            .iter()
+            .enumerate()
+            .try_for_each(|(plane, texture)| unsafe { images.bind_to_texture(plane, *texture) })
+            .map_err(ConvertError::Texture)
+            .and_then(|_| {
+                self.convert_planes(
+                    gl,
+                    images.format,
+                    images.y_inverted,
+                    (images.width as i32, images.height as i32),
+                    &planes,
+                    target,
+                )
+            });
+
+        for texture in planes {
+            gl_util::delete_texture(gl, texture);
+        }
+
+        result
+    }
+
+    /// Draws the given plane textures into `target`, which must already have storage matching
+    /// `size`.
+    pub fn convert_planes(
+        &self,
+        gl: &gl::Gl,
+        format: Format,
+        y_inverted: bool,
+        size: (i32, i32),
+        planes: &[u32],
+        target: u32,
+    ) -> Result<(), ConvertError> {
+        let program = self.program(format)?;
+        unsafe { self.draw(gl, program, y_inverted, size, planes, target) }
+    }
+
+    /// Draws with the given program into `target`, restoring the GL state it changes, as the
+    /// context may be shared with the engine.
+    unsafe fn draw(
+        &self,
+        gl: &gl::Gl,
+        program: u32,
+        y_inverted: bool,
+        size: (i32, i32),
+        planes: &[u32],
+        target: u32,
+    ) -> Result<(), ConvertError> {
+        let state = SavedState::save(gl);
+        let textures: Vec<SavedTexture> = (1..planes.len() as u32)
+            .map(|unit| SavedTexture::save(gl, unit))
+            .collect();
+
+        let mut fbo = 0;
+        gl.GenFramebuffers(1, &mut fbo);
+        gl.BindFramebuffer(gl::FRAMEBUFFER, fbo);
+        gl.FramebufferTexture2D(
+            gl::FRAMEBUFFER,
+            gl::COLOR_ATTACHMENT0,
+            gl::TEXTURE_2D,
+            target,
+            0,
+        );
+
+        let status = gl.CheckFramebufferStatus(gl::FRAMEBUFFER);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            warn!("Conversion framebuffer incomplete: {}", status);
+            state.restore(gl);
+            gl.DeleteFramebuffers(1, &fbo);
+            return Err(ConvertError::IncompleteFramebuffer(status));
+        }
+
+        gl.Viewport(0, 0, size.0, size.1);
+        gl.Disable(gl::BLEND);
+        gl.UseProgram(program);
+
+        for (plane, texture) in planes.iter().enumerate() {
+            let name = CString::new(format!("tex{}", plane)).unwrap();
+            gl.ActiveTexture(gl::TEXTURE0 + plane as u32);
+            gl.BindTexture(gl::TEXTURE_2D, *texture);
+            gl.Uniform1i(gl.GetUniformLocation(program, name.as_ptr()), plane as i32);
+        }
+
+        let uniform = CString::new("y_inverted").unwrap();
+        gl.Uniform1i(
+            gl.GetUniformLocation(program, uniform.as_ptr()),
+            y_inverted as i32,
+        );
+
+        let position = CString::new("position").unwrap();
+        let position = gl.GetAttribLocation(program, position.as_ptr()) as u32;
+        let attribute = SavedAttribute::save(gl, position);
+        gl.BindBuffer(gl::ARRAY_BUFFER, 0);
+        gl.VertexAttribPointer(
+            position,
+            2,
+            gl::FLOAT,
+            gl::FALSE,
+            0,
+            QUAD.as_ptr() as *const _,
+        );
+        gl.EnableVertexAttribArray(position);
+
+        gl.DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+        attribute.restore(gl);
+        for texture in &textures {
+            texture.restore(gl);
+        }
+        state.restore(gl);
+        gl.DeleteFramebuffers(1, &fbo);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use std::ffi::{c_void, CString};
+
+use smithay::reexports::image::RgbaImage;
+
+use crate::backends::cursor::CursorState;
+use crate::renderer::gl_state::{SavedAttribute, SavedState};
+use crate::renderer::{gl, gl_util};
+
+const VERTEX_SHADER: &str = r#"
+#version 100
+attribute vec2 position;
+// left, top, width and height of the cursor, in framebuffer pixels
+uniform vec4 rect;
+uniform vec2 framebuffer;
+varying vec2 v_tex_coords;
+
+void main() {
+    vec2 pixel = rect.xy + position * rect.zw;
+    gl_Position = vec4(
+        pixel.x / framebuffer.x * 2.0 - 1.0,
+        1.0 - pixel.y / framebuffer.y * 2.0,
+        0.0,
+        1.0
+    );
+    v_tex_coords = position;
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_FragColor = texture2D(tex0, v_tex_coords);
+}
+"#;
+
+const QUAD: [f32; 8] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+
+/// Composites the cursor onto flutter frames, for when no cursor plane is available.
+///
+/// The cursor is drawn right before the frame is presented, so it only moves when flutter renders
+/// a new frame.
+pub struct SoftwareCursor {
+    gl: gl::Gl,
+    program: Option<u32>,
+    /// The texture holding the cursor image, with the serial of the image it holds.
+    texture: Option<(u32, u64)>,
+}
+
+impl SoftwareCursor {
+    pub fn new(gl: gl::Gl) -> Self {
+        let program = gl_util::create_program(&gl, VERTEX_SHADER, FRAGMENT_SHADER);
+        Self {
+            gl,
+            program,
+            texture: None,
+        }
+    }
+
+    /// Draws the cursor onto the framebuffer flutter renders into, of the given size.
+    ///
+    /// Flutter does not expect the GL state to change between frames, so everything touched here
+    /// is restored afterwards.
+    pub fn draw(&mut self, cursor: &CursorState, framebuffer: u32, size: (u32, u32)) {
+        let program = match self.program {
+            Some(program) => program,
+            None => return,
+        };
+        let position = match cursor.position() {
+            Some(position) => position,
+            None => return,
+        };
+        let image = cursor.image();
+        let image = match image.as_ref() {
+            Some(cursor) => &cursor.image,
+            None => return,
+        };
+
+        unsafe {
+            let saved = SavedState::save(&self.gl);
+            let texture = self.texture(image, cursor.serial());
+            let gl = &self.gl;
+
+            gl.BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+            gl.Viewport(0, 0, size.0 as i32, size.1 as i32);
+            gl.Disable(gl::SCISSOR_TEST);
+            gl.Enable(gl::BLEND);
+            gl.BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+            gl.UseProgram(program);
+
+            gl.ActiveTexture(gl::TEXTURE0);
+            gl.BindTexture(gl::TEXTURE_2D, texture);
+            let name = CString::new("tex0").unwrap();
+            gl.Uniform1i(gl.GetUniformLocation(program, name.as_ptr()), 0);
+
+            let name = CString::new("rect").unwrap();
+            gl.Uniform4f(
+                gl.GetUniformLocation(program, name.as_ptr()),
+                position.0 as f32,
+                position.1 as f32,
+                image.width() as f32,
+                image.height() as f32,
+            );
+            let name = CString::new("framebuffer").unwrap();
+            gl.Uniform2f(
+                gl.GetUniformLocation(program, name.as_ptr()),
+                size.0 as f32,
+                size.1 as f32,
+            );
+
+            let name = CString::new("position").unwrap();
+            let attribute = gl.GetAttribLocation(program, name.as_ptr()) as u32;
+            let saved_attribute = SavedAttribute::save(gl, attribute);
+            gl.BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl.VertexAttribPointer(
+                attribute,
+                2,
+                gl::FLOAT,
+                gl::FALSE,
+                0,
+                QUAD.as_ptr() as *const _,
+            );
+            gl.EnableVertexAttribArray(attribute);
+
+            gl.DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+            saved_attribute.restore(gl);
+            saved.restore(gl);
+        }
+    }
+
+    /// Returns the texture holding the given image, uploading it if it changed.
+    unsafe fn texture(&mut self, image: &RgbaImage, serial: u64) -> u32 {
+        let gl = &self.gl;
+        match self.texture {
+            Some((texture, uploaded)) if uploaded == serial => return texture,
+            Some((texture, _)) => gl_util::delete_texture(gl, texture),
+            None => {}
+        }
+
+        let texture = gl_util::gen_texture(gl);
+        gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl.TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA as i32,
+            image.width() as i32,
+            image.height() as i32,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            image.as_ptr() as *const c_void,
+        );
+        gl.PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+
+        self.texture = Some((texture, serial));
+        texture
+    }
+}
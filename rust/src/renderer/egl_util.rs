@@ -1,12 +1,57 @@
 use core::{mem, ptr};
-use log::{debug, info};
+use log::{debug, error, info, trace, warn};
 
+use crate::protocols::dmabuf::{Dmabuf, DmabufFormat, DRM_FORMAT_MOD_INVALID};
 use crate::renderer::gl;
 use smithay::backend::egl::context::PixelFormatRequirements;
 use smithay::backend::egl::ffi;
 use smithay::backend::graphics::PixelFormat;
+use std::error::Error;
 use std::ffi::{c_void, CStr, CString};
+use std::fmt;
 use std::os::raw::c_int;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Failures setting up the extra EGL contexts, with the EGL error code where there is one.
+#[derive(Debug)]
+pub enum EglError {
+    NoCurrentDisplay,
+    Initialize(u32),
+    /// The EGL implementation does not provide OpenGL ES.
+    OpenGlesUnsupported,
+    /// The EGL version is too old for the requested OpenGL ES version.
+    VersionUnsupported {
+        egl: (i32, i32),
+        gles: (u8, u8),
+    },
+    ChooseConfig(u32),
+    NoMatchingConfig,
+    ConfigAttribute(u32),
+    CreateContext(u32),
+}
+
+impl fmt::Display for EglError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EglError::NoCurrentDisplay => write!(f, "no current EGL display"),
+            EglError::Initialize(code) => write!(f, "failed to initialize EGL: {:#x}", code),
+            EglError::OpenGlesUnsupported => write!(f, "OpenGL ES is not supported"),
+            EglError::VersionUnsupported { egl, gles } => write!(
+                f,
+                "EGL {}.{} does not support OpenGL ES {}.{}",
+                egl.0, egl.1, gles.0, gles.1
+            ),
+            EglError::ChooseConfig(code) => write!(f, "failed to choose a config: {:#x}", code),
+            EglError::NoMatchingConfig => write!(f, "no matching config found"),
+            EglError::ConfigAttribute(code) => {
+                write!(f, "failed to query a config attribute: {:#x}", code)
+            }
+            EglError::CreateContext(code) => write!(f, "failed to create context: {:#x}", code),
+        }
+    }
+}
+
+impl Error for EglError {}
 
 pub struct WrappedDisplay(ffi::egl::types::EGLDisplay);
 
@@ -17,38 +62,255 @@ impl Clone for WrappedDisplay {
 }
 
 impl WrappedDisplay {
-    pub unsafe fn new() -> Self {
+    pub unsafe fn new() -> Result<Self, EglError> {
         let display = ffi::egl::GetCurrentDisplay();
 
         if display == ptr::null() {
-            panic!("Failed to fetch display");
+            return Err(EglError::NoCurrentDisplay);
         }
 
-        info!("Current display was {:?}", display);
-        WrappedDisplay(display)
+        trace!("Current display was {:?}", display);
+        Ok(WrappedDisplay(display))
     }
 
     pub unsafe fn release_context(&self) {
         let _ret = ffi::egl::MakeCurrent(self.0, ptr::null(), ptr::null(), ptr::null());
     }
+
+    /// Queries the dmabuf formats, and their modifiers, that this display can import.
+    pub unsafe fn dmabuf_formats(&self) -> Vec<DmabufFormat> {
+        let query_formats: Option<QueryDmaBufFormats> = QUERY_DMABUF_FORMATS.get();
+        let query_formats = match query_formats {
+            Some(query_formats) => query_formats,
+            None => {
+                warn!("EGL_EXT_image_dma_buf_import_modifiers is not supported");
+                return vec![];
+            }
+        };
+        let query_modifiers: Option<QueryDmaBufModifiers> = QUERY_DMABUF_MODIFIERS.get();
+
+        let mut num = 0;
+        if query_formats(self.0, 0, ptr::null_mut(), &mut num) == 0 {
+            return vec![];
+        }
+        let mut formats = vec![0 as c_int; num as usize];
+        if query_formats(self.0, num, formats.as_mut_ptr(), &mut num) == 0 {
+            return vec![];
+        }
+        formats.truncate(num as usize);
+
+        let mut result = Vec::new();
+        for format in formats {
+            let format = format as u32;
+
+            let mut modifiers: Vec<u64> = vec![];
+            if let Some(query_modifiers) = query_modifiers {
+                let mut num = 0;
+                if query_modifiers(
+                    self.0,
+                    format as c_int,
+                    0,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut num,
+                ) != 0
+                    && num > 0
+                {
+                    modifiers.resize(num as usize, 0);
+                    if query_modifiers(
+                        self.0,
+                        format as c_int,
+                        num,
+                        modifiers.as_mut_ptr(),
+                        ptr::null_mut(),
+                        &mut num,
+                    ) == 0
+                    {
+                        modifiers.clear();
+                    }
+                    modifiers.truncate(num as usize);
+                }
+            }
+
+            // Without explicit modifiers the driver picks the layout
+            if modifiers.is_empty() {
+                modifiers.push(DRM_FORMAT_MOD_INVALID);
+            }
+
+            for modifier in modifiers {
+                result.push(DmabufFormat { format, modifier });
+            }
+        }
+
+        debug!("Supported dmabuf formats: {:?}", result);
+        result
+    }
+
+    /// Creates an EGLImage referencing the planes of a dmabuf.
+    pub unsafe fn create_dmabuf_image(&self, dmabuf: &Dmabuf) -> Option<WrappedImage> {
+        let create_image: CreateImage = CREATE_IMAGE.get()?;
+
+        let mut attributes: Vec<c_int> = vec![
+            ffi::egl::WIDTH as c_int,
+            dmabuf.width,
+            ffi::egl::HEIGHT as c_int,
+            dmabuf.height,
+            LINUX_DRM_FOURCC_EXT,
+            dmabuf.format as c_int,
+        ];
+
+        for (index, plane) in dmabuf.planes.iter().enumerate() {
+            let names = DMA_BUF_PLANE_ATTRIBUTES[index];
+            attributes.extend_from_slice(&[
+                names[0],
+                plane.fd,
+                names[1],
+                plane.offset as c_int,
+                names[2],
+                plane.stride as c_int,
+            ]);
+            if plane.modifier != DRM_FORMAT_MOD_INVALID {
+                attributes.extend_from_slice(&[
+                    names[3],
+                    (plane.modifier & 0xffff_ffff) as c_int,
+                    names[4],
+                    (plane.modifier >> 32) as c_int,
+                ]);
+            }
+        }
+        attributes.push(ffi::egl::NONE as c_int);
+
+        let image = create_image(
+            self.0,
+            ptr::null(),
+            LINUX_DMA_BUF_EXT,
+            ptr::null(),
+            attributes.as_ptr(),
+        );
+        if image.is_null() {
+            warn!("Failed to create dmabuf image: {}", ffi::egl::GetError());
+            return None;
+        }
+
+        Some(WrappedImage {
+            display: self.0,
+            image,
+        })
+    }
+}
+
+/// An EGLImage, destroyed when dropped.
+pub struct WrappedImage {
+    display: ffi::egl::types::EGLDisplay,
+    image: *const c_void,
+}
+
+impl WrappedImage {
+    pub fn as_ptr(&self) -> *const c_void {
+        self.image
+    }
+}
+
+impl Drop for WrappedImage {
+    fn drop(&mut self) {
+        unsafe {
+            let destroy_image: Option<DestroyImage> = DESTROY_IMAGE.get();
+            if let Some(destroy_image) = destroy_image {
+                destroy_image(self.display, self.image);
+            }
+        }
+    }
+}
+
+const LINUX_DMA_BUF_EXT: u32 = 0x3270;
+const LINUX_DRM_FOURCC_EXT: c_int = 0x3271;
+
+/// The fd, offset, pitch, modifier low and modifier high attributes of each plane.
+const DMA_BUF_PLANE_ATTRIBUTES: [[c_int; 5]; 4] = [
+    [0x3272, 0x3273, 0x3274, 0x3443, 0x3444],
+    [0x3275, 0x3276, 0x3277, 0x3445, 0x3446],
+    [0x3278, 0x3279, 0x327A, 0x3447, 0x3448],
+    [0x3440, 0x3441, 0x3442, 0x3449, 0x344A],
+];
+
+type QueryDmaBufFormats =
+    unsafe extern "system" fn(ffi::egl::types::EGLDisplay, c_int, *mut c_int, *mut c_int) -> u32;
+type QueryDmaBufModifiers = unsafe extern "system" fn(
+    ffi::egl::types::EGLDisplay,
+    c_int,
+    c_int,
+    *mut u64,
+    *mut u32,
+    *mut c_int,
+) -> u32;
+type CreateImage = unsafe extern "system" fn(
+    ffi::egl::types::EGLDisplay,
+    *const c_void,
+    u32,
+    *const c_void,
+    *const c_int,
+) -> *const c_void;
+type DestroyImage = unsafe extern "system" fn(ffi::egl::types::EGLDisplay, *const c_void) -> u32;
+
+static QUERY_DMABUF_FORMATS: ExtensionFn = ExtensionFn::new(b"eglQueryDmaBufFormatsEXT\0");
+static QUERY_DMABUF_MODIFIERS: ExtensionFn = ExtensionFn::new(b"eglQueryDmaBufModifiersEXT\0");
+static CREATE_IMAGE: ExtensionFn = ExtensionFn::new(b"eglCreateImageKHR\0");
+static DESTROY_IMAGE: ExtensionFn = ExtensionFn::new(b"eglDestroyImageKHR\0");
+
+/// An EGL extension function, looked up on first use.
+struct ExtensionFn {
+    /// Nul terminated name of the function.
+    name: &'static [u8],
+    /// Address of the function, `UNKNOWN` until looked up and `MISSING` if there is none.
+    addr: AtomicUsize,
+}
+
+impl ExtensionFn {
+    const UNKNOWN: usize = 0;
+    const MISSING: usize = 1;
+
+    const fn new(name: &'static [u8]) -> Self {
+        Self {
+            name,
+            addr: AtomicUsize::new(Self::UNKNOWN),
+        }
+    }
+
+    /// Returns the function as `T`, which must be its function pointer type.
+    unsafe fn get<T: Copy>(&self) -> Option<T> {
+        let mut addr = self.addr.load(Ordering::Relaxed);
+        if addr == Self::UNKNOWN {
+            let ptr = ffi::egl::GetProcAddress(self.name.as_ptr() as *const _) as usize;
+            addr = if ptr == 0 { Self::MISSING } else { ptr };
+            self.addr.store(addr, Ordering::Relaxed);
+        }
+
+        if addr == Self::MISSING {
+            None
+        } else {
+            Some(mem::transmute_copy(&(addr as *const c_void)))
+        }
+    }
 }
 
 pub struct WrappedContext(ffi::egl::types::EGLContext);
 
 impl WrappedContext {
-    pub unsafe fn create_context() -> WrappedContext {
+    /// Creates a context sharing resources with the current one, preferring OpenGL ES 3.0 and
+    /// falling back to 2.0.
+    pub unsafe fn create_context() -> Result<WrappedContext, EglError> {
         debug!("Trying to initialize EGL with OpenGLES 3.0");
-        create_extra_context_inner((3, 0))
-        //    attributes.version = Some((3, 0));
-        //    match EGLContext::<B, N>::new_internal(ptr, attributes, reqs, log.clone()) {
-        //        Ok(x) => return Ok(x),
-        //        Err(err) => {
-        //            warn!(log, "EGL OpenGLES 3.0 Initialization failed with {}", err);
-        //            debug!(log, "Trying to initialize EGL with OpenGLES 2.0");
-        //            attributes.version = Some((2, 0));
-        //            return EGLContext::<B, N>::new_internal(ptr, attributes, reqs, log);
-        //        }
-        //    }
+        match create_extra_context_inner((3, 0)) {
+            Ok(context) => Ok(context),
+            Err(err) => {
+                warn!("EGL OpenGLES 3.0 initialization failed with {:?}", err);
+                debug!("Trying to initialize EGL with OpenGLES 2.0");
+                create_extra_context_inner((2, 0)).map_err(|err| {
+                    error!("EGL OpenGLES 2.0 initialization failed with {:?}", err);
+                    err
+                })
+            }
+        }
     }
 
     pub unsafe fn current() -> Self {
@@ -76,18 +338,18 @@ impl WrappedContext {
     }
 }
 
-unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
+unsafe fn create_extra_context_inner(version: (u8, u8)) -> Result<WrappedContext, EglError> {
     let reqs: PixelFormatRequirements = Default::default();
 
     let display = ffi::egl::GetCurrentDisplay();
     info!("Current display was {:?}", display);
 
     let egl_version = {
-        let mut major: ffi::egl::types::EGLint = mem::uninitialized();
-        let mut minor: ffi::egl::types::EGLint = mem::uninitialized();
+        let mut major: ffi::egl::types::EGLint = 0;
+        let mut minor: ffi::egl::types::EGLint = 0;
 
         if ffi::egl::Initialize(display, &mut major, &mut minor) == 0 {
-            panic!("Display reinit failed");
+            return Err(EglError::Initialize(ffi::egl::GetError() as u32));
         }
 
         info!("EGL Version: {:?}", (major, minor));
@@ -106,7 +368,14 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
     info!("EGL Extensions: {:?}", extensions);
 
     if egl_version >= (1, 2) && ffi::egl::BindAPI(ffi::egl::OPENGL_ES_API) == 0 {
-        panic!("OpenGLES not supported by the underlying EGL implementation");
+        return Err(EglError::OpenGlesUnsupported);
+    }
+
+    if egl_version < (1, 3) {
+        return Err(EglError::VersionUnsupported {
+            egl: egl_version,
+            gles: version,
+        });
     }
 
     let descriptor = {
@@ -127,9 +396,6 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
 
         match version {
             (3, _) => {
-                if egl_version < (1, 3) {
-                    panic!("OpenglES 3.* is not supported on EGL Versions lower then 1.3");
-                }
                 debug!("Setting RENDERABLE_TYPE to OPENGL_ES3");
                 out.push(ffi::egl::RENDERABLE_TYPE as c_int);
                 out.push(ffi::egl::OPENGL_ES3_BIT as c_int);
@@ -138,9 +404,6 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
                 out.push(ffi::egl::OPENGL_ES3_BIT as c_int);
             }
             (2, _) => {
-                if egl_version < (1, 3) {
-                    panic!("OpenglES 2.* is not supported on EGL Versions lower then 1.3");
-                }
                 debug!("Setting RENDERABLE_TYPE to OPENGL_ES2");
                 out.push(ffi::egl::RENDERABLE_TYPE as c_int);
                 out.push(ffi::egl::OPENGL_ES2_BIT as c_int);
@@ -204,17 +467,13 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
             out.push(multisampling as c_int);
         }
 
-        if reqs.stereoscopy {
-            panic!("Stereoscopy is currently unsupported (sorry!)");
-        }
-
         out.push(ffi::egl::NONE as c_int);
         out
     };
 
     // calling `eglChooseConfig`
-    let mut config_id = mem::uninitialized();
-    let mut num_configs = mem::uninitialized();
+    let mut config_id = ptr::null();
+    let mut num_configs = 0;
     if ffi::egl::ChooseConfig(
         display,
         descriptor.as_ptr(),
@@ -223,16 +482,16 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
         &mut num_configs,
     ) == 0
     {
-        panic!("Config failed");
+        return Err(EglError::ChooseConfig(ffi::egl::GetError() as u32));
     }
     if num_configs == 0 {
-        panic!("No matching color format found");
+        return Err(EglError::NoMatchingConfig);
     }
 
     // analyzing each config
     macro_rules! attrib {
         ($display:expr, $config:expr, $attr:expr) => {{
-            let mut value = mem::uninitialized();
+            let mut value = 0;
             let res = ffi::egl::GetConfigAttrib(
                 $display,
                 $config,
@@ -240,7 +499,7 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
                 &mut value,
             );
             if res == 0 {
-                panic!("Config failed");
+                return Err(EglError::ConfigAttribute(ffi::egl::GetError() as u32));
             }
             value
         }};
@@ -295,12 +554,9 @@ unsafe fn create_extra_context_inner(version: (u8, u8)) -> WrappedContext {
         ffi::egl::CreateContext(display, config_id, old_context, context_attributes.as_ptr());
 
     if context.is_null() {
-        match ffi::egl::GetError() as u32 {
-            ffi::egl::BAD_ATTRIBUTE => panic!("Creation failed"),
-            err_no => panic!("Unknown error {}", err_no),
-        }
+        return Err(EglError::CreateContext(ffi::egl::GetError() as u32));
     }
     debug!("EGL context successfully created");
 
-    WrappedContext(context)
+    Ok(WrappedContext(context))
 }
@@ -0,0 +1,168 @@
+use std::ffi::c_void;
+use std::ptr;
+
+use crate::renderer::gl;
+
+/// GL state changed when drawing over flutter frames.
+///
+/// Flutter does not expect the GL state to change between frames, so it has to be restored
+/// afterwards.
+pub(crate) struct SavedState {
+    framebuffer: i32,
+    viewport: [i32; 4],
+    scissor: bool,
+    blend: bool,
+    blend_func: [i32; 4],
+    program: i32,
+    active_texture: i32,
+    texture: i32,
+    array_buffer: i32,
+}
+
+impl SavedState {
+    pub unsafe fn save(gl: &gl::Gl) -> Self {
+        let mut state = SavedState {
+            framebuffer: 0,
+            viewport: [0; 4],
+            scissor: gl.IsEnabled(gl::SCISSOR_TEST) == gl::TRUE,
+            blend: gl.IsEnabled(gl::BLEND) == gl::TRUE,
+            blend_func: [0; 4],
+            program: 0,
+            active_texture: 0,
+            texture: 0,
+            array_buffer: 0,
+        };
+
+        gl.GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut state.framebuffer);
+        gl.GetIntegerv(gl::VIEWPORT, state.viewport.as_mut_ptr());
+        gl.GetIntegerv(gl::BLEND_SRC_RGB, &mut state.blend_func[0]);
+        gl.GetIntegerv(gl::BLEND_DST_RGB, &mut state.blend_func[1]);
+        gl.GetIntegerv(gl::BLEND_SRC_ALPHA, &mut state.blend_func[2]);
+        gl.GetIntegerv(gl::BLEND_DST_ALPHA, &mut state.blend_func[3]);
+        gl.GetIntegerv(gl::CURRENT_PROGRAM, &mut state.program);
+        gl.GetIntegerv(gl::ACTIVE_TEXTURE, &mut state.active_texture);
+        // The texture binding is per unit, and only the first one is used
+        gl.ActiveTexture(gl::TEXTURE0);
+        gl.GetIntegerv(gl::TEXTURE_BINDING_2D, &mut state.texture);
+        gl.GetIntegerv(gl::ARRAY_BUFFER_BINDING, &mut state.array_buffer);
+        state
+    }
+
+    pub unsafe fn restore(&self, gl: &gl::Gl) {
+        gl.BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer as u32);
+        gl.Viewport(
+            self.viewport[0],
+            self.viewport[1],
+            self.viewport[2],
+            self.viewport[3],
+        );
+        set_enabled(gl, gl::SCISSOR_TEST, self.scissor);
+        set_enabled(gl, gl::BLEND, self.blend);
+        gl.BlendFuncSeparate(
+            self.blend_func[0] as u32,
+            self.blend_func[1] as u32,
+            self.blend_func[2] as u32,
+            self.blend_func[3] as u32,
+        );
+        gl.UseProgram(self.program as u32);
+        gl.BindTexture(gl::TEXTURE_2D, self.texture as u32);
+        gl.ActiveTexture(self.active_texture as u32);
+        gl.BindBuffer(gl::ARRAY_BUFFER, self.array_buffer as u32);
+    }
+}
+
+/// Texture bound to a unit other than the first one, which `SavedState` covers.
+pub(crate) struct SavedTexture {
+    unit: u32,
+    texture: i32,
+}
+
+impl SavedTexture {
+    /// Leaves the unit active, `SavedState::restore` brings back the active one.
+    pub unsafe fn save(gl: &gl::Gl, unit: u32) -> Self {
+        let mut texture = 0;
+        gl.ActiveTexture(gl::TEXTURE0 + unit);
+        gl.GetIntegerv(gl::TEXTURE_BINDING_2D, &mut texture);
+        SavedTexture { unit, texture }
+    }
+
+    pub unsafe fn restore(&self, gl: &gl::Gl) {
+        gl.ActiveTexture(gl::TEXTURE0 + self.unit);
+        gl.BindTexture(gl::TEXTURE_2D, self.texture as u32);
+    }
+}
+
+/// State of a vertex attribute changed when drawing over flutter frames.
+pub(crate) struct SavedAttribute {
+    index: u32,
+    enabled: i32,
+    size: i32,
+    kind: i32,
+    normalized: i32,
+    stride: i32,
+    buffer: i32,
+    pointer: *mut c_void,
+}
+
+impl SavedAttribute {
+    pub unsafe fn save(gl: &gl::Gl, index: u32) -> Self {
+        let mut attribute = SavedAttribute {
+            index,
+            enabled: 0,
+            size: 0,
+            kind: 0,
+            normalized: 0,
+            stride: 0,
+            buffer: 0,
+            pointer: ptr::null_mut(),
+        };
+
+        gl.GetVertexAttribiv(
+            index,
+            gl::VERTEX_ATTRIB_ARRAY_ENABLED,
+            &mut attribute.enabled,
+        );
+        gl.GetVertexAttribiv(index, gl::VERTEX_ATTRIB_ARRAY_SIZE, &mut attribute.size);
+        gl.GetVertexAttribiv(index, gl::VERTEX_ATTRIB_ARRAY_TYPE, &mut attribute.kind);
+        gl.GetVertexAttribiv(
+            index,
+            gl::VERTEX_ATTRIB_ARRAY_NORMALIZED,
+            &mut attribute.normalized,
+        );
+        gl.GetVertexAttribiv(index, gl::VERTEX_ATTRIB_ARRAY_STRIDE, &mut attribute.stride);
+        gl.GetVertexAttribiv(
+            index,
+            gl::VERTEX_ATTRIB_ARRAY_BUFFER_BINDING,
+            &mut attribute.buffer,
+        );
+        gl.GetVertexAttribPointerv(
+            index,
+            gl::VERTEX_ATTRIB_ARRAY_POINTER,
+            &mut attribute.pointer,
+        );
+        attribute
+    }
+
+    pub unsafe fn restore(&self, gl: &gl::Gl) {
+        gl.BindBuffer(gl::ARRAY_BUFFER, self.buffer as u32);
+        gl.VertexAttribPointer(
+            self.index,
+            self.size,
+            self.kind as u32,
+            self.normalized as u8,
+            self.stride,
+            self.pointer,
+        );
+        if self.enabled == 0 {
+            gl.DisableVertexAttribArray(self.index);
+        }
+    }
+}
+
+unsafe fn set_enabled(gl: &gl::Gl, capability: u32, enabled: bool) {
+    if enabled {
+        gl.Enable(capability);
+    } else {
+        gl.Disable(capability);
+    }
+}
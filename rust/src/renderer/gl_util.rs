@@ -1,36 +1,33 @@
+use smithay::utils::Rectangle;
 use smithay::wayland::shm::BufferData;
 
 use crate::renderer::gl;
-use log::info;
+use log::{error, trace};
 
-use std::ffi::c_void;
-use std::mem;
+use std::ffi::{c_void, CString};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{mem, ptr};
 
+// TODO: compute from data.format
+const PIXEL_SIZE: usize = 4;
 
-pub fn upload_texture(gl: gl::Gl, data: BufferData, pool: &[u8]) -> u32 {
-    unsafe {
-        let offset = data.offset as usize;
-        let width = data.width as usize;
-        let height = data.height as usize;
-        let stride = data.stride as usize;
-
-        // TODO: compute from data.format
-        let pixelsize = 4;
-        assert!(offset + (height - 1) * stride + width * pixelsize <= pool.len());
+static TEXTURES_CREATED: AtomicUsize = AtomicUsize::new(0);
+static TEXTURES_DELETED: AtomicUsize = AtomicUsize::new(0);
 
-        info!(
-            "data= offset={} width={} height={} stride={} format={:?} len={}",
-            data.offset,
-            data.width,
-            data.height,
-            data.stride,
-            data.format,
-            pool.len()
-        );
+/// Returns the number of textures created and deleted through `gen_texture` and
+/// `delete_texture`.
+pub fn texture_counts() -> (usize, usize) {
+    (
+        TEXTURES_CREATED.load(Ordering::SeqCst),
+        TEXTURES_DELETED.load(Ordering::SeqCst),
+    )
+}
 
+/// Generates a texture name configured for sampling without mipmaps.
+pub fn gen_texture(gl: &gl::Gl) -> u32 {
+    unsafe {
         let id: gl::types::GLuint = 0;
         gl.GenTextures(1, mem::transmute(&id));
-        info!("stage 1 = {}", gl.GetError());
 
         gl.BindTexture(gl::TEXTURE_2D, id);
 
@@ -45,24 +42,157 @@ pub fn upload_texture(gl: gl::Gl, data: BufferData, pool: &[u8]) -> u32 {
         gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, 0);
         gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
 
-        gl.PixelStorei(gl::UNPACK_ROW_LENGTH, data.stride / 4);
+        TEXTURES_CREATED.fetch_add(1, Ordering::SeqCst);
+        id
+    }
+}
+
+pub fn delete_texture(gl: &gl::Gl, id: u32) {
+    unsafe {
+        gl.DeleteTextures(1, &id);
+    }
+    TEXTURES_DELETED.fetch_add(1, Ordering::SeqCst);
+}
 
+/// Allocates a new texture with undefined contents.
+pub fn create_texture(gl: &gl::Gl, width: i32, height: i32) -> u32 {
+    let id = gen_texture(gl);
+    allocate_texture(gl, id, width, height);
+    id
+}
+
+/// (Re)specifies the storage of an existing texture, discarding its contents.
+pub fn allocate_texture(gl: &gl::Gl, id: u32, width: i32, height: i32) {
+    unsafe {
+        gl.BindTexture(gl::TEXTURE_2D, id);
         gl.TexImage2D(
             gl::TEXTURE_2D,
             0,
             gl::RGBA8 as i32,
-            data.width,
-            data.height,
+            width,
+            height,
+            0,
+            gl::RGBA as u32,
+            gl::UNSIGNED_BYTE,
+            ptr::null(),
+        );
+
+        trace!("Allocated texture {} ({}x{})", id, width, height);
+    }
+}
+
+/// Uploads a region of an shm pool into an existing texture.
+///
+/// The region is given in buffer coordinates and must lie within the buffer.
+pub fn upload_region(gl: &gl::Gl, id: u32, data: &BufferData, pool: &[u8], region: Rectangle) {
+    unsafe {
+        let offset = data.offset as usize;
+        let width = data.width as usize;
+        let height = data.height as usize;
+        let stride = data.stride as usize;
+
+        assert!(offset + (height - 1) * stride + width * PIXEL_SIZE <= pool.len());
+        assert!(region.x >= 0 && region.y >= 0);
+        assert!(region.x + region.width <= data.width && region.y + region.height <= data.height);
+
+        trace!(
+            "upload texture={} region={:?} stride={} format={:?}",
+            id,
+            region,
+            data.stride,
+            data.format,
+        );
+
+        gl.BindTexture(gl::TEXTURE_2D, id);
+
+        gl.PixelStorei(gl::UNPACK_ROW_LENGTH, data.stride / PIXEL_SIZE as i32);
+        gl.PixelStorei(gl::UNPACK_SKIP_PIXELS, region.x);
+        gl.PixelStorei(gl::UNPACK_SKIP_ROWS, region.y);
+
+        gl.TexSubImage2D(
+            gl::TEXTURE_2D,
             0,
+            region.x,
+            region.y,
+            region.width,
+            region.height,
             gl::RGBA as u32,
             gl::UNSIGNED_BYTE,
-            pool.as_ptr() as *const c_void,
+            pool[offset..].as_ptr() as *const c_void,
         );
 
         gl.PixelStorei(gl::UNPACK_ROW_LENGTH, 0);
+        gl.PixelStorei(gl::UNPACK_SKIP_PIXELS, 0);
+        gl.PixelStorei(gl::UNPACK_SKIP_ROWS, 0);
+    }
+}
 
-        info!("stage 3 = {}", gl.GetError());
+/// Compiles and links a shader program, returning `None` if either stage fails.
+pub fn create_program(gl: &gl::Gl, vertex: &str, fragment: &str) -> Option<u32> {
+    unsafe {
+        let vertex = compile_shader(gl, gl::VERTEX_SHADER, vertex)?;
+        let fragment = match compile_shader(gl, gl::FRAGMENT_SHADER, fragment) {
+            Some(shader) => shader,
+            None => {
+                gl.DeleteShader(vertex);
+                return None;
+            }
+        };
+
+        let program = gl.CreateProgram();
+        gl.AttachShader(program, vertex);
+        gl.AttachShader(program, fragment);
+        gl.LinkProgram(program);
+
+        // The program keeps the shaders alive while attached
+        gl.DeleteShader(vertex);
+        gl.DeleteShader(fragment);
+
+        let mut status = gl::FALSE as i32;
+        gl.GetProgramiv(program, gl::LINK_STATUS, &mut status);
+        if status != gl::TRUE as i32 {
+            error!("Failed to link program: {}", program_log(gl, program));
+            gl.DeleteProgram(program);
+            return None;
+        }
+
+        Some(program)
+    }
+}
 
-        id
+unsafe fn compile_shader(gl: &gl::Gl, kind: u32, source: &str) -> Option<u32> {
+    let shader = gl.CreateShader(kind);
+    let source = CString::new(source).unwrap();
+    gl.ShaderSource(shader, 1, &source.as_ptr(), ptr::null());
+    gl.CompileShader(shader);
+
+    let mut status = gl::FALSE as i32;
+    gl.GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+    if status != gl::TRUE as i32 {
+        error!("Failed to compile shader: {}", shader_log(gl, shader));
+        gl.DeleteShader(shader);
+        return None;
     }
+
+    Some(shader)
+}
+
+unsafe fn shader_log(gl: &gl::Gl, shader: u32) -> String {
+    let mut len = 0;
+    gl.GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
+    let mut buf = vec![0u8; len.max(1) as usize];
+    gl.GetShaderInfoLog(shader, len, ptr::null_mut(), buf.as_mut_ptr() as *mut _);
+    String::from_utf8_lossy(&buf)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+unsafe fn program_log(gl: &gl::Gl, program: u32) -> String {
+    let mut len = 0;
+    gl.GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
+    let mut buf = vec![0u8; len.max(1) as usize];
+    gl.GetProgramInfoLog(program, len, ptr::null_mut(), buf.as_mut_ptr() as *mut _);
+    String::from_utf8_lossy(&buf)
+        .trim_end_matches('\0')
+        .to_string()
 }
@@ -1,3 +1,8 @@
+pub(crate) mod convert;
+pub(crate) mod cursor;
 pub(crate) mod egl_util;
 pub(crate) mod gl;
+pub(crate) mod gl_state;
 pub(crate) mod gl_util;
+pub(crate) mod output;
+pub(crate) mod texture;
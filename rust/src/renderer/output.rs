@@ -0,0 +1,272 @@
+use std::ffi::CString;
+
+use log::{debug, error};
+use smithay::reexports::wayland_server::protocol::wl_output::Transform;
+use smithay::utils::Rectangle;
+
+use crate::backends::layout::transform_point;
+use crate::renderer::gl_state::{SavedAttribute, SavedState};
+use crate::renderer::{gl, gl_util};
+
+const VERTEX_SHADER: &str = r#"
+#version 100
+attribute vec2 position;
+attribute vec2 tex_coords;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_Position = vec4(position * 2.0 - 1.0, 0.0, 1.0);
+    v_tex_coords = tex_coords;
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+#version 100
+precision mediump float;
+uniform sampler2D tex0;
+varying vec2 v_tex_coords;
+
+void main() {
+    gl_FragColor = texture2D(tex0, v_tex_coords);
+}
+"#;
+
+const QUAD: [f32; 8] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+
+/// Shows flutter on several monitors.
+///
+/// Flutter renders into an offscreen framebuffer covering all monitors, and the region of every
+/// monitor is then copied onto its surface.
+pub struct OutputRenderer {
+    gl: gl::Gl,
+    program: Option<u32>,
+    target: Option<Target>,
+}
+
+/// The framebuffer flutter renders into, with the texture and stencil buffer attached to it.
+struct Target {
+    framebuffer: u32,
+    texture: u32,
+    stencil: u32,
+    size: (u32, u32),
+}
+
+impl OutputRenderer {
+    pub fn new(gl: gl::Gl) -> Self {
+        let program = gl_util::create_program(&gl, VERTEX_SHADER, FRAGMENT_SHADER);
+        Self {
+            gl,
+            program,
+            target: None,
+        }
+    }
+
+    /// Returns the framebuffer flutter should render into, recreating it if the size changed.
+    pub fn framebuffer(&mut self, size: (u32, u32)) -> Option<u32> {
+        match &self.target {
+            Some(target) if target.size == size => return Some(target.framebuffer),
+            Some(_) => self.delete_target(),
+            None => {}
+        }
+
+        let target = unsafe { self.create_target(size)? };
+        let framebuffer = target.framebuffer;
+        self.target = Some(target);
+        Some(framebuffer)
+    }
+
+    unsafe fn create_target(&self, size: (u32, u32)) -> Option<Target> {
+        let gl = &self.gl;
+        let saved = SavedState::save(gl);
+
+        let texture = gl_util::create_texture(gl, size.0 as i32, size.1 as i32);
+
+        // Skia clips with the stencil buffer
+        let mut stencil = 0;
+        gl.GenRenderbuffers(1, &mut stencil);
+        gl.BindRenderbuffer(gl::RENDERBUFFER, stencil);
+        gl.RenderbufferStorage(
+            gl::RENDERBUFFER,
+            gl::STENCIL_INDEX8,
+            size.0 as i32,
+            size.1 as i32,
+        );
+        gl.BindRenderbuffer(gl::RENDERBUFFER, 0);
+
+        let mut framebuffer = 0;
+        gl.GenFramebuffers(1, &mut framebuffer);
+        gl.BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl.FramebufferTexture2D(
+            gl::FRAMEBUFFER,
+            gl::COLOR_ATTACHMENT0,
+            gl::TEXTURE_2D,
+            texture,
+            0,
+        );
+        gl.FramebufferRenderbuffer(
+            gl::FRAMEBUFFER,
+            gl::STENCIL_ATTACHMENT,
+            gl::RENDERBUFFER,
+            stencil,
+        );
+        let status = gl.CheckFramebufferStatus(gl::FRAMEBUFFER);
+        saved.restore(gl);
+
+        let target = Target {
+            framebuffer,
+            texture,
+            stencil,
+            size,
+        };
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            error!(
+                "Failed to create the framebuffer for flutter: {:#x}",
+                status
+            );
+            delete(gl, target);
+            return None;
+        }
+
+        debug!("Created a {}x{} framebuffer for flutter", size.0, size.1);
+        Some(target)
+    }
+
+    fn delete_target(&mut self) {
+        if let Some(target) = self.target.take() {
+            unsafe {
+                delete(&self.gl, target);
+            }
+        }
+    }
+
+    /// Reads back the flutter framebuffer with its size, as RGBA rows stored bottom up.
+    pub fn read_frame(&self) -> Option<((u32, u32), Vec<u8>)> {
+        let target = self.target.as_ref()?;
+        let (width, height) = target.size;
+        let mut data = vec![0u8; width as usize * height as usize * 4];
+
+        unsafe {
+            let gl = &self.gl;
+            let saved = SavedState::save(gl);
+            gl.BindFramebuffer(gl::FRAMEBUFFER, target.framebuffer);
+            gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl.ReadPixels(
+                0,
+                0,
+                width as i32,
+                height as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                data.as_mut_ptr() as *mut _,
+            );
+            saved.restore(gl);
+        }
+        Some((target.size, data))
+    }
+
+    /// Replaces the contents of the framebuffer with a frame read back from another GPU, so that
+    /// it can be drawn on the monitors of this one.
+    pub fn upload_frame(&mut self, size: (u32, u32), data: &[u8]) {
+        if self.framebuffer(size).is_none() {
+            return;
+        }
+        let target = match &self.target {
+            Some(target) => target,
+            None => return,
+        };
+
+        unsafe {
+            let gl = &self.gl;
+            let saved = SavedState::save(gl);
+            gl.BindTexture(gl::TEXTURE_2D, target.texture);
+            gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl.TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                0,
+                0,
+                size.0 as i32,
+                size.1 as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                data.as_ptr() as *const _,
+            );
+            saved.restore(gl);
+        }
+    }
+
+    /// Copies a region of the flutter framebuffer onto the default framebuffer of the current
+    /// surface, of the given size.
+    ///
+    /// The region is in pixels with the origin at the top left, and is scaled to cover the whole
+    /// surface after applying the transform of the monitor.
+    pub fn draw(&self, source: Rectangle, transform: Transform, size: (u32, u32)) {
+        let (program, target) = match (self.program, &self.target) {
+            (Some(program), Some(target)) => (program, target),
+            _ => return,
+        };
+
+        // Each corner of the surface samples the corner of the region the transform puts there.
+        // Framebuffer rows are stored bottom up
+        let width = f64::from(target.size.0);
+        let height = f64::from(target.size.1);
+        let mut tex_coords = [0.0f32; 8];
+        for (corner, coords) in QUAD.chunks(2).zip(tex_coords.chunks_mut(2)) {
+            let (u, v) = transform_point(
+                transform,
+                (f64::from(corner[0]), 1.0 - f64::from(corner[1])),
+                (1.0, 1.0),
+            );
+            let x = f64::from(source.x) + u * f64::from(source.width);
+            let y = f64::from(source.y) + v * f64::from(source.height);
+            coords[0] = (x / width) as f32;
+            coords[1] = ((height - y) / height) as f32;
+        }
+
+        unsafe {
+            let gl = &self.gl;
+            let saved = SavedState::save(gl);
+
+            gl.BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl.Viewport(0, 0, size.0 as i32, size.1 as i32);
+            gl.Disable(gl::SCISSOR_TEST);
+            gl.Disable(gl::BLEND);
+            gl.UseProgram(program);
+
+            gl.ActiveTexture(gl::TEXTURE0);
+            gl.BindTexture(gl::TEXTURE_2D, target.texture);
+            let name = CString::new("tex0").unwrap();
+            gl.Uniform1i(gl.GetUniformLocation(program, name.as_ptr()), 0);
+
+            gl.BindBuffer(gl::ARRAY_BUFFER, 0);
+            let mut saved_attributes = vec![];
+            for (name, data) in &[("position", &QUAD), ("tex_coords", &tex_coords)] {
+                let name = CString::new(*name).unwrap();
+                let attribute = gl.GetAttribLocation(program, name.as_ptr()) as u32;
+                saved_attributes.push(SavedAttribute::save(gl, attribute));
+                gl.VertexAttribPointer(
+                    attribute,
+                    2,
+                    gl::FLOAT,
+                    gl::FALSE,
+                    0,
+                    data.as_ptr() as *const _,
+                );
+                gl.EnableVertexAttribArray(attribute);
+            }
+
+            gl.DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+            for saved_attribute in saved_attributes.into_iter().rev() {
+                saved_attribute.restore(gl);
+            }
+            saved.restore(gl);
+        }
+    }
+}
+
+unsafe fn delete(gl: &gl::Gl, target: Target) {
+    gl.DeleteFramebuffers(1, &target.framebuffer);
+    gl.DeleteRenderbuffers(1, &target.stencil);
+    gl_util::delete_texture(gl, target.texture);
+}
@@ -0,0 +1,515 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use smithay::backend::egl::{BufferAccessError as EGLBufferAccessError, EGLImages, Format};
+use smithay::reexports::wayland_server::protocol::{wl_buffer, wl_shm};
+use smithay::utils::Rectangle;
+use smithay::wayland::shm::{
+    with_buffer_contents as shm_buffer_contents, BufferAccessError as ShmBufferAccessError,
+};
+
+use crate::protocols::dmabuf::Dmabuf;
+use crate::protocols::viewporter::Viewport;
+use crate::renderer::convert::{ConvertError, Converter};
+use crate::renderer::egl_util::WrappedDisplay;
+use crate::renderer::{gl, gl_util};
+
+/// Damage accumulated on a surface since its contents were last uploaded.
+pub enum PendingDamage {
+    Full,
+    Regions(Vec<Rectangle>),
+}
+
+impl Default for PendingDamage {
+    fn default() -> Self {
+        PendingDamage::Regions(vec![])
+    }
+}
+
+impl PendingDamage {
+    pub fn add(&mut self, region: Rectangle) {
+        if let PendingDamage::Regions(regions) = self {
+            regions.push(region);
+        }
+    }
+
+    pub fn full(&mut self) {
+        *self = PendingDamage::Full;
+    }
+
+    pub fn take(&mut self) -> PendingDamage {
+        std::mem::replace(self, PendingDamage::default())
+    }
+}
+
+/// Details of a texture exposed to flutter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextureInfo {
+    pub name: u32,
+    pub target: u32,
+    /// Width of the texture in pixels.
+    pub width: i32,
+    /// Height of the texture in pixels.
+    pub height: i32,
+    /// Region of the texture to present, in pixels, as (x, y, width, height).
+    pub crop: (f64, f64, f64, f64),
+    /// Logical size the cropped region is presented at.
+    pub size: (f64, f64),
+}
+
+/// A change to the textures exposed to flutter, to be forwarded to the widgets presenting them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureChange {
+    Updated(i64, TextureInfo),
+    Removed(i64),
+}
+
+/// Textures of destroyed surfaces, waiting to be deleted once a context is current.
+#[derive(Clone, Default)]
+pub struct TextureGarbage(Rc<RefCell<Vec<SurfaceTexture>>>);
+
+impl TextureGarbage {
+    pub fn push(&self, texture: SurfaceTexture) {
+        self.0.borrow_mut().push(texture);
+    }
+
+    fn take(&self) -> Vec<SurfaceTexture> {
+        self.0.replace(vec![])
+    }
+}
+
+/// Counters used to verify that textures are not leaked.
+#[derive(Clone, Copy, Debug)]
+pub struct TextureStats {
+    /// Textures created since startup.
+    pub created: usize,
+    /// Textures deleted since startup.
+    pub deleted: usize,
+    /// Textures currently exposed to flutter.
+    pub registered: usize,
+    /// Textures waiting to be deleted.
+    pub pending: usize,
+}
+
+impl TextureStats {
+    pub fn live(&self) -> usize {
+        self.created - self.deleted
+    }
+}
+
+/// Tracks the textures flutter can sample from, keyed by their flutter texture id.
+#[derive(Default)]
+pub struct TextureRegistry {
+    next_id: i64,
+    textures: HashMap<i64, TextureInfo>,
+    changes: Vec<TextureChange>,
+    garbage: TextureGarbage,
+}
+
+impl TextureRegistry {
+    pub fn register(&mut self, info: TextureInfo) -> i64 {
+        self.next_id += 1;
+        self.textures.insert(self.next_id, info);
+        self.changes
+            .push(TextureChange::Updated(self.next_id, info));
+        self.next_id
+    }
+
+    pub fn update(&mut self, id: i64, info: TextureInfo) {
+        if self.textures.insert(id, info) != Some(info) {
+            self.changes.push(TextureChange::Updated(id, info));
+        }
+    }
+
+    pub fn unregister(&mut self, id: i64) -> Option<TextureInfo> {
+        self.changes.push(TextureChange::Removed(id));
+        self.textures.remove(&id)
+    }
+
+    pub fn get(&self, id: i64) -> Option<TextureInfo> {
+        self.textures.get(&id).cloned()
+    }
+
+    pub fn garbage(&self) -> TextureGarbage {
+        self.garbage.clone()
+    }
+
+    /// Removes the textures of destroyed surfaces, returning them for deletion.
+    pub fn collect_garbage(&mut self) -> Vec<SurfaceTexture> {
+        let garbage = self.garbage.take();
+        for texture in &garbage {
+            if let Some(id) = texture.flutter_id {
+                self.unregister(id);
+            }
+        }
+        garbage
+    }
+
+    /// Returns the changes since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<TextureChange> {
+        std::mem::replace(&mut self.changes, vec![])
+    }
+
+    pub fn stats(&self) -> TextureStats {
+        let (created, deleted) = gl_util::texture_counts();
+        TextureStats {
+            created,
+            deleted,
+            registered: self.textures.len(),
+            pending: self.garbage.0.borrow().len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Shm(ShmBufferAccessError),
+    Egl(EGLBufferAccessError),
+    Dmabuf,
+    Convert(ConvertError),
+}
+
+/// The pixel format of the buffer a texture was imported from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceFormat {
+    Shm(wl_shm::Format),
+    Egl(Format),
+    Dmabuf(u32),
+}
+
+/// The persistent texture holding the contents of a surface.
+pub struct SurfaceTexture {
+    pub flutter_id: Option<i64>,
+    pub name: u32,
+    pub width: i32,
+    pub height: i32,
+    pub format: SourceFormat,
+    /// Images backing the texture when an EGL buffer is sampled directly.
+    pub images: Option<EGLImages>,
+}
+
+impl SurfaceTexture {
+    fn matches(&self, width: i32, height: i32, format: SourceFormat) -> bool {
+        self.width == width && self.height == height && self.format == format
+    }
+
+    /// Deletes the texture, the upload context must be current.
+    pub fn delete(self, gl: &gl::Gl) {
+        gl_util::delete_texture(gl, self.name);
+    }
+
+    /// Describes the texture as presented for the given buffer scale and viewport.
+    pub fn info(&self, scale: i32, viewport: &Viewport) -> TextureInfo {
+        let scale = f64::from(scale);
+        let width = f64::from(self.width) / scale;
+        let height = f64::from(self.height) / scale;
+
+        let (x, y, w, h) = viewport.source.unwrap_or((0.0, 0.0, width, height));
+
+        let size = match viewport.destination {
+            Some((w, h)) => (f64::from(w), f64::from(h)),
+            None => (w, h),
+        };
+
+        TextureInfo {
+            name: self.name,
+            target: gl::TEXTURE_2D,
+            width: self.width,
+            height: self.height,
+            crop: (x * scale, y * scale, w * scale, h * scale),
+            size,
+        }
+    }
+}
+
+/// Copies the damaged parts of an shm buffer into the surface texture.
+///
+/// The texture is only reallocated when the buffer size or format changes, in which case the
+/// whole buffer is uploaded. Returns whether the texture was reallocated.
+pub fn import_shm_buffer(
+    gl: &gl::Gl,
+    texture: &mut Option<SurfaceTexture>,
+    buffer: &wl_buffer::WlBuffer,
+    damage: PendingDamage,
+) -> Result<bool, ImportError> {
+    shm_buffer_contents(buffer, |pool, data| {
+        let format = SourceFormat::Shm(data.format);
+        let matches = match texture {
+            Some(tex) => tex.matches(data.width, data.height, format) && tex.images.is_none(),
+            None => false,
+        };
+
+        let full = Rectangle {
+            x: 0,
+            y: 0,
+            width: data.width,
+            height: data.height,
+        };
+
+        if !matches {
+            let flutter_id = discard_texture(gl, texture);
+
+            let name = gl_util::create_texture(gl, data.width, data.height);
+            gl_util::upload_region(gl, name, &data, pool, full);
+
+            *texture = Some(SurfaceTexture {
+                flutter_id,
+                name,
+                width: data.width,
+                height: data.height,
+                format,
+                images: None,
+            });
+            return true;
+        }
+
+        let name = texture.as_ref().unwrap().name;
+        match damage {
+            PendingDamage::Full => gl_util::upload_region(gl, name, &data, pool, full),
+            PendingDamage::Regions(regions) => {
+                for region in regions {
+                    if let Some(region) = clamp_region(region, full) {
+                        gl_util::upload_region(gl, name, &data, pool, region);
+                    }
+                }
+            }
+        }
+        false
+    })
+    .map_err(ImportError::Shm)
+}
+
+/// Imports the contents of an EGL buffer into the surface texture.
+///
+/// Single plane RGB(A) buffers using the standard orientation are bound to the texture directly,
+/// all other buffers are converted into RGBA. Returns whether the texture was reallocated.
+pub fn import_egl_buffer(
+    gl: &gl::Gl,
+    converter: &mut Option<Converter>,
+    texture: &mut Option<SurfaceTexture>,
+    images: EGLImages,
+) -> Result<bool, ImportError> {
+    let format = SourceFormat::Egl(images.format);
+    let width = images.width as i32;
+    let height = images.height as i32;
+    let direct =
+        images.y_inverted && (images.format == Format::RGB || images.format == Format::RGBA);
+
+    let matches = match texture {
+        Some(tex) => tex.matches(width, height, format) && tex.images.is_some() == direct,
+        None => false,
+    };
+
+    let (name, flutter_id) = if matches {
+        let tex = texture.take().unwrap();
+        (tex.name, tex.flutter_id)
+    } else {
+        let flutter_id = discard_texture(gl, texture);
+        (gl_util::gen_texture(gl), flutter_id)
+    };
+
+    let result = if direct {
+        unsafe { images.bind_to_texture(0, name) }
+            .map_err(|err| ImportError::Convert(ConvertError::Texture(err)))
+    } else {
+        if !matches {
+            gl_util::allocate_texture(gl, name, width, height);
+        }
+        converter
+            .get_or_insert_with(|| Converter::new(gl))
+            .convert(gl, &images, name)
+            .map_err(ImportError::Convert)
+    };
+
+    *texture = Some(SurfaceTexture {
+        flutter_id,
+        name,
+        width,
+        height,
+        format,
+        images: if direct { Some(images) } else { None },
+    });
+
+    result.map(|_| !matches)
+}
+
+/// Imports a dmabuf into the surface texture through an EGLImage.
+///
+/// Y-inverted buffers are flipped into a separate copy. Returns whether the texture was
+/// reallocated.
+pub fn import_dmabuf(
+    gl: &gl::Gl,
+    converter: &mut Option<Converter>,
+    texture: &mut Option<SurfaceTexture>,
+    dmabuf: &Dmabuf,
+) -> Result<bool, ImportError> {
+    let image = unsafe {
+        WrappedDisplay::new()
+            .ok()
+            .and_then(|display| display.create_dmabuf_image(dmabuf))
+    }
+    .ok_or(ImportError::Dmabuf)?;
+
+    let format = SourceFormat::Dmabuf(dmabuf.format);
+    let matches = match texture {
+        Some(tex) => tex.matches(dmabuf.width, dmabuf.height, format),
+        None => false,
+    };
+
+    let (name, flutter_id) = if matches {
+        let tex = texture.take().unwrap();
+        (tex.name, tex.flutter_id)
+    } else {
+        let flutter_id = discard_texture(gl, texture);
+        (gl_util::gen_texture(gl), flutter_id)
+    };
+
+    let result = unsafe {
+        if dmabuf.y_inverted() {
+            let source = gl_util::gen_texture(gl);
+            gl.EGLImageTargetTexture2DOES(gl::TEXTURE_2D, image.as_ptr());
+            gl_util::allocate_texture(gl, name, dmabuf.width, dmabuf.height);
+
+            let result = converter
+                .get_or_insert_with(|| Converter::new(gl))
+                .convert_planes(
+                    gl,
+                    Format::RGBA,
+                    false,
+                    (dmabuf.width, dmabuf.height),
+                    &[source],
+                    name,
+                )
+                .map_err(ImportError::Convert);
+
+            gl_util::delete_texture(gl, source);
+            result
+        } else {
+            // The texture keeps the storage alive once the image is destroyed
+            gl.BindTexture(gl::TEXTURE_2D, name);
+            gl.EGLImageTargetTexture2DOES(gl::TEXTURE_2D, image.as_ptr());
+            gl.BindTexture(gl::TEXTURE_2D, 0);
+            Ok(())
+        }
+    };
+
+    *texture = Some(SurfaceTexture {
+        flutter_id,
+        name,
+        width: dmabuf.width,
+        height: dmabuf.height,
+        format,
+        images: None,
+    });
+
+    result.map(|_| !matches)
+}
+
+/// Deletes the current texture, returning its flutter id so that it can be reused.
+fn discard_texture(gl: &gl::Gl, texture: &mut Option<SurfaceTexture>) -> Option<i64> {
+    texture.take().and_then(|old| {
+        gl_util::delete_texture(gl, old.name);
+        old.flutter_id
+    })
+}
+
+fn clamp_region(region: Rectangle, bounds: Rectangle) -> Option<Rectangle> {
+    let x1 = region.x.max(bounds.x);
+    let y1 = region.y.max(bounds.y);
+    let x2 = (region.x.saturating_add(region.width)).min(bounds.x + bounds.width);
+    let y2 = (region.y.saturating_add(region.height)).min(bounds.y + bounds.height);
+
+    if x2 <= x1 || y2 <= y1 {
+        return None;
+    }
+
+    Some(Rectangle {
+        x: x1,
+        y: y1,
+        width: x2 - x1,
+        height: y2 - y1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn surface_texture(registry: &mut TextureRegistry, name: u32) -> SurfaceTexture {
+        let mut texture = SurfaceTexture {
+            flutter_id: None,
+            name,
+            width: 64,
+            height: 32,
+            format: SourceFormat::Shm(wl_shm::Format::Argb8888),
+            images: None,
+        };
+        let info = texture.info(1, &Viewport::default());
+        texture.flutter_id = Some(registry.register(info));
+        texture
+    }
+
+    #[test]
+    fn destroyed_textures_are_collected() {
+        let mut registry = TextureRegistry::default();
+        let garbage = registry.garbage();
+
+        let textures: Vec<_> = (1..=3)
+            .map(|name| surface_texture(&mut registry, name))
+            .collect();
+        let stats = registry.stats();
+        assert_eq!((stats.registered, stats.pending), (3, 0));
+
+        for texture in textures {
+            garbage.push(texture);
+        }
+        let stats = registry.stats();
+        assert_eq!((stats.registered, stats.pending), (3, 3));
+
+        let collected = registry.collect_garbage();
+        assert_eq!(collected.len(), 3);
+        let stats = registry.stats();
+        assert_eq!((stats.registered, stats.pending), (0, 0));
+        assert!(registry.collect_garbage().is_empty());
+    }
+
+    #[test]
+    fn clamp_region_inside() {
+        let bounds = rect(0, 0, 100, 50);
+        assert_eq!(
+            clamp_region(rect(10, 10, 20, 20), bounds),
+            Some(rect(10, 10, 20, 20))
+        );
+    }
+
+    #[test]
+    fn clamp_region_partially_outside() {
+        let bounds = rect(0, 0, 100, 50);
+        assert_eq!(
+            clamp_region(rect(-10, 40, 30, 30), bounds),
+            Some(rect(0, 40, 20, 10))
+        );
+        assert_eq!(
+            clamp_region(rect(90, -5, i32::MAX, i32::MAX), bounds),
+            Some(rect(90, 0, 10, 50))
+        );
+    }
+
+    #[test]
+    fn clamp_region_outside() {
+        let bounds = rect(0, 0, 100, 50);
+        assert_eq!(clamp_region(rect(100, 0, 10, 10), bounds), None);
+        assert_eq!(clamp_region(rect(0, -10, 10, 10), bounds), None);
+        assert_eq!(clamp_region(rect(10, 10, 0, 10), bounds), None);
+    }
+}
@@ -7,12 +7,14 @@ use std::{
 use rand;
 
 use smithay::{
+    backend::egl::BufferAccessError as EGLBufferAccessError,
     reexports::wayland_server::{
         protocol::{wl_buffer, wl_shell_surface, wl_surface},
         Display,
     },
+    utils::Rectangle,
     wayland::{
-        compositor::{compositor_init, CompositorToken, SurfaceEvent},
+        compositor::{compositor_init, CompositorToken, Damage, SurfaceAttributes, SurfaceEvent},
         data_device::DnDIconRole,
         seat::CursorImageRole,
         shell::{
@@ -32,7 +34,16 @@ use log::{debug, error, info, trace, warn};
 
 
 
+use crate::backends::cursor::{self, CursorContents};
+use crate::protocols::dmabuf::Dmabuf;
+use crate::protocols::presentation::PendingFeedback;
+use crate::protocols::surface_damage::SurfaceDamage;
+use crate::protocols::viewporter::{Viewport, ViewportState};
+use crate::renderer::texture::{
+    self, ImportError, PendingDamage, SourceFormat, SurfaceTexture, TextureGarbage,
+};
 use crate::window_map::{Kind as SurfaceKind, WindowMap};
+use crate::{FlutterCompositor, FlutterCompositorWeakRef};
 
 define_roles!(Roles =>
     [ XdgSurface, XdgSurfaceRole ]
@@ -47,20 +58,33 @@ pub type MyCompositorToken = CompositorToken<Roles>;
 
 pub fn init_shell(
     display: &mut Display,
+    compositor: FlutterCompositorWeakRef,
 ) -> (
     CompositorToken<Roles>,
     Arc<Mutex<XdgShellState<Roles>>>,
     Arc<Mutex<WlShellState<Roles>>>,
     Rc<RefCell<MyWindowMap>>,
 ) {
+    let damage = SurfaceDamage::default();
+    damage.install(display);
+
     // Create the compositor
     let (compositor_token, _, _) = compositor_init(
         display,
         move |request, surface, ctoken| match request {
-            SurfaceEvent::Commit => surface_commit(&surface, ctoken),
-            SurfaceEvent::Frame { callback } => callback
-                .implement_closure(|_, _| unreachable!(), None::<fn(_)>, ())
-                .done(0), // TODO: Frame events should be sent at the end of a flutter frame
+            SurfaceEvent::Commit => surface_commit(&surface, ctoken, &compositor, &damage),
+            SurfaceEvent::Frame { callback } => {
+                let callback = callback.implement_closure(|_, _| unreachable!(), None::<fn(_)>, ());
+                // Frame callbacks are sent once flutter presents its next frame
+                match compositor.upgrade() {
+                    Some(compositor_ref) => compositor_ref
+                        .get()
+                        .backend
+                        .presentation
+                        .frame_callback(callback),
+                    None => callback.done(0),
+                }
+            }
         },
         None,
     );
@@ -134,34 +158,368 @@ pub fn init_shell(
     )
 }
 
-#[derive(Default)]
 pub struct SurfaceData {
     pub buffer: Option<wl_buffer::WlBuffer>,
-    pub texture: Option<u32>,
+    pub texture: Option<SurfaceTexture>,
+    pub damage: PendingDamage,
+    /// Scale of the buffer contents relative to surface coordinates.
+    pub buffer_scale: i32,
+    pub viewport: Viewport,
+    /// Attach offsets not yet applied to the window location.
+    pub pending_move: (i32, i32),
+    /// Accumulated attach offsets of a drag and drop icon.
+    pub icon_offset: (i32, i32),
+    /// Set when the buffer could not be uploaded yet because no upload context was available.
+    upload_pending: bool,
+    garbage: TextureGarbage,
+}
+
+impl SurfaceData {
+    fn new(garbage: TextureGarbage) -> Self {
+        Self {
+            buffer: None,
+            texture: None,
+            damage: PendingDamage::default(),
+            buffer_scale: 1,
+            viewport: Viewport::default(),
+            pending_move: (0, 0),
+            icon_offset: (0, 0),
+            upload_pending: false,
+            garbage,
+        }
+    }
+
+    fn release_buffer(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            if buffer.as_ref().is_alive() {
+                buffer.release();
+            }
+        }
+    }
+}
+
+impl Drop for SurfaceData {
+    fn drop(&mut self) {
+        // The surface was destroyed, its texture is deleted on the next loop iteration
+        self.release_buffer();
+        if let Some(texture) = self.texture.take() {
+            self.garbage.push(texture);
+        }
+    }
 }
 
-fn surface_commit(surface: &wl_surface::WlSurface, token: CompositorToken<Roles>) {
-    info!("surface_commit");
+fn surface_commit(
+    surface: &wl_surface::WlSurface,
+    token: CompositorToken<Roles>,
+    compositor: &FlutterCompositorWeakRef,
+    damage: &SurfaceDamage,
+) {
+    trace!("surface_commit");
+    let compositor_ref = match compositor.upgrade() {
+        Some(compositor_ref) => compositor_ref,
+        None => return,
+    };
+    let compositor = compositor_ref.get();
+
+    let is_cursor = token.has_role::<CursorImageRole>(surface);
+
     // we retrieve the contents of the associated buffer and copy it
-    token.with_surface_data(surface, |attributes| {
+    let mut feedback = vec![];
+    let offset = token.with_surface_data(surface, |attributes| {
+        if let Some(pending) = attributes.user_data.get_mut::<PendingFeedback>() {
+            feedback = pending.take();
+        }
+
+        if is_cursor {
+            store_cursor_contents(attributes);
+        }
+
+        let viewport = attributes
+            .user_data
+            .get::<ViewportState>()
+            .map(ViewportState::commit)
+            .unwrap_or_default();
+
         attributes
             .user_data
-            .insert_if_missing(|| SurfaceData::default());
-        match attributes.buffer.take() {
-            Some(Some((buffer, (_x, _y)))) => {
+            .insert_if_missing(|| SurfaceData::new(compositor.textures.borrow().garbage()));
+        let data = attributes.user_data.get_mut::<SurfaceData>().unwrap();
+
+        data.buffer_scale = attributes.buffer_scale.max(1);
+        data.viewport = viewport;
+
+        // Commits without any damage requests are treated as fully damaged
+        let requests = damage.take(surface);
+        if requests.is_empty() {
+            data.damage.full();
+        }
+        for request in requests {
+            match request {
+                Damage::Full => data.damage.full(),
+                // Surface coordinates no longer map linearly onto the buffer with a viewport
+                Damage::Surface(_) if viewport != Viewport::default() => data.damage.full(),
+                Damage::Surface(region) => data.damage.add(scale_region(region, data.buffer_scale)),
+                Damage::Buffer(region) => data.damage.add(region),
+            }
+        }
+
+        let offset = match attributes.buffer.take() {
+            Some(Some((buffer, offset))) => {
                 // new contents
-                // TODO: handle hotspot coordinates
-                let data = attributes.user_data.get_mut::<SurfaceData>().unwrap();
-                data.buffer = Some(buffer);
-                data.texture = None;
+                let previous = data.buffer.replace(buffer);
+                upload_surface(data, &compositor);
+
+                // EGL and dmabuf buffers are sampled directly, so the previous buffer can only
+                // be reused once it has been replaced
+                if let Some(previous) = previous {
+                    let reattached = match &data.buffer {
+                        Some(buffer) => buffer.as_ref().equals(previous.as_ref()),
+                        None => false,
+                    };
+                    if !reattached && previous.as_ref().is_alive() {
+                        previous.release();
+                    }
+                }
+
+                Some(offset)
             }
             Some(None) => {
                 // erase the contents
-                let data = attributes.user_data.get_mut::<SurfaceData>().unwrap();
-                data.buffer = None;
-                data.texture = None;
+                data.release_buffer();
+                data.damage = PendingDamage::default();
+                data.upload_pending = false;
+                if let Some(texture) = data.texture.take() {
+                    delete_texture(&compositor, texture);
+                }
+                None
             }
-            None => {}
+            None if data.upload_pending && data.buffer.is_some() => {
+                // retry the upload of the attached buffer that had to be skipped
+                upload_surface(data, &compositor);
+                None
+            }
+            None => {
+                // the client may change its scale or viewport without attaching a new buffer
+                refresh_texture_info(data, &compositor);
+                None
+            }
+        };
+
+        // The size of the buffer is only known once it has been imported
+        let buffer_size = data.texture.as_ref().map(|texture| {
+            let scale = f64::from(data.buffer_scale);
+            (
+                f64::from(texture.width) / scale,
+                f64::from(texture.height) / scale,
+            )
+        });
+        if let (Some(size), Some(state)) =
+            (buffer_size, attributes.user_data.get::<ViewportState>())
+        {
+            state.check_source(size);
         }
+
+        offset
     });
+
+    compositor.backend.presentation.commit(surface, feedback);
+
+    if let Some((x, y)) = offset {
+        if x != 0 || y != 0 {
+            apply_attach_offset(surface, token, (x, y));
+        }
+    }
+
+    if is_cursor {
+        compositor.backend.cursor_committed(surface);
+    }
+}
+
+/// Keeps a copy of the new contents of a cursor surface, before the buffer is released.
+fn store_cursor_contents(attributes: &mut SurfaceAttributes) {
+    let image = match &attributes.buffer {
+        Some(Some((buffer, _))) => cursor::read_shm_buffer(buffer),
+        Some(None) => None,
+        None => return,
+    };
+
+    attributes
+        .user_data
+        .insert_if_missing(CursorContents::default);
+    attributes
+        .user_data
+        .get_mut::<CursorContents>()
+        .unwrap()
+        .set(image);
+}
+
+/// Applies the offset of a new buffer, relative to the previous one, according to the role of
+/// the surface.
+fn apply_attach_offset(
+    surface: &wl_surface::WlSurface,
+    token: CompositorToken<Roles>,
+    (x, y): (i32, i32),
+) {
+    if token.has_role::<CursorImageRole>(surface) {
+        // The hotspot stays at the same position on screen
+        let _ = token.with_role_data(surface, |role: &mut CursorImageRole| {
+            role.hotspot.0 -= x;
+            role.hotspot.1 -= y;
+        });
+        return;
+    }
+
+    let icon = token.has_role::<DnDIconRole>(surface);
+    token.with_surface_data(surface, |attributes| {
+        if let Some(data) = attributes.user_data.get_mut::<SurfaceData>() {
+            if icon {
+                data.icon_offset.0 += x;
+                data.icon_offset.1 += y;
+            } else {
+                data.pending_move.0 += x;
+                data.pending_move.1 += y;
+            }
+        }
+    });
+}
+
+/// Returns where the cursor image should be drawn for the given pointer location.
+pub fn cursor_image_location(
+    surface: &wl_surface::WlSurface,
+    token: CompositorToken<Roles>,
+    pointer: (i32, i32),
+) -> (i32, i32) {
+    let hotspot = token
+        .with_role_data(surface, |role: &mut CursorImageRole| role.hotspot)
+        .unwrap_or((0, 0));
+    (pointer.0 - hotspot.0, pointer.1 - hotspot.1)
+}
+
+/// Returns where a drag and drop icon should be drawn for the given pointer location.
+pub fn dnd_icon_location(
+    surface: &wl_surface::WlSurface,
+    token: CompositorToken<Roles>,
+    pointer: (i32, i32),
+) -> (i32, i32) {
+    let offset = token.with_surface_data(surface, |attributes| {
+        attributes
+            .user_data
+            .get::<SurfaceData>()
+            .map(|data| data.icon_offset)
+            .unwrap_or((0, 0))
+    });
+    (pointer.0 + offset.0, pointer.1 + offset.1)
+}
+
+/// Converts a region in surface coordinates into buffer coordinates.
+fn scale_region(region: Rectangle, scale: i32) -> Rectangle {
+    Rectangle {
+        x: region.x * scale,
+        y: region.y * scale,
+        width: region.width * scale,
+        height: region.height * scale,
+    }
+}
+
+fn delete_texture(compositor: &FlutterCompositor, texture: SurfaceTexture) {
+    if let Some(id) = texture.flutter_id {
+        compositor.textures.borrow_mut().unregister(id);
+        compositor.engine.unregister_external_texture(id);
+    }
+    compositor
+        .backend
+        .with_upload_context(move |gl| texture.delete(gl));
+}
+
+fn upload_surface(data: &mut SurfaceData, compositor: &FlutterCompositor) {
+    let buffer = data.buffer.as_ref().unwrap().clone();
+    let damage = data.damage.take();
+    let texture = &mut data.texture;
+    let backend = &compositor.backend;
+
+    // try dmabufs first, then the egl contents of this buffer, falling back to shm
+    if let Some(dmabuf) = buffer.as_ref().user_data::<Dmabuf>() {
+        let result = backend.with_upload_context(|gl| {
+            let mut converter = backend.converter.borrow_mut();
+            texture::import_dmabuf(gl, &mut converter, texture, dmabuf)
+        });
+        finish_upload(data, compositor, result);
+        return;
+    }
+
+    let result = match backend.egl_buffer_contents(buffer) {
+        Ok(images) => backend.with_upload_context(|gl| {
+            let mut converter = backend.converter.borrow_mut();
+            texture::import_egl_buffer(gl, &mut converter, texture, images)
+        }),
+        Err(EGLBufferAccessError::NotManaged(buffer)) => backend
+            .with_upload_context(|gl| texture::import_shm_buffer(gl, texture, &buffer, damage)),
+        Err(err) => Some(Err(ImportError::Egl(err))),
+    };
+
+    finish_upload(data, compositor, result);
+}
+
+fn refresh_texture_info(data: &SurfaceData, compositor: &FlutterCompositor) {
+    if let Some(texture) = &data.texture {
+        if let Some(id) = texture.flutter_id {
+            let info = texture.info(data.buffer_scale, &data.viewport);
+            let mut textures = compositor.textures.borrow_mut();
+            if textures.get(id) != Some(info) {
+                textures.update(id, info);
+                compositor.engine.mark_texture_frame_available(id);
+            }
+        }
+    }
+}
+
+fn finish_upload(
+    data: &mut SurfaceData,
+    compositor: &FlutterCompositor,
+    result: Option<Result<bool, ImportError>>,
+) {
+    data.upload_pending = result.is_none();
+    match result {
+        Some(Ok(_)) => {
+            let texture = data.texture.as_mut().unwrap();
+            let info = texture.info(data.buffer_scale, &data.viewport);
+            let mut textures = compositor.textures.borrow_mut();
+            match texture.flutter_id {
+                Some(id) => {
+                    if textures.get(id) != Some(info) {
+                        textures.update(id, info);
+                    }
+                }
+                None => {
+                    let id = textures.register(info);
+                    compositor.engine.register_external_texture(id);
+                    texture.flutter_id = Some(id);
+                }
+            }
+            compositor
+                .engine
+                .mark_texture_frame_available(texture.flutter_id.unwrap());
+
+            let copied = match texture.format {
+                SourceFormat::Shm(_) => true,
+                _ => false,
+            };
+            drop(textures);
+
+            // shm contents have been copied, so the client can reuse the buffer straight away
+            if copied {
+                data.release_buffer();
+            }
+        }
+        Some(Err(err)) => {
+            // there was an error reading the buffer, release it
+            error!("Failed to import buffer {:?}", err);
+            data.release_buffer();
+        }
+        None => {
+            // No upload context yet, keep the buffer and upload everything on the next commit
+            warn!("No upload context, retrying the upload on the next commit");
+            data.damage.full();
+        }
+    }
 }
@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+use std::sync::{Arc, RwLock, Weak};
+
+use crate::channel_util::IgnoreHandler;
+use crate::flutter::channel::{BasicMessageChannel, ChannelRegistry};
+use crate::flutter::codec::json_codec;
+use crate::renderer::texture::{TextureChange, TextureRegistry};
+use crate::{json_value, FlutterCompositorWeakRef};
+
+const TEXTURES_CHANNEL_NAME: &str = "flutter_compositor/textures";
+
+/// Tells flutter how to present the surface textures.
+///
+/// Textures hold whole buffers, so each change is sent on the textures channel as an `updated`
+/// event with the texture size in pixels, the region of it to show and the logical size to show
+/// that region at, or as a `removed` event once the texture is gone.
+#[derive(Default)]
+pub(crate) struct TextureManager {
+    handler: Arc<RwLock<IgnoreHandler>>,
+    channel: RefCell<Weak<BasicMessageChannel>>,
+}
+
+impl TextureManager {
+    pub fn register_channels(
+        &self,
+        registry: &ChannelRegistry,
+        compositor: FlutterCompositorWeakRef,
+    ) {
+        let handler = Arc::downgrade(&self.handler);
+
+        self.channel
+            .replace(registry.register_channel(BasicMessageChannel::new(
+                TEXTURES_CHANNEL_NAME,
+                handler,
+                &json_codec::CODEC,
+                compositor,
+            )));
+    }
+
+    /// Sends the texture changes since the last call.
+    pub fn poll(&self, textures: &mut TextureRegistry) {
+        let changes = textures.take_changes();
+        if changes.is_empty() {
+            return;
+        }
+
+        if let Some(channel) = self.channel.borrow().upgrade() {
+            for change in changes {
+                let json = match change {
+                    TextureChange::Updated(id, info) => json_value!({
+                        "event": "updated",
+                        "id": id,
+                        "width": info.width,
+                        "height": info.height,
+                        "cropX": info.crop.0,
+                        "cropY": info.crop.1,
+                        "cropWidth": info.crop.2,
+                        "cropHeight": info.crop.3,
+                        "logicalWidth": info.size.0,
+                        "logicalHeight": info.size.1
+                    }),
+                    TextureChange::Removed(id) => json_value!({
+                        "event": "removed",
+                        "id": id
+                    }),
+                };
+                channel.send(&json);
+            }
+        }
+    }
+}
@@ -14,6 +14,8 @@ use smithay::{
     },
 };
 
+use crate::shell::SurfaceData;
+
 pub enum Kind<R> {
     Xdg(ToplevelSurface<R>),
     Wl(ShellSurface<R>),
@@ -84,6 +86,24 @@ where
 
     pub fn refresh(&mut self) {
         self.windows.retain(|w| w.toplevel.alive());
+
+        // Move windows whose client attached a buffer with an offset, such as when resizing
+        // from the top or left edge
+        let ctoken = self.ctoken;
+        for w in &mut self.windows {
+            let offset = match w.toplevel.get_surface() {
+                Some(surface) => ctoken.with_surface_data(surface, |attributes| {
+                    attributes
+                        .user_data
+                        .get_mut::<SurfaceData>()
+                        .map(|data| std::mem::replace(&mut data.pending_move, (0, 0)))
+                        .unwrap_or((0, 0))
+                }),
+                None => (0, 0),
+            };
+            w.location.0 += offset.0;
+            w.location.1 += offset.1;
+        }
     }
 
     pub fn clear(&mut self) {